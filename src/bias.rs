@@ -0,0 +1,137 @@
+use std::fmt;
+use super::errors::*;
+
+// Bias potential acting on a single dimension of an umbrella window. The
+// energy is evaluated from the (periodic) distance dx = x - x0 between the
+// coordinate and the bias position x0 and the force constant k of the window.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum BiasPotential {
+    // U = 0.5*k*dx^2
+    Harmonic,
+
+    // U = 0.5*k*(|dx|-w)^2 for |dx| > w, 0 otherwise
+    FlatBottom { width: f64 },
+
+    // U = k*dx, e.g. for constant force pulling
+    Linear,
+
+    // U = k*dx^e for dx > 0, 0 otherwise (PLUMED UPPER_WALLS)
+    UpperWall { exponent: f64 },
+
+    // U = k*(-dx)^e for dx < 0, 0 otherwise (PLUMED LOWER_WALLS)
+    LowerWall { exponent: f64 },
+}
+
+impl BiasPotential {
+    // Creates a bias potential from its name as given in the metadata file.
+    // param is the width of flat-bottom potentials or the exponent of walls.
+    pub fn from_name(name: &str, param: Option<f64>) -> Result<BiasPotential> {
+        match name.to_ascii_lowercase().as_str() {
+            "harmonic" => Ok(BiasPotential::Harmonic),
+            "flat_bottom" | "flatbottom" => match param {
+                Some(width) => Ok(BiasPotential::FlatBottom { width }),
+                None => bail!("Flat-bottom bias requires a width."),
+            },
+            "linear" => Ok(BiasPotential::Linear),
+            "upper_wall" | "upper_walls" => Ok(BiasPotential::UpperWall { exponent: param.unwrap_or(2.0) }),
+            "lower_wall" | "lower_walls" => Ok(BiasPotential::LowerWall { exponent: param.unwrap_or(2.0) }),
+            _ => bail!(format!("Unknown bias potential: {}", name)),
+        }
+    }
+
+    // bias energy for distance dx from the bias position and force constant k
+    pub fn energy(&self, dx: f64, k: f64) -> f64 {
+        match *self {
+            BiasPotential::Harmonic => 0.5 * k * dx * dx,
+            BiasPotential::FlatBottom { width } => {
+                let d = dx.abs() - width;
+                if d > 0.0 {
+                    0.5 * k * d * d
+                } else {
+                    0.0
+                }
+            },
+            BiasPotential::Linear => k * dx,
+            BiasPotential::UpperWall { exponent } => {
+                if dx > 0.0 {
+                    k * dx.powf(exponent)
+                } else {
+                    0.0
+                }
+            },
+            BiasPotential::LowerWall { exponent } => {
+                if dx < 0.0 {
+                    k * (-dx).powf(exponent)
+                } else {
+                    0.0
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for BiasPotential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BiasPotential::Harmonic => write!(f, "harmonic"),
+            BiasPotential::FlatBottom { width } => write!(f, "flat_bottom(width={})", width),
+            BiasPotential::Linear => write!(f, "linear"),
+            BiasPotential::UpperWall { exponent } => write!(f, "upper_wall(exp={})", exponent),
+            BiasPotential::LowerWall { exponent } => write!(f, "lower_wall(exp={})", exponent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BiasPotential;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn from_name() {
+        assert_eq!(BiasPotential::Harmonic, BiasPotential::from_name("harmonic", None).unwrap());
+        assert_eq!(BiasPotential::Linear, BiasPotential::from_name("LINEAR", None).unwrap());
+        assert_eq!(BiasPotential::FlatBottom { width: 0.5 },
+            BiasPotential::from_name("flat_bottom", Some(0.5)).unwrap());
+        assert_eq!(BiasPotential::UpperWall { exponent: 2.0 },
+            BiasPotential::from_name("upper_wall", None).unwrap());
+        assert_eq!(BiasPotential::LowerWall { exponent: 4.0 },
+            BiasPotential::from_name("lower_walls", Some(4.0)).unwrap());
+        assert!(BiasPotential::from_name("flat_bottom", None).is_err());
+        assert!(BiasPotential::from_name("gaussian", None).is_err());
+    }
+
+    #[test]
+    fn harmonic() {
+        let bias = BiasPotential::Harmonic;
+        assert_approx_eq!(5.0, bias.energy(1.0, 10.0));
+        assert_approx_eq!(5.0, bias.energy(-1.0, 10.0));
+        assert_approx_eq!(0.0, bias.energy(0.0, 10.0));
+    }
+
+    #[test]
+    fn flat_bottom() {
+        let bias = BiasPotential::FlatBottom { width: 0.5 };
+        assert_approx_eq!(0.0, bias.energy(0.3, 10.0));
+        assert_approx_eq!(0.0, bias.energy(-0.5, 10.0));
+        assert_approx_eq!(1.25, bias.energy(1.0, 10.0));
+        assert_approx_eq!(1.25, bias.energy(-1.0, 10.0));
+    }
+
+    #[test]
+    fn linear() {
+        let bias = BiasPotential::Linear;
+        assert_approx_eq!(20.0, bias.energy(2.0, 10.0));
+        assert_approx_eq!(-20.0, bias.energy(-2.0, 10.0));
+    }
+
+    #[test]
+    fn walls() {
+        let upper = BiasPotential::UpperWall { exponent: 2.0 };
+        assert_approx_eq!(0.0, upper.energy(-2.0, 10.0));
+        assert_approx_eq!(40.0, upper.energy(2.0, 10.0));
+        let lower = BiasPotential::LowerWall { exponent: 4.0 };
+        assert_approx_eq!(160.0, lower.energy(-2.0, 10.0));
+        assert_approx_eq!(0.0, lower.energy(2.0, 10.0));
+    }
+}
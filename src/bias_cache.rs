@@ -0,0 +1,300 @@
+use std::f64;
+
+// Cache of the bias factors exp(-U/kT) and their logarithms -U/kT of every
+// window in every bin. The dense cache stores all num_bins*num_windows values.
+// The sparse cache drops the values of bins where the reduced bias of a window
+// exceeds its minimum by more than a cutoff (in units of kT). Dropped bias
+// factors are treated as 0, which keeps high-dimensional grids in memory,
+// because most bins are far away from any single umbrella.
+#[derive(Debug,Clone)]
+pub enum BiasCache {
+    Dense {
+        num_bins: usize,
+        bias: Vec<f64>,
+        log_bias: Vec<f64>,
+    },
+    Sparse {
+        // kept bins of each window, sorted by bin
+        by_window: SparseRows,
+        // kept windows of each bin, sorted by window. Same values as
+        // by_window, so that both WHAM equations only visit kept entries.
+        by_bin: SparseRows,
+    },
+}
+
+// Compressed rows of sparse bias values. The entries of row r are stored in
+// offsets[r]..offsets[r+1] of the other vectors.
+#[derive(Debug,Clone)]
+pub struct SparseRows {
+    offsets: Vec<usize>,
+    indices: Vec<u32>,
+    bias: Vec<f64>,
+    log_bias: Vec<f64>,
+}
+
+impl SparseRows {
+    fn row(&self, row: usize) -> BiasEntries<'_> {
+        let range = self.offsets[row]..self.offsets[row+1];
+        BiasEntries::Sparse {
+            indices: &self.indices[range.clone()],
+            bias: &self.bias[range.clone()],
+            log_bias: &self.log_bias[range],
+            pos: 0,
+        }
+    }
+
+    // position of the entry in a column of a row, None if it was dropped
+    fn find(&self, row: usize, col: usize) -> Option<usize> {
+        let start = self.offsets[row];
+        self.indices[start..self.offsets[row+1]].binary_search(&(col as u32)).ok()
+            .map(|idx| start + idx)
+    }
+
+    // Transposes the rows, e.g. from windows with their bins to bins with
+    // their windows. Entries of the new rows stay sorted.
+    fn transpose(&self, num_cols: usize) -> SparseRows {
+        let mut offsets = vec![0; num_cols + 1];
+        for idx in &self.indices {
+            offsets[*idx as usize + 1] += 1;
+        }
+        for col in 0..num_cols {
+            offsets[col+1] += offsets[col];
+        }
+        let len = self.indices.len();
+        let mut next = offsets.clone();
+        let mut indices = vec![0; len];
+        let mut bias = vec![0.0; len];
+        let mut log_bias = vec![0.0; len];
+        for row in 0..self.offsets.len()-1 {
+            for ndx in self.offsets[row]..self.offsets[row+1] {
+                let col = self.indices[ndx] as usize;
+                indices[next[col]] = row as u32;
+                bias[next[col]] = self.bias[ndx];
+                log_bias[next[col]] = self.log_bias[ndx];
+                next[col] += 1;
+            }
+        }
+        SparseRows { offsets, indices, bias, log_bias }
+    }
+}
+
+impl BiasCache {
+    // Evaluates log_bias(bin, window) = -U/kT for every bin and window.
+    pub fn dense<B>(num_bins: usize, num_windows: usize, log_bias: B) -> BiasCache
+            where B: Fn(usize, usize) -> f64 {
+        let mut cache_log_bias = vec![0.0; num_bins*num_windows];
+        for window in 0..num_windows {
+            for bin in 0..num_bins {
+                cache_log_bias[window * num_bins + bin] = log_bias(bin, window);
+            }
+        }
+        let bias = cache_log_bias.iter().map(|b| b.exp()).collect();
+        BiasCache::Dense { num_bins, bias, log_bias: cache_log_bias }
+    }
+
+    // Evaluates log_bias(bin, window) = -U/kT for every bin and window, but
+    // only keeps the values within cutoff kT of the smallest bias of each
+    // window. Bins selected by keep(bin, window), e.g. the bins with samples of
+    // the window, are always kept, unless their bias is infinite.
+    pub fn sparse<B, K>(num_bins: usize, num_windows: usize, cutoff: f64, log_bias: B, keep: K)
+            -> BiasCache
+            where B: Fn(usize, usize) -> f64, K: Fn(usize, usize) -> bool {
+        let mut offsets = vec![0];
+        let mut indices = Vec::new();
+        let mut row_log_bias = Vec::new();
+        let mut window_log_bias = vec![0.0; num_bins];
+        for window in 0..num_windows {
+            for (bin, b) in window_log_bias.iter_mut().enumerate() {
+                *b = log_bias(bin, window);
+            }
+            let max = window_log_bias.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            for (bin, b) in window_log_bias.iter().enumerate() {
+                if *b > f64::NEG_INFINITY && (max - b <= cutoff || keep(bin, window)) {
+                    indices.push(bin as u32);
+                    row_log_bias.push(*b);
+                }
+            }
+            offsets.push(indices.len());
+        }
+        let bias = row_log_bias.iter().map(|b| b.exp()).collect();
+        let by_window = SparseRows { offsets, indices, bias, log_bias: row_log_bias };
+        let by_bin = by_window.transpose(num_bins);
+        BiasCache::Sparse { by_window, by_bin }
+    }
+
+    // bias factor exp(-U/kT) of a window in a bin, 0 if it was dropped
+    pub fn bias(&self, bin: usize, window: usize) -> f64 {
+        match *self {
+            BiasCache::Dense { num_bins, ref bias, .. } => bias[window * num_bins + bin],
+            BiasCache::Sparse { ref by_window, .. } => by_window.find(window, bin)
+                .map_or(0.0, |ndx| by_window.bias[ndx]),
+        }
+    }
+
+    // logarithmic bias factor -U/kT of a window in a bin, -inf if it was dropped
+    pub fn log_bias(&self, bin: usize, window: usize) -> f64 {
+        match *self {
+            BiasCache::Dense { num_bins, ref log_bias, .. } => log_bias[window * num_bins + bin],
+            BiasCache::Sparse { ref by_window, .. } => by_window.find(window, bin)
+                .map_or(f64::NEG_INFINITY, |ndx| by_window.log_bias[ndx]),
+        }
+    }
+
+    // cached values of all windows in a bin
+    pub fn bin_entries(&self, bin: usize) -> BiasEntries<'_> {
+        match *self {
+            BiasCache::Dense { num_bins, ref bias, ref log_bias } => BiasEntries::Dense {
+                bias, log_bias, start: bin, stride: num_bins, len: bias.len() / num_bins, pos: 0,
+            },
+            BiasCache::Sparse { ref by_bin, .. } => by_bin.row(bin),
+        }
+    }
+
+    // Cached values of the bins of a window. The dense cache only yields the
+    // given bins (bin, count), e.g. the occupied ones, while the sparse cache
+    // yields all bins that were kept.
+    pub fn window_entries<'a>(&'a self, window: usize, bins: &'a [(usize, f64)]) -> BiasEntries<'a> {
+        match *self {
+            BiasCache::Dense { num_bins, ref bias, ref log_bias } => BiasEntries::Selected {
+                bias, log_bias, start: window * num_bins, bins, pos: 0,
+            },
+            BiasCache::Sparse { ref by_window, .. } => by_window.row(window),
+        }
+    }
+
+    // all cached bias factors exp(-U/kT)
+    pub fn values(&self) -> &[f64] {
+        match *self {
+            BiasCache::Dense { ref bias, .. } => bias,
+            BiasCache::Sparse { ref by_window, .. } => &by_window.bias,
+        }
+    }
+}
+
+// Iterator over the cached values of a bin or a window. Yields the index of
+// the window (or bin) with the bias factor exp(-U/kT) and -U/kT. Dropped
+// values of a sparse cache are skipped.
+pub enum BiasEntries<'a> {
+    Dense {
+        bias: &'a [f64],
+        log_bias: &'a [f64],
+        start: usize,
+        stride: usize,
+        len: usize,
+        pos: usize,
+    },
+    Sparse {
+        indices: &'a [u32],
+        bias: &'a [f64],
+        log_bias: &'a [f64],
+        pos: usize,
+    },
+    // selected bins of a window in the dense cache
+    Selected {
+        bias: &'a [f64],
+        log_bias: &'a [f64],
+        start: usize,
+        bins: &'a [(usize, f64)],
+        pos: usize,
+    },
+}
+
+impl<'a> Iterator for BiasEntries<'a> {
+    type Item = (usize, f64, f64);
+
+    fn next(&mut self) -> Option<(usize, f64, f64)> {
+        match *self {
+            BiasEntries::Dense { bias, log_bias, start, stride, len, ref mut pos } => {
+                if *pos == len {
+                    return None
+                }
+                let ndx = start + *pos * stride;
+                *pos += 1;
+                Some((*pos - 1, bias[ndx], log_bias[ndx]))
+            },
+            BiasEntries::Sparse { indices, bias, log_bias, ref mut pos } => {
+                if *pos == indices.len() {
+                    return None
+                }
+                *pos += 1;
+                Some((indices[*pos-1] as usize, bias[*pos-1], log_bias[*pos-1]))
+            },
+            BiasEntries::Selected { bias, log_bias, start, bins, ref mut pos } => {
+                if *pos == bins.len() {
+                    return None
+                }
+                let bin = bins[*pos].0;
+                *pos += 1;
+                Some((bin, bias[start + bin], log_bias[start + bin]))
+            },
+        }
+    }
+
+    // Iterates the entries of each variant in a separate loop, which is much
+    // faster than matching the variant for every entry. Used by sum() and
+    // for_each() of the WHAM kernels.
+    fn fold<B, G>(self, init: B, mut g: G) -> B where G: FnMut(B, (usize, f64, f64)) -> B {
+        let mut acc = init;
+        match self {
+            BiasEntries::Dense { bias, log_bias, start, stride, len, pos } => {
+                for idx in pos..len {
+                    let ndx = start + idx * stride;
+                    acc = g(acc, (idx, bias[ndx], log_bias[ndx]));
+                }
+            },
+            BiasEntries::Sparse { indices, bias, log_bias, pos } => {
+                for ndx in pos..indices.len() {
+                    acc = g(acc, (indices[ndx] as usize, bias[ndx], log_bias[ndx]));
+                }
+            },
+            BiasEntries::Selected { bias, log_bias, start, bins, pos } => {
+                for &(bin, _) in &bins[pos..] {
+                    acc = g(acc, (bin, bias[start + bin], log_bias[start + bin]));
+                }
+            },
+        }
+        acc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bias of 4 bins and 2 windows at bins 0 and 3
+    fn log_bias(bin: usize, window: usize) -> f64 {
+        let dist = bin as f64 - 3.0 * window as f64;
+        -dist * dist
+    }
+
+    #[test]
+    fn dense() {
+        let cache = BiasCache::dense(4, 2, log_bias);
+        assert_eq!(-4.0, cache.log_bias(2, 0));
+        assert_eq!((-4.0_f64).exp(), cache.bias(2, 0));
+        let bin: Vec<(usize, f64, f64)> = cache.bin_entries(1).collect();
+        assert_eq!(vec![(0, (-1.0_f64).exp(), -1.0), (1, (-4.0_f64).exp(), -4.0)], bin);
+        let window: Vec<(usize, f64, f64)> = cache.window_entries(1, &[(1, 2.0), (3, 1.0)]).collect();
+        assert_eq!(vec![(1, (-4.0_f64).exp(), -4.0), (3, 1.0, 0.0)], window);
+    }
+
+    #[test]
+    fn sparse() {
+        // bin 0 of window 1 is kept, because it holds samples
+        let cache = BiasCache::sparse(4, 2, 1.5, log_bias, |bin, window| bin == 0 && window == 1);
+        let window: Vec<usize> = cache.window_entries(0, &[]).map(|(bin, _, _)| bin).collect();
+        assert_eq!(vec![0, 1], window);
+        let window: Vec<usize> = cache.window_entries(1, &[]).map(|(bin, _, _)| bin).collect();
+        assert_eq!(vec![0, 2, 3], window);
+        let bin: Vec<(usize, f64, f64)> = cache.bin_entries(0).collect();
+        assert_eq!(vec![(0, 1.0, 0.0), (1, (-9.0_f64).exp(), -9.0)], bin);
+        let bin: Vec<usize> = cache.bin_entries(3).map(|(window, _, _)| window).collect();
+        assert_eq!(vec![1], bin);
+
+        // dropped values
+        assert_eq!(0.0, cache.bias(3, 0));
+        assert_eq!(f64::NEG_INFINITY, cache.log_bias(3, 0));
+        assert_eq!(-1.0, cache.log_bias(2, 1));
+        assert_eq!(5, cache.values().len());
+    }
+}
@@ -0,0 +1,550 @@
+use super::histogram::{count_bins,Dataset,Histogram};
+use super::bias::BiasPotential;
+use super::correlation_analysis::statistical_ineff;
+use super::error_analysis;
+use super::{BootstrapScheme, Config, Method, WhamResult, is_converged, perform_estimation, k_B};
+use super::errors::*;
+
+// Programmatic interface to the estimators without metadata and timeseries
+// files. Windows are built from their bias and samples, binned on a
+// HistogramGrid into a Dataset by a DatasetBuilder and solved by a Solver:
+//
+//     let grid = HistogramGrid::new(vec![-3.0], vec![3.0], vec![60]);
+//     let dataset = DatasetBuilder::new(grid, 300.0)
+//         .window(Window::new(vec![-1.0], vec![100.0]).samples(samples_1))
+//         .window(Window::new(vec![1.0], vec![100.0]).samples(samples_2))
+//         .build()?;
+//     let result = Solver::new().tolerance(1e-7).solve(&dataset)?;
+
+// Histogram grid of the reaction coordinates with the minimum, maximum and
+// number of bins of each dimension.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HistogramGrid {
+    pub min: Vec<f64>,
+    pub max: Vec<f64>,
+    pub bins: Vec<usize>,
+    pub cyclic: Vec<bool>,
+}
+
+impl HistogramGrid {
+    // non-periodic grid
+    pub fn new(min: Vec<f64>, max: Vec<f64>, bins: Vec<usize>) -> HistogramGrid {
+        let cyclic = vec![false; bins.len()];
+        HistogramGrid { min, max, bins, cyclic }
+    }
+
+    // flags for periodic dimensions
+    pub fn cyclic(mut self, cyclic: Vec<bool>) -> HistogramGrid {
+        self.cyclic = cyclic;
+        self
+    }
+
+    pub fn dimens(&self) -> usize {
+        self.bins.len()
+    }
+
+    // total number of bins
+    pub fn num_bins(&self) -> usize {
+        self.bins.iter().product()
+    }
+
+    pub fn bin_width(&self) -> Vec<f64> {
+        (0..self.dimens()).map(|d| (self.max[d] - self.min[d]) / self.bins[d] as f64).collect()
+    }
+
+    // Flat bin index of a sample or None if it is outside of the grid. Samples
+    // of cyclic dimensions are folded into the primary image [min, max) first.
+    // Same binning as for timeseries files.
+    pub fn bin(&self, x: &[f64]) -> Option<usize> {
+        let bin_width = self.bin_width();
+        let mut bin = 0;
+        let mut stride = 1;
+        for d in 0..self.dimens() {
+            let mut val = x[d];
+            if self.cyclic[d] && (val < self.min[d] || val >= self.max[d]) {
+                val = self.min[d] + (val - self.min[d]).rem_euclid(self.max[d] - self.min[d]);
+            }
+            let above = if self.cyclic[d] { val > self.max[d] } else { val >= self.max[d] };
+            if val < self.min[d] || above {
+                return None
+            }
+            let mut idx = ((val - self.min[d]) / bin_width[d]) as usize;
+            if self.cyclic[d] {
+                idx %= self.bins[d];
+            }
+            bin += idx * stride;
+            stride *= self.bins[d];
+        }
+        Some(bin)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let dimens = self.dimens();
+        if dimens == 0 {
+            bail!("Histogram grid has no dimensions.");
+        }
+        if self.min.len() != dimens || self.max.len() != dimens || self.cyclic.len() != dimens {
+            bail!(format!("Histogram grid dimensions do not match (min: {}, max: {}, bins: {}, cyclic: {}).",
+                self.min.len(), self.max.len(), dimens, self.cyclic.len()));
+        }
+        for d in 0..dimens {
+            if self.bins[d] == 0 || self.min[d] >= self.max[d] {
+                bail!(format!("Invalid histogram grid in dimension {}: {} bins from {} to {}.",
+                    d+1, self.bins[d], self.min[d], self.max[d]));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Umbrella window with the bias position, force constant and bias potential
+// of each dimension and the samples of the reaction coordinates. Potentials
+// are harmonic unless set otherwise.
+#[derive(Debug,Clone)]
+pub struct Window {
+    pub position: Vec<f64>,
+    pub force_constant: Vec<f64>,
+    pub potentials: Vec<BiasPotential>,
+    // one sample per frame in the order of the timeseries
+    pub samples: Vec<Vec<f64>>,
+}
+
+impl Window {
+    pub fn new(position: Vec<f64>, force_constant: Vec<f64>) -> Window {
+        let potentials = vec![BiasPotential::Harmonic; position.len()];
+        Window { position, force_constant, potentials, samples: Vec::new() }
+    }
+
+    pub fn potentials(mut self, potentials: Vec<BiasPotential>) -> Window {
+        self.potentials = potentials;
+        self
+    }
+
+    pub fn samples(mut self, samples: Vec<Vec<f64>>) -> Window {
+        self.samples = samples;
+        self
+    }
+
+    // appends a single sample
+    pub fn sample(mut self, x: Vec<f64>) -> Window {
+        self.samples.push(x);
+        self
+    }
+}
+
+// Bins the samples of a set of windows on a histogram grid and creates the
+// Dataset for the estimators.
+#[derive(Debug,Clone)]
+pub struct DatasetBuilder {
+    grid: HistogramGrid,
+    temperature: f64,
+    windows: Vec<Window>,
+    keep_samples: bool,
+    keep_frames: bool,
+    ignore_empty: bool,
+    bias_cutoff: f64,
+}
+
+impl DatasetBuilder {
+    // windows are simulated at the temperature in Kelvin
+    pub fn new(grid: HistogramGrid, temperature: f64) -> DatasetBuilder {
+        DatasetBuilder {
+            grid,
+            temperature,
+            windows: Vec::new(),
+            keep_samples: false,
+            keep_frames: false,
+            ignore_empty: false,
+            bias_cutoff: 0.0,
+        }
+    }
+
+    pub fn window(mut self, window: Window) -> DatasetBuilder {
+        self.windows.push(window);
+        self
+    }
+
+    pub fn windows<I: IntoIterator<Item=Window>>(mut self, windows: I) -> DatasetBuilder {
+        self.windows.extend(windows);
+        self
+    }
+
+    // Keep the samples in addition to the histograms. Required by MBAR.
+    pub fn keep_samples(mut self, keep_samples: bool) -> DatasetBuilder {
+        self.keep_samples = keep_samples;
+        self
+    }
+
+    // Keep the bin of every frame and estimate the statistical inefficiency of
+    // each window. Required by the block bootstrap.
+    pub fn keep_frames(mut self, keep_frames: bool) -> DatasetBuilder {
+        self.keep_frames = keep_frames;
+        self
+    }
+
+    // Do not fail if no sample of a window is inside the grid.
+    pub fn ignore_empty(mut self, ignore_empty: bool) -> DatasetBuilder {
+        self.ignore_empty = ignore_empty;
+        self
+    }
+
+    // Drop the bias values of each window beyond this many kT of its minimum
+    // to save memory on large grids. 0 keeps all values (default).
+    pub fn bias_cutoff(mut self, bias_cutoff: f64) -> DatasetBuilder {
+        self.bias_cutoff = bias_cutoff;
+        self
+    }
+
+    pub fn build(self) -> Result<Dataset> {
+        self.grid.validate()?;
+        if self.windows.is_empty() {
+            bail!("Dataset has no windows.");
+        }
+        let dimens = self.grid.dimens();
+        let num_bins = self.grid.num_bins();
+
+        let mut bias_pos: Vec<f64> = Vec::new();
+        let mut bias_fc: Vec<f64> = Vec::new();
+        let mut bias_potentials: Vec<BiasPotential> = Vec::new();
+        let mut histograms: Vec<Histogram> = Vec::new();
+        for (idx, window) in self.windows.iter().enumerate() {
+            if window.position.len() != dimens || window.force_constant.len() != dimens
+                    || window.potentials.len() != dimens {
+                bail!(format!("Expected bias position, force constant and potential of {} dimensions \
+                    for window {}.", dimens, idx+1));
+            }
+            if let Some(sample) = window.samples.iter().find(|x| x.len() != dimens) {
+                bail!(format!("Expected samples of {} dimensions for window {} but got {}.",
+                    dimens, idx+1, sample.len()));
+            }
+            bias_pos.extend_from_slice(&window.position);
+            bias_fc.extend_from_slice(&window.force_constant);
+            bias_potentials.extend_from_slice(&window.potentials);
+
+            let hist = self.build_histogram(window);
+            if hist.num_points == 0 {
+                let warning = format!("No data points in histogram boundaries for window {}.", idx+1);
+                if self.ignore_empty {
+                    info!("{}", warning);
+                } else {
+                    bail!(warning);
+                }
+            }
+            histograms.push(hist);
+        }
+
+        let kT = self.temperature * k_B;
+        let mut ds = Dataset::new(num_bins, self.grid.bins.clone(), self.grid.bin_width(),
+            self.grid.min.clone(), self.grid.max.clone(), bias_pos, bias_fc, kT, histograms,
+            self.grid.cyclic.clone());
+        if !bias_potentials.iter().all(|b| *b == BiasPotential::Harmonic) {
+            ds = Dataset::new_with_potentials(ds, bias_potentials);
+        }
+        if self.bias_cutoff > 0.0 {
+            ds = Dataset::new_with_bias_cutoff(ds, self.bias_cutoff);
+        }
+        Ok(ds)
+    }
+
+    fn build_histogram(&self, window: &Window) -> Histogram {
+        let mut counts: Vec<(usize, f64)> = Vec::new();
+        let mut samples: Vec<f64> = Vec::new();
+        let mut frames: Vec<usize> = Vec::new();
+        for x in &window.samples {
+            if let Some(bin) = self.grid.bin(x) {
+                counts.push((bin, 1.0));
+                if self.keep_samples {
+                    samples.extend_from_slice(x);
+                }
+                if self.keep_frames {
+                    frames.push(bin);
+                }
+            }
+        }
+        let num_points = counts.len() as u32;
+        let mut hist = Histogram::new_sparse(num_points, count_bins(counts));
+        hist.samples = samples;
+        hist.frames = frames;
+        if self.keep_frames && window.samples.len() > 2 {
+            hist.inefficiency = (0..self.grid.dimens())
+                .map(|d| statistical_ineff(&window.samples.iter().map(|x| x[d]).collect::<Vec<f64>>()))
+                .fold(1.0, f64::max);
+        }
+        hist
+    }
+}
+
+// Free energy estimator with its convergence and error analysis options.
+#[derive(Debug,Clone)]
+pub struct Solver {
+    method: Method,
+    tolerance: f64,
+    max_iterations: usize,
+    logspace: bool,
+    diis: usize,
+    initial_offsets: Option<Vec<f64>>,
+    bootstrap: usize,
+    seed: u64,
+    bootstrap_scheme: BootstrapScheme,
+    bootstrap_retries: usize,
+    bootstrap_sd: bool,
+    bootstrap_ci: f64,
+}
+
+impl Default for Solver {
+    fn default() -> Solver {
+        Solver::new()
+    }
+}
+
+impl Solver {
+    // WHAM with the defaults of the command line interface
+    pub fn new() -> Solver {
+        Solver {
+            method: Method::Wham,
+            tolerance: 0.000_001,
+            max_iterations: 100_000,
+            logspace: false,
+            diis: 0,
+            initial_offsets: None,
+            bootstrap: 0,
+            seed: 0,
+            bootstrap_scheme: BootstrapScheme::Bayesian,
+            bootstrap_retries: 0,
+            bootstrap_sd: false,
+            bootstrap_ci: 0.0,
+        }
+    }
+
+    pub fn method(mut self, method: Method) -> Solver {
+        self.method = method;
+        self
+    }
+
+    // maximum change of the bias offsets in kJ/mol at convergence
+    pub fn tolerance(mut self, tolerance: f64) -> Solver {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn max_iterations(mut self, max_iterations: usize) -> Solver {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn logspace(mut self, logspace: bool) -> Solver {
+        self.logspace = logspace;
+        self
+    }
+
+    // number of previous iterations for DIIS, 0 disables DIIS
+    pub fn diis(mut self, diis: usize) -> Solver {
+        self.diis = diis;
+        self
+    }
+
+    // bias offsets F in kJ/mol to start the iteration from
+    pub fn initial_offsets(mut self, F: Vec<f64>) -> Solver {
+        self.initial_offsets = Some(F);
+        self
+    }
+
+    // number of bootstrapping runs and the random seed
+    pub fn bootstrap(mut self, runs: usize, seed: u64) -> Solver {
+        self.bootstrap = runs;
+        self.seed = seed;
+        self
+    }
+
+    pub fn bootstrap_scheme(mut self, scheme: BootstrapScheme) -> Solver {
+        self.bootstrap_scheme = scheme;
+        self
+    }
+
+    pub fn bootstrap_retries(mut self, retries: usize) -> Solver {
+        self.bootstrap_retries = retries;
+        self
+    }
+
+    // standard deviation instead of the standard error of the bootstrapping runs
+    pub fn bootstrap_sd(mut self, sd: bool) -> Solver {
+        self.bootstrap_sd = sd;
+        self
+    }
+
+    // confidence level of the percentile intervals in percent, 0 disables them
+    pub fn bootstrap_ci(mut self, level: f64) -> Solver {
+        self.bootstrap_ci = level;
+        self
+    }
+
+    // Solves for the bin probabilities and bias offsets of the dataset. The
+    // result is returned even if the iteration does not converge within max
+    // iterations, which is indicated by `WhamResult::converged`. Bootstrapping
+    // is only performed for converged results.
+    pub fn solve(&self, ds: &Dataset) -> Result<WhamResult> {
+        if self.method == Method::Mbar && ds.histograms.iter().all(|h| h.samples.is_empty()) {
+            bail!("MBAR requires the samples of each window. Build the dataset with keep_samples.");
+        }
+        if self.bootstrap > 0 && self.bootstrap_scheme == BootstrapScheme::Block
+                && ds.histograms.iter().any(|h| h.frames.len() != h.num_points as usize) {
+            bail!("The block bootstrap requires the frames of each window. Build the dataset with keep_frames.");
+        }
+        let cfg = self.config(ds);
+        let (probability, F, F_prev, iterations) =
+            perform_estimation(&cfg, ds, self.initial_offsets.as_deref())?;
+        let bootstrap = if self.bootstrap > 0 && is_converged(&F_prev, &F, self.tolerance) {
+            Some(error_analysis::run_bootstrap(&cfg, ds.clone(), self.bootstrap, &F)?)
+        } else {
+            None
+        };
+        Ok(WhamResult::new(ds, probability, F, F_prev, iterations, self.tolerance, bootstrap))
+    }
+
+    // The estimators share the Config of the command line interface. Options
+    // that only concern input and output files keep their defaults.
+    fn config(&self, ds: &Dataset) -> Config {
+        Config {
+            num_bins: ds.dimens_lengths.clone(),
+            dimens: ds.dimens_lengths.len(),
+            tolerance: self.tolerance,
+            max_iterations: self.max_iterations,
+            temperature: ds.kT / k_B,
+            cyclic: ds.cyclic.clone(),
+            bootstrap: self.bootstrap,
+            bootstrap_seed: self.seed,
+            bootstrap_scheme: self.bootstrap_scheme,
+            method: self.method,
+            logspace: self.logspace,
+            diis: self.diis,
+            bootstrap_sd: self.bootstrap_sd,
+            bootstrap_ci: self.bootstrap_ci,
+            bootstrap_retries: self.bootstrap_retries,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> HistogramGrid {
+        HistogramGrid::new(vec![0.0], vec![4.0], vec![4])
+    }
+
+    // samples of two windows in 4 bins
+    fn windows() -> Vec<Window> {
+        vec![
+            Window::new(vec![1.0], vec![10.0])
+                .samples(vec![vec![0.5], vec![1.5], vec![1.5], vec![2.5], vec![4.5]]),
+            Window::new(vec![3.0], vec![10.0])
+                .samples(vec![vec![1.5], vec![2.5], vec![2.5], vec![3.5], vec![3.9]]),
+        ]
+    }
+
+    #[test]
+    fn grid_bin() {
+        let grid = HistogramGrid::new(vec![0.0, -1.0], vec![4.0, 1.0], vec![4, 2]);
+        assert_eq!(Some(0), grid.bin(&[0.0, -1.0]));
+        assert_eq!(Some(5), grid.bin(&[1.5, 0.5]));
+        assert_eq!(None, grid.bin(&[4.0, 0.0]));
+        assert_eq!(None, grid.bin(&[-0.1, 0.0]));
+
+        let grid = grid.cyclic(vec![true, false]);
+        assert_eq!(Some(0), grid.bin(&[4.0, -1.0]));
+        assert_eq!(Some(3), grid.bin(&[-0.5, -1.0]));
+        assert_eq!(Some(1), grid.bin(&[9.5, -1.0]));
+    }
+
+    #[test]
+    fn build() {
+        let ds = DatasetBuilder::new(grid(), 300.0).windows(windows()).build().unwrap();
+        assert_eq!(2, ds.num_windows);
+        assert_eq!(4, ds.num_bins);
+        assert_eq!(vec![(0, 1.0), (1, 2.0), (2, 1.0)], ds.histograms[0].bins);
+        assert_eq!(4, ds.histograms[0].num_points);
+        assert_eq!(vec![(1, 1.0), (2, 2.0), (3, 2.0)], ds.histograms[1].bins);
+        assert!(ds.histograms[0].samples.is_empty());
+        assert!(ds.histograms[0].frames.is_empty());
+
+        let ds = DatasetBuilder::new(grid(), 300.0).windows(windows())
+            .keep_samples(true).keep_frames(true).build().unwrap();
+        assert_eq!(vec![0.5, 1.5, 1.5, 2.5], ds.histograms[0].samples);
+        assert_eq!(vec![0, 1, 1, 2], ds.histograms[0].frames);
+        assert!(ds.histograms[0].inefficiency >= 1.0);
+    }
+
+    #[test]
+    fn build_errors() {
+        assert!(DatasetBuilder::new(grid(), 300.0).build().is_err());
+        let window = Window::new(vec![1.0, 2.0], vec![10.0]);
+        assert!(DatasetBuilder::new(grid(), 300.0).window(window).build().is_err());
+        let window = Window::new(vec![1.0], vec![10.0]).sample(vec![1.0, 2.0]);
+        assert!(DatasetBuilder::new(grid(), 300.0).window(window).build().is_err());
+
+        let empty = Window::new(vec![1.0], vec![10.0]).sample(vec![5.0]);
+        let result = DatasetBuilder::new(grid(), 300.0).windows(windows()).window(empty.clone()).build();
+        assert_eq!("No data points in histogram boundaries for window 3.", result.unwrap_err().to_string());
+        let ds = DatasetBuilder::new(grid(), 300.0).windows(windows()).window(empty)
+            .ignore_empty(true).build().unwrap();
+        assert_eq!(3, ds.num_windows);
+
+        let grid = HistogramGrid::new(vec![0.0], vec![0.0], vec![4]);
+        assert!(DatasetBuilder::new(grid, 300.0).windows(windows()).build().is_err());
+    }
+
+    #[test]
+    fn build_potentials() {
+        let window = Window::new(vec![1.0], vec![10.0])
+            .potentials(vec![BiasPotential::FlatBottom { width: 1.0 }])
+            .sample(vec![1.5]);
+        let ds = DatasetBuilder::new(grid(), 300.0).window(window).build().unwrap();
+        // the first two bin centers are within the flat bottom
+        assert_eq!(1.0, ds.get_bias(0, 0));
+        assert_eq!(1.0, ds.get_bias(1, 0));
+        assert!(ds.get_bias(3, 0) < 1.0);
+    }
+
+    #[test]
+    fn solve() {
+        let ds = DatasetBuilder::new(grid(), 300.0).windows(windows()).keep_samples(true)
+            .build().unwrap();
+        let result = Solver::new().solve(&ds).unwrap();
+        assert_eq!(vec![vec![0.5], vec![1.5], vec![2.5], vec![3.5]], result.coords);
+        assert_approx_eq!(1.0, result.probability.iter().sum::<f64>());
+        assert_eq!(0.0, result.free_energy.iter().cloned().fold(f64::INFINITY, f64::min));
+        assert_eq!(2, result.F.len());
+        assert!(result.converged);
+        assert!(result.bootstrap.is_none());
+
+        let mbar = Solver::new().method(Method::Mbar).solve(&ds).unwrap();
+        assert_approx_eq!(1.0, mbar.probability.iter().sum::<f64>());
+        assert_eq!(4, mbar.free_energy.len());
+        assert!(mbar.converged);
+
+        // non-converged results are returned without bootstrapping
+        for method in &[Method::Wham, Method::Mbar] {
+            let result = Solver::new().method(*method).max_iterations(1).bootstrap(8, 1234)
+                .solve(&ds).unwrap();
+            assert!(!result.converged);
+            assert_eq!(1, result.iterations);
+            assert!(result.max_dF() > 0.000_001);
+            assert!(result.bootstrap.is_none());
+        }
+    }
+
+    #[test]
+    fn solve_bootstrap() {
+        let ds = DatasetBuilder::new(grid(), 300.0).windows(windows()).build().unwrap();
+        let result = Solver::new().bootstrap(8, 1234).solve(&ds).unwrap();
+        let bootstrap = result.bootstrap.unwrap();
+        assert_eq!(4, bootstrap.free_energy_std.len());
+        assert_eq!(8, bootstrap.free_energies.len());
+
+        let result = Solver::new().method(Method::Mbar).solve(&ds);
+        assert!(result.unwrap_err().to_string().contains("keep_samples"));
+        let result = Solver::new().bootstrap(8, 1234).bootstrap_scheme(BootstrapScheme::Block).solve(&ds);
+        assert!(result.unwrap_err().to_string().contains("keep_frames"));
+    }
+}
@@ -19,7 +19,7 @@ pub fn statistical_ineff(timeseries: &[f64]) -> f64 {
         if c <= 0.0 {
             break;
         }
-        g = g + (2.0*c*(1.0-t as f64/n as f64))
+        g += 2.0*c*(1.0-t as f64/n as f64)
     }
     if g < 1.0 {
         1.0
@@ -29,11 +29,110 @@ pub fn statistical_ineff(timeseries: &[f64]) -> f64 {
 }
 
 // The autocorrelation time of a timeseries can be deduced from the
-// `statistical_ineff` by (g-1)/2.0 
+// `statistical_ineff` by (g-1)/2.0
 pub fn autocorrelation_time(g: f64) -> f64 {
     (g - 1.0) / 2.0
 }
 
+// minimum number of blocks of a blocking level for its variance to be used
+const MIN_BLOCKS: usize = 32;
+
+// Online estimate of the statistical inefficiency g by blocking analysis
+// (Flyvbjerg & Petersen (1989). Error estimates on averages of correlated
+// data, J. Chem. Phys.). The samples are averaged in blocks of 2^k samples on
+// each level k. The variance of the block averages times the block length
+// grows with k until the blocks are uncorrelated and approaches g times the
+// variance of the samples. Only the sums of each level are stored, so that
+// timeseries of any length can be analyzed while they are read.
+#[derive(Debug,Clone,Default)]
+pub struct BlockingAnalysis {
+    levels: Vec<BlockingLevel>,
+}
+
+#[derive(Debug,Clone,Default)]
+struct BlockingLevel {
+    // first block average of the next pair, which is averaged into a block
+    // of the next level
+    pending: Option<f64>,
+    n: usize,
+    sum: f64,
+    sum_sq: f64,
+}
+
+impl BlockingLevel {
+    fn variance(&self) -> f64 {
+        let n = self.n as f64;
+        let mean = self.sum / n;
+        (self.sum_sq / n - mean * mean).max(0.0) * n / (n - 1.0)
+    }
+}
+
+impl BlockingAnalysis {
+    pub fn new() -> BlockingAnalysis {
+        BlockingAnalysis::default()
+    }
+
+    pub fn push(&mut self, x: f64) {
+        let mut value = x;
+        let mut k = 0;
+        loop {
+            if k == self.levels.len() {
+                self.levels.push(BlockingLevel::default());
+            }
+            let level = &mut self.levels[k];
+            level.n += 1;
+            level.sum += value;
+            level.sum_sq += value * value;
+            match level.pending.take() {
+                Some(first) => value = 0.5 * (first + value),
+                None => {
+                    level.pending = Some(value);
+                    return
+                },
+            }
+            k += 1;
+        }
+    }
+
+    // number of samples
+    pub fn len(&self) -> usize {
+        self.levels.first().map_or(0, |level| level.n)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Statistical inefficiency g, i.e. the largest g_k = 2^k var_k / var_0 up
+    // to the smallest level whose blocks are long enough to be uncorrelated,
+    // 2^3k > 2 N g_k^2 (Lee, Needs & Towler (2011). Strong coupling and the
+    // electron gas, Phys. Rev. B). Like the truncated autocorrelation function
+    // of `statistical_ineff`, anticorrelations at longer lags do not lower the
+    // estimate. If no level meets the criterion, the timeseries is too short
+    // and all levels with enough blocks are used. 1.0 for uncorrelated or
+    // constant timeseries.
+    pub fn statistical_ineff(&self) -> f64 {
+        let var_0 = match self.levels.first() {
+            Some(level) if level.n > 1 => level.variance(),
+            _ => return 1.0,
+        };
+        if var_0 == 0.0 {
+            return 1.0
+        }
+        let n = self.len() as f64;
+        let mut max_g = 1.0;
+        for (k, level) in self.levels.iter().enumerate().filter(|(_, level)| level.n >= MIN_BLOCKS) {
+            let block_len = 2_f64.powi(k as i32);
+            let g = block_len * level.variance() / var_0;
+            max_g = f64::max(max_g, g);
+            if block_len.powi(3) > 2.0 * n * g * g {
+                break
+            }
+        }
+        max_g
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{BufRead, BufReader};
@@ -75,4 +174,39 @@ mod tests {
         assert!((tau - 1.430).abs() < 0.001)
     }
 
+    #[test]
+    fn blocking_analysis() {
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::StdRng;
+
+        // AR(1) process x_n = phi x_{n-1} + noise with g = (1+phi)/(1-phi) = 9
+        let mut rng = StdRng::seed_from_u64(1234);
+        let phi = 0.8;
+        let mut x = 0.0;
+        let mut blocking = super::BlockingAnalysis::new();
+        let mut uncorrelated = super::BlockingAnalysis::new();
+        for _ in 0..100_000 {
+            let noise: f64 = rng.gen_range(-1.0..1.0);
+            x = phi * x + noise;
+            blocking.push(x);
+            uncorrelated.push(noise);
+        }
+        assert_eq!(100_000, blocking.len());
+        let g = blocking.statistical_ineff();
+        assert!((g - 9.0).abs() < 1.5, "{}", g);
+        assert!(uncorrelated.statistical_ineff() < 1.5);
+
+        // same order of magnitude as the autocorrelation function
+        let timeseries = read_timeseries("example/1d_cyclic/COLVAR-2.5.xvg");
+        let mut blocking = super::BlockingAnalysis::new();
+        timeseries.iter().for_each(|x| blocking.push(*x));
+        assert!((blocking.statistical_ineff() - super::statistical_ineff(&timeseries)).abs() < 1.0);
+
+        // constant timeseries
+        let mut blocking = super::BlockingAnalysis::new();
+        assert!(blocking.is_empty());
+        (0..100).for_each(|_| blocking.push(1.0));
+        assert_eq!(1.0, blocking.statistical_ineff());
+    }
+
 }
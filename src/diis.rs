@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use std::f64;
+
+// Direct inversion in the iterative subspace (DIIS), also known as Anderson
+// mixing. WHAM and MBAR are self-consistent fixed point problems f = g(f) for
+// the reduced bias offsets f = F/kT. Plain fixed point iteration converges
+// slowly if neighboring windows overlap strongly. DIIS extrapolates the next
+// offsets from a linear combination of the previous iterates g(f_i) whose
+// residuals r_i = g(f_i) - f_i have the smallest norm:
+// min ||\sum_i{c_i r_i}|| subject to \sum_i{c_i} = 1
+// For details, see "Anderson, D.G. (1965). Iterative procedures for nonlinear
+// integral equations, JACM" and "Walker, H.F. and Ni, P. (2011). Anderson
+// acceleration for fixed-point iterations, SIAM J. Numer. Anal."
+pub struct Diis {
+    // maximum number of stored iterates
+    history: usize,
+
+    // previous fixed point iterates g(f_i)
+    g: VecDeque<Vec<f64>>,
+
+    // previous residuals g(f_i) - f_i
+    r: VecDeque<Vec<f64>>,
+}
+
+impl Diis {
+    pub fn new(history: usize) -> Diis {
+        Diis { history, g: VecDeque::with_capacity(history), r: VecDeque::with_capacity(history) }
+    }
+
+    // forget all stored iterates
+    pub fn reset(&mut self) {
+        self.g.clear();
+        self.r.clear();
+    }
+
+    // Stores the fixed point iterate g = g(f) and overwrites g with the
+    // extrapolated offsets for the next iteration. If the extrapolation is
+    // ill-conditioned, the history is reset and g is left untouched, which
+    // falls back to plain fixed point iteration for this step.
+    #[allow(clippy::needless_range_loop)]
+    pub fn extrapolate(&mut self, f: &[f64], g: &mut [f64]) {
+        if self.history == 0 {
+            return
+        }
+        if self.g.len() == self.history {
+            self.g.pop_front();
+            self.r.pop_front();
+        }
+        self.r.push_back(g.iter().zip(f.iter()).map(|(gi, fi)| gi - fi).collect());
+        self.g.push_back(g.to_vec());
+
+        let n = self.g.len();
+        if n < 2 {
+            return
+        }
+
+        // Lagrange formulation of the constrained least squares problem:
+        // [B 1; 1 0] [c; lambda] = [0; 1] with B_ij = r_i * r_j
+        let mut a = vec![vec![0.0; n+1]; n+1];
+        for i in 0..n {
+            for j in 0..=i {
+                let b: f64 = self.r[i].iter().zip(self.r[j].iter()).map(|(x, y)| x*y).sum();
+                a[i][j] = b;
+                a[j][i] = b;
+            }
+            a[i][n] = 1.0;
+            a[n][i] = 1.0;
+        }
+        // scale B to unit diagonal to improve the condition of the system
+        let scale = (0..n).map(|i| a[i][i]).fold(0.0, f64::max);
+        if scale == 0.0 || !scale.is_finite() {
+            self.reset();
+            return
+        }
+        for row in a.iter_mut().take(n) {
+            for v in row.iter_mut().take(n) {
+                *v /= scale;
+            }
+        }
+        let mut rhs = vec![0.0; n+1];
+        rhs[n] = 1.0;
+
+        match solve_linear_system(a, rhs) {
+            Some(c) => {
+                for (k, gk) in g.iter_mut().enumerate() {
+                    *gk = (0..n).map(|i| c[i] * self.g[i][k]).sum();
+                }
+                if g.iter().any(|x| !x.is_finite()) {
+                    g.copy_from_slice(self.g.back().unwrap());
+                    self.reset();
+                }
+            },
+            None => self.reset(),
+        }
+    }
+}
+
+// Solves the linear system a*x = b by Gaussian elimination with partial
+// pivoting. Returns None if the system is singular.
+#[allow(clippy::needless_range_loop)]
+fn solve_linear_system(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|i, j| {
+            a[*i][col].abs().partial_cmp(&a[*j][col].abs()).unwrap()
+        })?;
+        if a[pivot][col].abs() < 1e-14 {
+            return None
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col+1..n {
+            let factor = a[row][col] / a[col][col];
+            for k in col..n {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row+1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn solve_linear_system() {
+        let a = vec![vec![2.0, 1.0], vec![1.0, 3.0]];
+        let b = vec![3.0, 5.0];
+        let x = super::solve_linear_system(a, b).unwrap();
+        assert_approx_eq!(0.8, x[0]);
+        assert_approx_eq!(1.4, x[1]);
+
+        let singular = vec![vec![1.0, 2.0], vec![2.0, 4.0]];
+        assert!(super::solve_linear_system(singular, vec![1.0, 1.0]).is_none());
+    }
+
+    // DIIS solves a linear fixed point problem g(f) = A*f + b exactly
+    // after dim+1 iterations, while plain iteration converges slowly.
+    #[test]
+    fn extrapolate_linear() {
+        let g = |f: &[f64]| vec![0.9*f[0] + 0.05*f[1] + 1.0, 0.05*f[0] + 0.9*f[1] - 1.0];
+        let mut diis = Diis::new(5);
+        let mut f = vec![0.0, 0.0];
+        for _ in 0..4 {
+            let mut next = g(&f);
+            diis.extrapolate(&f, &mut next);
+            f = next;
+        }
+        // solution of f = g(f)
+        assert_approx_eq!(20.0/3.0, f[0], 0.000_001);
+        assert_approx_eq!(-20.0/3.0, f[1], 0.000_001);
+    }
+
+    #[test]
+    fn extrapolate_disabled() {
+        let mut diis = Diis::new(0);
+        let mut g = vec![1.0, 2.0];
+        diis.extrapolate(&[0.0, 0.0], &mut g);
+        diis.extrapolate(&[1.0, 1.0], &mut g);
+        assert_approx_eq!(1.0, g[0]);
+        assert_approx_eq!(2.0, g[1]);
+    }
+}
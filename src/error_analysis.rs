@@ -1,8 +1,12 @@
 use rand::prelude::*;
-use super::histogram::{Dataset};
-use super::perform_wham;
-use super::{Config,calc_free_energy};
+use rayon::prelude::*;
+use super::histogram::{count_bins,Dataset,Histogram};
+use super::{is_converged,perform_estimation};
+use super::{BootstrapScheme,Config,calc_free_energy};
 use super::statistics;
+use super::log;
+use super::errors::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // returns a set of num_windows continious weights by
 // a) generate num_windows-1 random variables and sort them
@@ -23,47 +27,302 @@ fn generate_random_weights(num_windows: usize, rng: &mut StdRng) -> Vec<f64> {
     weights
 }
 
-// Generate a random weighted dataset from the given dataset by changing the weights
-fn generate_random_weighted_dataset(ds: Dataset, rng: &mut StdRng) -> Dataset {
-    let weights = generate_random_weights(ds.num_windows, rng);
-    Dataset::new_weighted(ds, weights)
+// Random input of a single bootstrap run. The inputs of all runs are drawn in order from the
+// seeded rng before the runs are performed in parallel, so that the results do not depend on
+// the order in which the runs finish.
+enum Replica {
+    // random window weights of the bayesian bootstrap
+    Weights(Vec<f64>),
+    // seed of the rng that resamples the frames of the block and Poisson bootstrap
+    Seed(u64),
 }
 
-// Perform bootstrap error analysis. This runs the WHAM analysis num_runs times on random weighted
-// datasets. The standard deviation is calculated on the bootstrapped probabilities of each bin. The
-// standard deviation of the free eneergy is then deduced by error propagation (A_std = kT*1/P*P_std)
-pub fn run_bootstrap(cfg: &Config, ds: Dataset, num_runs: usize) -> (Vec<f64>,Vec<f64>) {
-    // seed the rng
-    let mut rng: StdRng = SeedableRng::seed_from_u64(cfg.bootstrap_seed);
+// Draw the random input of the next bootstrap run. The bayesian bootstrap draws the weights
+// directly, which keeps its random numbers identical to a sequential bootstrap.
+fn draw_replica(ds: &Dataset, scheme: BootstrapScheme, rng: &mut StdRng) -> Replica {
+    match scheme {
+        BootstrapScheme::Bayesian => Replica::Weights(generate_random_weights(ds.num_windows, rng)),
+        _ => Replica::Seed(rng.gen()),
+    }
+}
 
-    // Calculate bootstrapped probabilities
-    let bootstrapped_Ps: Vec<Vec<f64>> = (0..num_runs).map(|x| {
-        println!("Bootstrap run {}/{}", x, num_runs);
-        let rnd_weighted_dataset = generate_random_weighted_dataset(ds.clone(), &mut rng);
-        perform_wham(cfg, &rnd_weighted_dataset).unwrap().0
-    }).collect();
+// Returns the multiplicity of each of num_frames frames for a moving-block bootstrap. Blocks of
+// block_length consecutive frames are drawn with replacement until num_frames frames are drawn.
+// Blocks should span the statistical inefficiency so that correlated frames are resampled together.
+fn block_counts(num_frames: usize, block_length: usize, rng: &mut StdRng) -> Vec<u32> {
+    let mut counts = vec![0; num_frames];
+    if num_frames == 0 {
+        return counts
+    }
+    let block_length = block_length.max(1).min(num_frames);
+    let mut drawn = 0;
+    while drawn < num_frames {
+        let start = rng.gen_range(0..=num_frames-block_length);
+        for count in counts[start..start+block_length].iter_mut().take(num_frames-drawn) {
+            *count += 1;
+            drawn += 1;
+        }
+    }
+    counts
+}
 
-    // Standard error (SE) of P per bin
-    // SE = SD/sqrt(n)
-    let mut P_se = vec![0.0; ds.num_bins];
-    for bin in 0..ds.num_bins {
-        let Ps = bootstrapped_Ps.iter().map(|window| window[bin]).collect::<Vec<f64>>();
-        P_se[bin] = statistics::sd(&Ps)/(num_runs as f64).sqrt();
+// Poisson distributed random number with mean 1 (Knuth's algorithm)
+fn poisson_one(rng: &mut StdRng) -> u32 {
+    let limit = (-1.0_f64).exp();
+    let mut k = 0;
+    let mut p: f64 = rng.gen();
+    while p > limit {
+        k += 1;
+        p *= rng.gen::<f64>();
     }
+    k
+}
+
+// Build a histogram from the frames of h, where frame i is counted counts[i] times. Samples of
+// binless estimators are resampled accordingly.
+fn resample_frames(ds: &Dataset, h: &Histogram, frames: &[usize], counts: &[u32]) -> Histogram {
+    let dimens = ds.dimens_lengths.len();
+    let mut bins: Vec<(usize, f64)> = Vec::new();
+    let mut samples: Vec<f64> = Vec::new();
+    let mut sample_bias: Vec<f64> = Vec::new();
+    for (i, (frame, count)) in frames.iter().zip(counts.iter()).enumerate() {
+        bins.push((*frame, f64::from(*count)));
+        if !h.samples.is_empty() {
+            for _ in 0..*count {
+                samples.extend_from_slice(&h.samples[i*dimens..(i+1)*dimens]);
+            }
+        }
+        if let Some(b) = h.sample_bias.get(i) {
+            sample_bias.extend(std::iter::repeat_n(*b, *count as usize));
+        }
+    }
+    let num_points = counts.iter().sum();
+    let mut hist = Histogram::new_sparse(num_points, count_bins(bins));
+    hist.samples = samples;
+    hist.sample_bias = sample_bias;
+    hist
+}
 
-    // SE of A
-    let bootstrapped_As: Vec<Vec<f64>> = (0..num_runs).map(|x| {
-        let run_Ps = &bootstrapped_Ps[x];
-        calc_free_energy(&ds, run_Ps)
+// Resample the frames of each window in blocks of g frames (moving-block bootstrap)
+fn generate_block_resampled_dataset(ds: Dataset, rng: &mut StdRng) -> Dataset {
+    let histograms = ds.histograms.iter().map(|h| {
+        let block_length = h.inefficiency.ceil() as usize;
+        let counts = block_counts(h.frames.len(), block_length, rng);
+        resample_frames(&ds, h, &h.frames, &counts)
     }).collect();
-    
-    let mut A_se = vec![0.0; ds.num_bins];
-    for bin in 0..ds.num_bins {
-        let As = bootstrapped_As.iter().map(|window| window[bin]).collect::<Vec<f64>>();
-        A_se[bin] = statistics::sd(&As)/(num_runs as f64).sqrt();
+    Dataset::new_with_histograms(ds, histograms)
+}
+
+// Assign a Poisson distributed weight with mean 1 to every frame of each window (Poisson
+// bootstrap). Without samples, the count n of a bin is replaced by the sum of n Poisson weights.
+// Samples outside of the histogram grid are skipped like in MBAR.
+fn generate_poisson_resampled_dataset(ds: Dataset, rng: &mut StdRng) -> Dataset {
+    let dimens = ds.dimens_lengths.len();
+    let histograms = ds.histograms.iter().map(|h| {
+        if h.samples.is_empty() {
+            let bins: Vec<(usize, f64)> = h.bins.iter().map(|(bin, n)| {
+                (*bin, (0..*n as usize).map(|_| f64::from(poisson_one(rng))).sum())
+            }).filter(|b| b.1 != 0.0).collect();
+            let num_points: f64 = bins.iter().map(|b| b.1).sum();
+            Histogram::new_sparse(num_points as u32, bins)
+        } else {
+            let bins: Vec<Option<usize>> = h.samples.chunks(dimens)
+                .map(|sample| ds.get_bin_for_coords(sample))
+                .collect();
+            let counts: Vec<u32> = bins.iter().map(|bin| {
+                let count = poisson_one(rng);
+                if bin.is_some() { count } else { 0 }
+            }).collect();
+            let frames: Vec<usize> = bins.iter().map(|bin| bin.unwrap_or(0)).collect();
+            resample_frames(&ds, h, &frames, &counts)
+        }
+    }).collect();
+    Dataset::new_with_histograms(ds, histograms)
+}
+
+// Generate a bootstrap sample of the dataset with the given resampling scheme
+fn generate_bootstrap_dataset(ds: Dataset, scheme: BootstrapScheme, replica: &Replica) -> Dataset {
+    match replica {
+        Replica::Weights(weights) => Dataset::new_weighted(ds, weights.clone()),
+        Replica::Seed(seed) => {
+            let mut rng: StdRng = SeedableRng::seed_from_u64(*seed);
+            match scheme {
+                BootstrapScheme::Poisson => generate_poisson_resampled_dataset(ds, &mut rng),
+                _ => generate_block_resampled_dataset(ds, &mut rng),
+            }
+        }
     }
+}
+
+// Percentile confidence interval (lower, upper) of a bootstrapped quantity at the level
+// requested with --bt_ci.
+pub type Interval = (f64, f64);
 
-    (P_se, A_se)
+// Bootstrap errors of P, the free energy A and the bias offsets F together
+// with the results of every bootstrap run.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Bootstrap {
+    pub P_std: Vec<f64>,
+    pub free_energy_std: Vec<f64>,
+    pub F_std: Vec<f64>,
+    // percentile confidence intervals (lower, upper), empty if not requested
+    pub P_ci: Vec<Interval>,
+    pub free_energy_ci: Vec<Interval>,
+    // free energy and relative bias offsets of each converged run
+    pub free_energies: Vec<Vec<f64>>,
+    pub Fs: Vec<Vec<f64>>,
+    // number of runs that did not converge and are excluded from the statistics
+    pub failed: usize,
+}
+
+impl Bootstrap {
+    // zero errors for runs without bootstrapping
+    pub fn none(num_bins: usize, num_windows: usize) -> Bootstrap {
+        Bootstrap {
+            P_std: vec![0.0; num_bins],
+            free_energy_std: vec![0.0; num_bins],
+            F_std: vec![0.0; num_windows],
+            P_ci: Vec::new(),
+            free_energy_ci: Vec::new(),
+            free_energies: Vec::new(),
+            Fs: Vec::new(),
+            failed: 0,
+        }
+    }
+}
+
+// Error of each column of values: the standard deviation (SD) of the bootstrap
+// runs with --bt_sd, otherwise the standard error SE = SD/sqrt(n).
+fn column_errors(cfg: &Config, values: &[Vec<f64>], len: usize) -> Vec<f64> {
+    let scale = if cfg.bootstrap_sd { 1.0 } else { (values.len() as f64).sqrt() };
+    (0..len).map(|i| {
+        let column = values.iter().map(|run| run[i]).collect::<Vec<f64>>();
+        statistics::sd(&column)/scale
+    }).collect()
+}
+
+// Percentile confidence interval of each column of values at the given level
+// in percent, e.g. 95 gives the 2.5th and 97.5th percentile.
+fn column_intervals(values: &[Vec<f64>], len: usize, level: f64) -> Vec<Interval> {
+    let q = (100.0 - level) / 2.0;
+    (0..len).map(|i| {
+        let column = values.iter().map(|run| run[i]).collect::<Vec<f64>>();
+        (statistics::percentile(&column, q), statistics::percentile(&column, 100.0 - q))
+    }).collect()
+}
+
+// domain of the retry seeds ("bt_retry"), which separates them from the bootstrap seeds
+const RETRY_SEED_DOMAIN: u64 = 0x6274_5f72_6574_7279;
+
+// Seed of the retries of a bootstrap run. The bootstrap seed and the run index are hashed
+// together with RETRY_SEED_DOMAIN (SplitMix64 finalizer), so that the retries are independent
+// of the runs of any other bootstrap seed.
+fn retry_seed(seed: u64, run: usize) -> u64 {
+    let mix = |z: u64| {
+        let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    mix(mix(seed ^ RETRY_SEED_DOMAIN).wrapping_add(run as u64))
+}
+
+// Performs a single bootstrap run. If the estimator fails, e.g. because it does not converge
+// within the maximum number of iterations, the run is retried up to cfg.bootstrap_retries times
+// with new random input. The retries draw from an rng seeded with `retry_seed`, so results
+// stay reproducible for a given seed. Returns None if all attempts failed.
+fn bootstrap_run(cfg: &Config, ds: &Dataset, F: &[f64], run: usize, replica: &Replica)
+        -> Option<(Vec<f64>, Vec<f64>)> {
+    let mut retry_rng: StdRng = SeedableRng::seed_from_u64(retry_seed(cfg.bootstrap_seed, run));
+    let mut retry_replica;
+    let mut replica = replica;
+    for attempt in 0..=cfg.bootstrap_retries {
+        if attempt > 0 {
+            retry_replica = draw_replica(ds, cfg.bootstrap_scheme, &mut retry_rng);
+            replica = &retry_replica;
+        }
+        let bootstrap_dataset = generate_bootstrap_dataset(ds.clone(), cfg.bootstrap_scheme, replica);
+        match perform_estimation(cfg, &bootstrap_dataset, Some(F)) {
+            Ok((_, ref F_run, ref F_prev, iterations)) if !is_converged(F_prev, F_run, cfg.tolerance) =>
+                info!("Bootstrap run {} failed (attempt {}/{}): not converged after {} iterations", run,
+                    attempt+1, cfg.bootstrap_retries+1, iterations),
+            Ok((P, F_run, _, _)) => return Some((P, relative_offsets(&F_run))),
+            Err(e) => info!("Bootstrap run {} failed (attempt {}/{}): {}", run, attempt+1,
+                cfg.bootstrap_retries+1, e),
+        }
+    }
+    None
+}
+
+// Perform bootstrap error analysis. This runs the WHAM analysis num_runs times on bootstrap
+// samples of the dataset: random weighted windows (bayesian), frames resampled in blocks of the
+// statistical inefficiency (block) or Poisson weighted frames (poisson). The standard deviation
+// is calculated on the bootstrapped probabilities of each bin and on the free energies of the
+// runs. With --bt_sd the standard deviation is reported, otherwise the standard error.
+// The error of the bias offsets F is calculated on the offsets relative to the first window,
+// because F is only defined up to a constant. Each run starts from the converged bias offsets
+// F of the unweighted dataset. The runs are performed in parallel and are reproducible for a
+// given seed.
+pub fn run_bootstrap(cfg: &Config, ds: Dataset, num_runs: usize, F: &[f64]) -> Result<Bootstrap> {
+    if cfg.bootstrap_scheme == BootstrapScheme::Block
+            && ds.histograms.iter().any(|h| h.frames.len() != h.num_points as usize) {
+        bail!("The block bootstrap requires the frames of each window. Build the dataset with keep_frames.");
+    }
+
+    // seed the rng and draw the random input of each run
+    let mut rng: StdRng = SeedableRng::seed_from_u64(cfg.bootstrap_seed);
+    let replicas: Vec<Replica> = (0..num_runs)
+        .map(|_| draw_replica(&ds, cfg.bootstrap_scheme, &mut rng))
+        .collect();
+
+    // Calculate bootstrapped probabilities and bias offsets
+    let done = AtomicUsize::new(0);
+    let runs: Vec<Option<(Vec<f64>, Vec<f64>)>> = replicas.par_iter()
+        .enumerate()
+        .map(|(x, replica)| {
+            let run = bootstrap_run(cfg, &ds, F, x, replica);
+            log::progress("Bootstrap run", done.fetch_add(1, Ordering::Relaxed) + 1, num_runs);
+            run
+        }).collect();
+    log::finish("Bootstrap run");
+
+    // statistics are calculated from the converged runs only
+    let (bootstrapped_Ps, bootstrapped_Fs): (Vec<Vec<f64>>, Vec<Vec<f64>>) = runs.into_iter()
+        .flatten()
+        .unzip();
+    let failed = num_runs - bootstrapped_Ps.len();
+    if bootstrapped_Ps.len() < 2 {
+        bail!(format!("Only {} of {} bootstrap runs converged. At least 2 are required.",
+            bootstrapped_Ps.len(), num_runs));
+    }
+
+    let bootstrapped_As: Vec<Vec<f64>> = bootstrapped_Ps.iter()
+        .map(|run_Ps| calc_free_energy(&ds, run_Ps))
+        .collect();
+
+    // errors of P, A and F
+    let num_bins = ds.num_coord_bins();
+    let P_std = column_errors(cfg, &bootstrapped_Ps, num_bins);
+    let free_energy_std = column_errors(cfg, &bootstrapped_As, num_bins);
+    let F_std = column_errors(cfg, &bootstrapped_Fs, ds.num_windows);
+
+    let (P_ci, free_energy_ci) = if cfg.bootstrap_ci > 0.0 {
+        (column_intervals(&bootstrapped_Ps, num_bins, cfg.bootstrap_ci),
+            column_intervals(&bootstrapped_As, num_bins, cfg.bootstrap_ci))
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    Ok(Bootstrap {
+        P_std, free_energy_std, F_std, P_ci, free_energy_ci,
+        free_energies: bootstrapped_As,
+        Fs: bootstrapped_Fs,
+        failed,
+    })
+}
+
+// bias offsets shifted so that the offset of the first window is 0
+pub fn relative_offsets(F: &[f64]) -> Vec<f64> {
+    F.iter().map(|f| f - F[0]).collect()
 }
 
 #[cfg(test)]
@@ -71,6 +330,18 @@ mod tests {
     use super::*;
     use super::super::k_B;
     use super::super::histogram::Histogram;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn cfg() -> Config {
+        Config {
+            hist_min: vec![0.0],
+            hist_max: vec![4.0],
+            num_bins: vec![5],
+            dimens: 1,
+            cyclic: vec![false],
+            ..Default::default()
+        }
+    }
 
     fn build_hist() -> Histogram {
         Histogram::new(
@@ -93,7 +364,7 @@ mod tests {
             vec![10.0, 10.0, 10.0], // fc
             300.0*k_B, // kT
             vec![h1, h2, h3], // hists
-            false // cyclic
+            vec![false] // cyclic
         )
     }
 
@@ -112,7 +383,8 @@ mod tests {
     fn random_weighted_dataset() {
         let mut rng = StdRng::from_entropy();
         let ds = build_hist_set();
-        let rnd_weights_ds = generate_random_weighted_dataset(ds, &mut rng);
+        let replica = draw_replica(&ds, BootstrapScheme::Bayesian, &mut rng);
+        let rnd_weights_ds = generate_bootstrap_dataset(ds, BootstrapScheme::Bayesian, &replica);
         println!("{:?}", rnd_weights_ds.weights);
         for w in rnd_weights_ds.weights {
             assert!(w > 0.0);
@@ -120,4 +392,183 @@ mod tests {
         }
     }
 
+    #[test]
+    fn block_counts() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        for (num_frames, block_length) in &[(10, 3), (10, 1), (5, 10), (0, 2)] {
+            let counts = super::block_counts(*num_frames, *block_length, &mut rng);
+            assert_eq!(*num_frames, counts.len());
+            assert_eq!(*num_frames as u32, counts.iter().sum::<u32>());
+        }
+        // a block spanning all frames reproduces the timeseries
+        assert_eq!(vec![1; 5], super::block_counts(5, 10, &mut rng));
+    }
+
+    #[test]
+    fn poisson_one() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        let n = 100_000;
+        let draws: Vec<f64> = (0..n).map(|_| f64::from(super::poisson_one(&mut rng))).collect();
+        assert!((statistics::mean(&draws) - 1.0).abs() < 0.01);
+        assert!((statistics::sd(&draws) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn block_resampled_dataset() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        let mut ds = build_hist_set();
+        for h in ds.histograms.iter_mut() {
+            h.frames = (0..22).map(|i| i % 5).collect();
+            h.inefficiency = 2.5;
+        }
+        let replica = draw_replica(&ds, BootstrapScheme::Block, &mut rng);
+        let resampled = generate_bootstrap_dataset(ds, BootstrapScheme::Block, &replica);
+        for h in resampled.histograms {
+            assert_eq!(22, h.num_points);
+            assert_eq!(22.0, h.bins.iter().map(|b| b.1).sum::<f64>());
+        }
+    }
+
+    #[test]
+    fn poisson_resampled_dataset() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        let ds = build_hist_set();
+        let replica = draw_replica(&ds, BootstrapScheme::Poisson, &mut rng);
+        let resampled = generate_bootstrap_dataset(ds, BootstrapScheme::Poisson, &replica);
+        for h in resampled.histograms {
+            assert_eq!(h.num_points as f64, h.bins.iter().map(|b| b.1).sum::<f64>());
+        }
+
+        // samples of binless estimators are resampled together with the bins
+        let h = Histogram::new_with_samples(3, vec![1.0, 2.0, 0.0, 0.0, 0.0], vec![0.5, 1.5, 1.5]);
+        let ds = Dataset::new(5, vec![5], vec![1.0], vec![0.0], vec![5.0],
+            vec![2.5], vec![10.0], 300.0*k_B, vec![h], vec![false]);
+        let replica = draw_replica(&ds, BootstrapScheme::Poisson, &mut rng);
+        let resampled = generate_bootstrap_dataset(ds, BootstrapScheme::Poisson, &replica);
+        let h = &resampled.histograms[0];
+        assert_eq!(h.num_points as usize, h.samples.len());
+        assert_eq!(h.samples.iter().filter(|x| **x == 0.5).count() as f64, h.count(0));
+        assert_eq!(0.0, h.count(2));
+
+        // samples outside of the grid are skipped
+        let h = Histogram::new_with_samples(3, vec![1.0, 1.0, 0.0, 0.0, 0.0], vec![0.5, 1.5, 5.0]);
+        let ds = Dataset::new(5, vec![5], vec![1.0], vec![0.0], vec![5.0],
+            vec![2.5], vec![10.0], 300.0*k_B, vec![h], vec![false]);
+        let replica = draw_replica(&ds, BootstrapScheme::Poisson, &mut rng);
+        let resampled = generate_bootstrap_dataset(ds, BootstrapScheme::Poisson, &replica);
+        let h = &resampled.histograms[0];
+        assert_eq!(h.num_points as usize, h.samples.len());
+        assert!(h.samples.iter().all(|x| *x < 5.0));
+    }
+
+    // parallel runs give identical results for the same seed
+    #[test]
+    fn run_bootstrap_reproducible() {
+        let mut cfg = cfg();
+        let ds = build_hist_set();
+        let F = vec![0.0; ds.num_windows];
+        for scheme in &[BootstrapScheme::Bayesian, BootstrapScheme::Poisson] {
+            cfg.bootstrap_scheme = *scheme;
+            let first = run_bootstrap(&cfg, ds.clone(), 8, &F).unwrap();
+            let second = run_bootstrap(&cfg, ds.clone(), 8, &F).unwrap();
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn run_bootstrap_block_without_frames() {
+        let mut cfg = cfg();
+        cfg.bootstrap_scheme = BootstrapScheme::Block;
+        let ds = build_hist_set();
+        let F = vec![0.0; ds.num_windows];
+        let result = run_bootstrap(&cfg, ds, 4, &F);
+        assert!(result.unwrap_err().to_string().contains("keep_frames"));
+    }
+
+    #[test]
+    fn run_bootstrap_intervals() {
+        let mut cfg = cfg();
+        let ds = build_hist_set();
+        let F = vec![0.0; ds.num_windows];
+        let se = run_bootstrap(&cfg, ds.clone(), 16, &F).unwrap();
+        assert!(se.P_ci.is_empty());
+        assert_eq!(16, se.free_energies.len());
+        assert_eq!(16, se.Fs.len());
+
+        cfg.bootstrap_sd = true;
+        cfg.bootstrap_ci = 90.0;
+        let sd = run_bootstrap(&cfg, ds.clone(), 16, &F).unwrap();
+        for bin in 0..ds.num_coord_bins() {
+            assert_approx_eq!(se.P_std[bin] * 4.0, sd.P_std[bin]);
+            assert!(sd.P_ci[bin].0 <= sd.P_ci[bin].1);
+            // at most 5% of the 16 runs lie on either side of the interval
+            let (lower, upper) = sd.free_energy_ci[bin];
+            let outside = sd.free_energies.iter().filter(|A| A[bin] < lower || A[bin] > upper).count();
+            assert!(outside <= 2);
+        }
+        assert_eq!(se.free_energies, sd.free_energies);
+        assert_eq!(se.Fs, sd.Fs);
+    }
+
+    // windows at different positions, so that convergence depends on the weights
+    fn build_shifted_hist_set() -> Dataset {
+        let ds = build_hist_set();
+        Dataset::new(5, vec![3], vec![1.0], vec![0.0], vec![9.0], vec![0.5, 1.5, 2.5],
+            vec![10.0, 10.0, 10.0], 300.0*k_B, ds.histograms, vec![false])
+    }
+
+    #[test]
+    fn bootstrap_run_retries() {
+        let mut cfg = cfg();
+        cfg.max_iterations = 90;
+        let ds = build_shifted_hist_set();
+        let F = vec![0.0; ds.num_windows];
+        // without the second window, WHAM needs 100 iterations
+        let replica = Replica::Weights(vec![1.0, 0.0, 1.0]);
+        assert!(bootstrap_run(&cfg, &ds, &F, 0, &replica).is_none());
+        cfg.bootstrap_retries = 1;
+        assert!(bootstrap_run(&cfg, &ds, &F, 0, &replica).is_some());
+    }
+
+    #[test]
+    fn retry_seed() {
+        // retries neither replay other bootstrap seeds nor the retries of other runs
+        let seeds: Vec<u64> = (0..4).flat_map(|seed| (0..4).map(move |run| super::retry_seed(seed, run)))
+            .collect();
+        for (i, seed) in seeds.iter().enumerate() {
+            assert!(*seed > 1000);
+            assert!(seeds[i+1..].iter().all(|other| other != seed));
+        }
+        assert_eq!(super::retry_seed(1234, 3), super::retry_seed(1234, 3));
+    }
+
+    #[test]
+    fn run_bootstrap_failed_runs() {
+        let mut cfg = cfg();
+        cfg.max_iterations = 70;
+        let ds = build_shifted_hist_set();
+        let F = vec![0.0; ds.num_windows];
+        let bootstrap = run_bootstrap(&cfg, ds, 16, &F).unwrap();
+        assert!(bootstrap.failed > 0);
+        assert_eq!(16 - bootstrap.failed, bootstrap.free_energies.len());
+        assert_eq!(16 - bootstrap.failed, bootstrap.Fs.len());
+        assert!(bootstrap.free_energy_std.iter().all(|std| std.is_finite()));
+    }
+
+    #[test]
+    fn run_bootstrap_not_converged() {
+        let mut cfg = cfg();
+        cfg.max_iterations = 5;
+        let ds = build_shifted_hist_set();
+        let F = vec![0.0; ds.num_windows];
+        let result = run_bootstrap(&cfg, ds, 4, &F);
+        assert_eq!("Only 0 of 4 bootstrap runs converged. At least 2 are required.",
+            result.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn relative_offsets() {
+        assert_eq!(vec![0.0, 1.5, -2.0], super::relative_offsets(&[1.0, 2.5, -1.0]));
+    }
+
 }
@@ -1,4 +1,7 @@
 use std::fmt;
+use std::sync::OnceLock;
+use super::bias::BiasPotential;
+use super::bias_cache::{BiasCache, BiasEntries};
 
 // One histogram
 #[derive(Debug,Clone)]
@@ -6,16 +9,73 @@ pub struct Histogram {
     // total number of data points stored in the histogram
     pub num_points: u32,
 
-    // histogram bins
-    pub bins: Vec<f64>
+    // counts of the occupied histogram bins as (flat bin index, count),
+    // sorted by bin. Empty bins are not stored, so that histograms of
+    // high-dimensional grids stay small.
+    pub bins: Vec<(usize, f64)>,
+
+    // raw samples that were binned into the histogram. Only populated for
+    // binless estimators (MBAR). Samples are stored flat with one value per
+    // dimension, i.e. sample i occupies samples[i*dimens..(i+1)*dimens].
+    pub samples: Vec<f64>,
+
+    // bias energy of every sample from a per-frame bias column in kJ/mol, in
+    // the order of the samples. Only populated for binless estimators (MBAR)
+    // of windows with per-frame biases.
+    pub sample_bias: Vec<f64>,
+
+    // flat bin index of every binned sample in the order of the timeseries.
+    // Only populated for the block bootstrap, which resamples the frames.
+    pub frames: Vec<usize>,
+
+    // statistical inefficiency g of the timeseries, i.e. the number of frames
+    // between uncorrelated samples. Sets the block length of the block bootstrap.
+    pub inefficiency: f64,
 }
 
 impl Histogram {
+    // histogram from the counts of all bins
     pub fn new(num_points: u32, bins: Vec<f64>) -> Histogram {
-        Histogram {num_points, bins}
+        Histogram::new_sparse(num_points, sparse_bins(&bins))
+    }
+
+    pub fn new_with_samples(num_points: u32, bins: Vec<f64>, samples: Vec<f64>) -> Histogram {
+        Histogram {samples, ..Histogram::new(num_points, bins)}
+    }
+
+    // histogram from the counts of the occupied bins, sorted by bin
+    pub fn new_sparse(num_points: u32, bins: Vec<(usize, f64)>) -> Histogram {
+        Histogram {num_points, bins, samples: Vec::new(), sample_bias: Vec::new(), frames: Vec::new(),
+            inefficiency: 1.0}
+    }
+
+    // count of a bin, 0 for empty bins
+    pub fn count(&self, bin: usize) -> f64 {
+        self.bins.binary_search_by_key(&bin, |b| b.0).map_or(0.0, |idx| self.bins[idx].1)
     }
 }
 
+// (bin, count) of the occupied bins of dense histogram bins
+fn sparse_bins(bins: &[f64]) -> Vec<(usize, f64)> {
+    bins.iter().cloned().enumerate().filter(|b| b.1 != 0.0).collect()
+}
+
+// Sums the counts of each bin, e.g. (bin, 1.0) for every sample, and returns
+// the counts of the occupied bins sorted by bin. Counts of the same bin are
+// summed in the given order.
+pub fn count_bins(mut counts: Vec<(usize, f64)>) -> Vec<(usize, f64)> {
+    counts.sort_by_key(|c| c.0);
+    let mut bins: Vec<(usize, f64)> = Vec::new();
+    for (bin, n) in counts {
+        match bins.last_mut() {
+            Some(last) if last.0 == bin => last.1 += n,
+            _ => bins.push((bin, n)),
+        }
+    }
+    bins.retain(|b| b.1 != 0.0);
+    bins
+}
+
 // a set of histograms
 #[derive(Debug,Clone)]
 pub struct Dataset {
@@ -37,14 +97,26 @@ pub struct Dataset {
     // width of a bin in unit of its dimension
     bin_width: Vec<f64>,
 
-    // value of kT
+    // value of kT at the target temperature
     pub kT: f64,
 
+    // value of kT for each window. Equals kT unless windows were simulated at
+    // different temperatures.
+    window_kT: Vec<f64>,
+
+    // flag for multi-temperature datasets. If set, the last dimension of the
+    // histogram grid is the potential energy of the samples.
+    pub energy_dimen: bool,
+
+    // reference energy E_0 for the temperature reweighting exp(-(1/kT_i - 1/kT)(E - E_0)).
+    // The reference only shifts the bias offsets, but keeps the bias factors finite.
+    energy_ref: f64,
+
     // histogram for each window
     pub histograms: Vec<Histogram>,
 
-    // flag for cyclic reaction coordinates
-    pub cyclic: bool,
+    // flags for cyclic (periodic) dimensions
+    pub cyclic: Vec<bool>,
 
     // locations of biases
     bias_pos: Vec<f64>,
@@ -52,22 +124,46 @@ pub struct Dataset {
     // force constants of biases
     bias_fc: Vec<f64>,
 
-    // bias value cache
-    bias: Vec<f64>,
+    // type of the bias potential for each window and dimension
+    bias_potentials: Vec<BiasPotential>,
+
+    // Per-frame biases of each window, e.g. time-dependent metadynamics biases,
+    // that add to the bias potential. For each occupied bin of a window, the
+    // frame biases U_n of its samples are combined into the bias energy
+    // kT_i ln <exp(U_n/kT_i)> of the bin, stored as (bin, energy) and sorted by
+    // bin. The bias of a window is unknown in bins without its samples, so
+    // that its bias factor is 0 there. None for windows with static biases
+    // only, and empty if no window has per-frame biases.
+    frame_bias: Vec<Option<Vec<(usize, f64)>>>,
+
+    // Bias values of a window are dropped from the cache where its reduced
+    // bias exceeds the minimum by more than this cutoff in kT. 0 keeps all values.
+    bias_cutoff: f64,
+
+    // cache of the bias values exp(-U/kT) and -U/kT for log-space calculations.
+    // The cache is built on first use, so that it is only built once for the
+    // final potentials and cutoff of the dataset.
+    bias_cache: OnceLock<BiasCache>,
 
     // histogram weight
     pub weights: Vec<f64>,
+
+    // weighted sum of the counts of all windows for each occupied bin, sorted by
+    // bin. The WHAM equations are only evaluated for these bins.
+    bin_counts: Vec<(usize, f64)>,
 }
 
 impl Dataset {
 
     pub fn new(num_bins: usize, dimens_lengths: Vec<usize>, bin_width: Vec<f64>,
         hist_min: Vec<f64>, hist_max: Vec<f64>, bias_pos: Vec<f64>,
-        bias_fc: Vec<f64>, kT: f64, histograms: Vec<Histogram>, cyclic: bool) -> Dataset {
+        bias_fc: Vec<f64>, kT: f64, histograms: Vec<Histogram>, cyclic: Vec<bool>) -> Dataset {
         let num_windows = histograms.len();
-        let bias: Vec<f64> = vec![0.0; num_bins*num_windows];
         let weights = vec![1.0; num_windows];
-        let mut ds = Dataset{
+        let bin_counts = weighted_bin_counts(&histograms, &weights);
+        let bias_potentials = vec![BiasPotential::Harmonic; bias_pos.len()];
+        let window_kT = vec![kT; num_windows];
+        Dataset{
             num_windows,
             num_bins,
             dimens_lengths,
@@ -75,32 +171,114 @@ impl Dataset {
             hist_min,
             hist_max,
             kT,
+            window_kT,
+            energy_dimen: false,
+            energy_ref: 0.0,
             histograms,
             cyclic,
             bias_pos,
             bias_fc,
-            bias,
-            weights
-        };
-        for window in 0..num_windows {
-            for bin in 0..num_bins {
-                let ndx = window * num_bins + bin;
-                ds.bias[ndx] = ds.calc_bias(bin, window);
-            }
+            bias_potentials,
+            frame_bias: Vec::new(),
+            bias_cutoff: 0.0,
+            bias_cache: OnceLock::new(),
+            weights,
+            bin_counts,
+        }
+    }
+
+    // Create a dataset with the given bias potential types instead of harmonic
+    // potentials. The potentials are given for each window and dimension.
+    pub fn new_with_potentials(ds: Dataset, bias_potentials: Vec<BiasPotential>) -> Dataset {
+        Dataset {
+            bias_potentials,
+            bias_cache: OnceLock::new(),
+            ..ds
+        }
+    }
+
+    // Create a multi-temperature dataset from a dataset whose last grid dimension
+    // is the potential energy. window_kT is the value of kT of each window, while
+    // the kT of the dataset is the target temperature of the PMF.
+    pub fn new_with_temperatures(ds: Dataset, window_kT: Vec<f64>) -> Dataset {
+        let e = ds.dimens_lengths.len() - 1;
+        let energy_ref = 0.5 * (ds.hist_min[e] + ds.hist_max[e]);
+        Dataset {
+            window_kT,
+            energy_dimen: true,
+            energy_ref,
+            bias_cache: OnceLock::new(),
+            ..ds
+        }
+    }
+
+    // Create a dataset with a sparse bias cache that drops the bias values of
+    // each window beyond cutoff kT of its minimum. The bias factors of bins far
+    // away from an umbrella are then treated as 0. Bins with samples of a
+    // window are always kept.
+    pub fn new_with_bias_cutoff(ds: Dataset, bias_cutoff: f64) -> Dataset {
+        Dataset {
+            bias_cutoff,
+            bias_cache: OnceLock::new(),
+            ..ds
+        }
+    }
+
+    // Create a dataset with per-frame biases of each window in addition to the
+    // bias potentials, given as (bin, bias energy) of the occupied bins of
+    // each window, or None for windows without per-frame biases.
+    pub fn new_with_frame_bias(ds: Dataset, frame_bias: Vec<Option<Vec<(usize, f64)>>>) -> Dataset {
+        Dataset {
+            frame_bias,
+            bias_cache: OnceLock::new(),
+            ..ds
         }
-        ds
+    }
 
+    fn bias_cache(&self) -> &BiasCache {
+        self.bias_cache.get_or_init(|| {
+            let log_bias = |bin, window| self.calc_log_bias(bin, window);
+            // the unknown per-frame biases outside of the sampled bins are dropped
+            let has_frame_bias = self.frame_bias.iter().any(|b| b.is_some());
+            if self.bias_cutoff > 0.0 || has_frame_bias {
+                let cutoff = if self.bias_cutoff > 0.0 { self.bias_cutoff } else { f64::INFINITY };
+                BiasCache::sparse(self.num_bins, self.num_windows, cutoff, log_bias,
+                    |bin, window| self.histograms[window].count(bin) > 0.0)
+            } else {
+                BiasCache::dense(self.num_bins, self.num_windows, log_bias)
+            }
+        })
     }
 
     pub fn new_weighted(ds: Dataset, weights: Vec<f64>) -> Dataset {
+        let bin_counts = weighted_bin_counts(&ds.histograms, &weights);
         Dataset {
             weights,
+            bin_counts,
+            ..ds
+        }
+    }
+
+    // Create a dataset with resampled histograms of the same windows and bins.
+    // The bias cache is kept, because it does not depend on the histograms.
+    // Resampled histograms only occupy bins of the original ones, so that the
+    // bins kept by a sparse bias cache still include all samples.
+    pub fn new_with_histograms(ds: Dataset, histograms: Vec<Histogram>) -> Dataset {
+        let bin_counts = weighted_bin_counts(&histograms, &ds.weights);
+        Dataset {
+            histograms,
+            bin_counts,
             ..ds
         }
     }
 
     pub fn get_weighted_bin_count(&self, bin: usize) -> f64 {
-        self.histograms.iter().enumerate().map(|(idx,h)| self.weights[idx]*h.bins[bin]).sum()
+        self.bin_counts.binary_search_by_key(&bin, |b| b.0).map_or(0.0, |idx| self.bin_counts[idx].1)
+    }
+
+    // weighted counts of all occupied bins as (bin, count), sorted by bin
+    pub fn occupied_bins(&self) -> &[(usize, f64)] {
+        &self.bin_counts
     }
 
     fn expand_index(&self, bin: usize, lengths: &[usize]) -> Vec<usize> {
@@ -115,6 +293,35 @@ impl Dataset {
         idx
     }
 
+    // number of reaction coordinate dimensions (without the potential energy)
+    pub fn coord_dimens(&self) -> usize {
+        self.dimens_lengths.len() - self.energy_dimen as usize
+    }
+
+    // number of bins of the reaction coordinate grid (without the potential energy)
+    pub fn num_coord_bins(&self) -> usize {
+        if self.energy_dimen {
+            self.num_bins / self.dimens_lengths.last().unwrap()
+        } else {
+            self.num_bins
+        }
+    }
+
+    // Sums the probabilities of a multi-temperature dataset over the potential
+    // energy, which yields the probabilities of the reaction coordinate bins at
+    // the target temperature. Other datasets are returned unchanged.
+    pub fn marginalize_energy(&self, P: Vec<f64>) -> Vec<f64> {
+        if !self.energy_dimen {
+            return P
+        }
+        let num_coord_bins = self.num_coord_bins();
+        let mut P_coord = vec![0.0; num_coord_bins];
+        for (bin, p) in P.iter().enumerate() {
+            P_coord[bin % num_coord_bins] += p;
+        }
+        P_coord
+    }
+
     // get center x value for a bin
     pub fn get_coords_for_bin(&self, bin: usize) -> Vec<f64> {
         self.expand_index(bin, &self.dimens_lengths).iter().enumerate().map(|(i, dimen_bin)| {
@@ -122,41 +329,143 @@ impl Dataset {
         }).collect()
     }
 
+    // get the flat bin index for a set of coordinates or None if the
+    // coordinates are outside of the histogram boundaries.
+    pub fn get_bin_for_coords(&self, coords: &[f64]) -> Option<usize> {
+        let mut bin = 0;
+        let mut stride = 1;
+        for (i, x) in coords.iter().enumerate() {
+            // the upper boundary of cyclic dimensions is the periodic image of the lower one
+            let above = if self.cyclic[i] { *x > self.hist_max[i] } else { *x >= self.hist_max[i] };
+            if *x < self.hist_min[i] || above {
+                return None
+            }
+            let mut idx = ((x - self.hist_min[i]) / self.bin_width[i]) as usize;
+            if self.cyclic[i] {
+                idx %= self.dimens_lengths[i];
+            }
+            bin += idx.min(self.dimens_lengths[i]-1) * stride;
+            stride *= self.dimens_lengths[i];
+        }
+        Some(bin)
+    }
+
     pub fn get_bias(&self, bin: usize, window: usize) -> f64 {
-        let ndx = window * self.num_bins + bin;
-        self.bias[ndx]
+        self.bias_cache().bias(bin, window)
+    }
+
+    pub fn get_log_bias(&self, bin: usize, window: usize) -> f64 {
+        self.bias_cache().log_bias(bin, window)
     }
 
-    // Harmonic bias calculation: bias = 0.5*k(dx)^2
-    // if cyclic is true, lowest and highest bins are assumed to be
-    // neighbors. This returns exp(U/kT) instead of U for better performance.
+    // Cached bias values of all windows in a bin as (window, exp(-U/kT), -U/kT).
+    // Windows whose bias values were dropped by the cutoff are skipped.
+    pub fn bin_bias_entries(&self, bin: usize) -> BiasEntries<'_> {
+        self.bias_cache().bin_entries(bin)
+    }
+
+    // Cached bias values of the bins of a window as (bin, exp(-U/kT), -U/kT).
+    // Bins whose bias values were dropped by the cutoff are skipped, and so are
+    // the empty bins of a dense cache. All occupied bins within the cutoff are
+    // included.
+    pub fn window_bias_entries(&self, window: usize) -> BiasEntries<'_> {
+        self.bias_cache().window_entries(window, &self.bin_counts)
+    }
+
+    // Returns true if any cached bias value exp(-U/kT) is too small to be
+    // represented as a normal f64, or overflows. WHAM should be performed in
+    // log-space then. Values dropped by the cutoff are exactly 0 and do not
+    // underflow.
+    pub fn bias_underflows(&self) -> bool {
+        self.bias_cache().values().iter().any(|b| *b < f64::MIN_POSITIVE || b.is_infinite())
+    }
+
+    // Bias factor of a window for a bin. This returns exp(-U/kT) instead of
+    // U for better performance.
+    #[cfg(test)]
     fn calc_bias(&self, bin: usize, window: usize) -> f64 {
-        let dimens = self.dimens_lengths.len();
-        // index of the bias value depends on the window und dimension
-        let bias_ndx: Vec<usize> = (0..dimens)
-            .map(|dimen| { window * dimens + dimen }).collect();
+        self.calc_log_bias(bin, window).exp()
+    }
 
-        // find the N coords, force constants and bias coords
+    // Logarithm of the bias factor: -U/kT
+    fn calc_log_bias(&self, bin: usize, window: usize) -> f64 {
         let coord = self.get_coords_for_bin(bin);
-        let bias_fc: Vec<f64> = bias_ndx.iter().map(|ndx| { self.bias_fc[*ndx] }).collect();
-        let bias_pos: Vec<f64> = bias_ndx.iter().map(|ndx| { self.bias_pos[*ndx] }).collect();
+        -self.calc_reduced_bias(&coord, window)
+    }
+
+    // Reduced bias u_i = U_i/kT_i of a window at the given coordinates. For
+    // multi-temperature datasets, the last coordinate is the potential energy E
+    // and the bias includes the reweighting from the window temperature to the
+    // target temperature: u_i = U_i/kT_i + (1/kT_i - 1/kT)(E - E_0)
+    pub fn calc_reduced_bias(&self, coord: &[f64], window: usize) -> f64 {
+        self.reduce_bias(self.calc_bias_energy(coord, window), coord, window)
+    }
+
+    // Reduced bias of a window at one of its own samples with a known per-frame
+    // bias energy, which replaces the average frame bias of its bin.
+    pub fn calc_reduced_sample_bias(&self, coord: &[f64], window: usize, frame_bias: f64) -> f64 {
+        self.reduce_bias(self.calc_potential_energy(coord, window) + frame_bias, coord, window)
+    }
+
+    fn reduce_bias(&self, bias_energy: f64, coord: &[f64], window: usize) -> f64 {
+        let window_kT = self.window_kT[window];
+        let mut u = bias_energy/window_kT;
+        if self.energy_dimen {
+            let energy = coord[self.dimens_lengths.len() - 1] - self.energy_ref;
+            u += (1.0/window_kT - 1.0/self.kT) * energy;
+        }
+        u
+    }
+
+    // Bias energy U of a window at the given coordinates. The energy is the
+    // sum of the bias potentials and the average per-frame bias of the bin of
+    // the coordinates, which is infinite in bins without samples of the window.
+    pub fn calc_bias_energy(&self, coord: &[f64], window: usize) -> f64 {
+        let energy = self.calc_potential_energy(coord, window);
+        match self.frame_bias.get(window) {
+            Some(Some(frame_bias)) => {
+                let bin_bias = self.get_bin_for_coords(coord)
+                    .and_then(|bin| frame_bias.binary_search_by_key(&bin, |b| b.0).ok())
+                    .map_or(f64::INFINITY, |idx| frame_bias[idx].1);
+                energy + bin_bias
+            },
+            _ => energy,
+        }
+    }
 
+    // Energy of the bias potentials of a window at the given coordinates,
+    // i.e. the sum of the potentials in each dimension, e.g. 0.5*k(dx)^2 for
+    // harmonic potentials. For cyclic dimensions, lowest and highest bins are
+    // assumed to be neighbors.
+    fn calc_potential_energy(&self, coord: &[f64], window: usize) -> f64 {
+        // unbiased dimensions like the potential energy are ignored
+        let dimens = self.bias_pos.len() / self.num_windows;
         let mut bias_sum = 0.0;
-        for i in 0..dimens {
-            let mut dist = (coord[i] - bias_pos[i]).abs();
-            if self.cyclic { // periodic conditions
+        for (i, x) in coord.iter().enumerate().take(dimens) {
+            // index of the bias value depends on the window und dimension
+            let ndx = window * dimens + i;
+            let mut dist = x - self.bias_pos[ndx];
+            if self.cyclic[i] { // periodic conditions
                 let hist_len = self.hist_max[i] - self.hist_min[i];
                 if dist > 0.5 * hist_len {
                     dist -= hist_len;
+                } else if dist < -0.5 * hist_len {
+                    dist += hist_len;
                 }
             }
-            // store exp(U/kT) for better performance
-            bias_sum += 0.5 * bias_fc[i] * dist * dist
+            bias_sum += self.bias_potentials[ndx].energy(dist, self.bias_fc[ndx])
         }
-        (-bias_sum/self.kT).exp()
+        bias_sum
     }
 }
 
+// Sums the weighted counts of all histograms for each occupied bin
+fn weighted_bin_counts(histograms: &[Histogram], weights: &[f64]) -> Vec<(usize, f64)> {
+    count_bins(histograms.iter().zip(weights.iter())
+        .flat_map(|(h, w)| h.bins.iter().map(move |(bin, n)| (*bin, w * n)))
+        .collect())
+}
+
 impl fmt::Display for Dataset {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut datapoints: u32 = 0;
@@ -197,7 +506,7 @@ mod tests {
             vec![10.0], // fc
             300.0*k_B, // kT
             vec![h], // hists
-            false // cyclic
+            vec![false] // cyclic
         )
     }
 
@@ -218,7 +527,7 @@ mod tests {
     #[test]
     fn calc_biascyclic() {
         let mut ds = build_hist_set();
-        ds.cyclic = true;
+        ds.cyclic = vec![true];
 
         // 7th element -> x=3.5, x0=3.5
         assert_delta!(0.134_722_337_796, ds.calc_bias(3, 0), 0.000_000_01);
@@ -235,16 +544,195 @@ mod tests {
         assert_delta!(0.000_000_01, ds.calc_bias(1, 0), 0.000_000_01);
     }
 
+    #[test]
+    fn sparse_histogram() {
+        let h = build_hist();
+        assert_eq!(5, h.bins.len());
+        let h = Histogram::new(3, vec![0.0, 2.0, 0.0, 1.0]);
+        assert_eq!(vec![(1, 2.0), (3, 1.0)], h.bins);
+        assert_eq!(2.0, h.count(1));
+        assert_eq!(0.0, h.count(2));
+
+        let counts = vec![(3, 1.0), (1, 1.0), (3, 0.5), (2, 0.0)];
+        assert_eq!(vec![(1, 1.0), (3, 1.5)], count_bins(counts));
+    }
+
+    #[test]
+    fn occupied_bins() {
+        let h1 = Histogram::new(3, vec![0.0, 2.0, 0.0, 1.0, 0.0]);
+        let h2 = Histogram::new(2, vec![0.0, 0.0, 0.0, 1.0, 1.0]);
+        let ds = Dataset::new(5, vec![5], vec![1.0], vec![0.0], vec![5.0], vec![1.5, 3.5],
+            vec![10.0, 10.0], 300.0*k_B, vec![h1, h2], vec![false]);
+        assert_eq!(&[(1, 2.0), (3, 2.0), (4, 1.0)], ds.occupied_bins());
+        let ds = Dataset::new_weighted(ds, vec![0.5, 2.0]);
+        assert_eq!(&[(1, 1.0), (3, 2.5), (4, 2.0)], ds.occupied_bins());
+        assert_eq!(2.5, ds.get_weighted_bin_count(3));
+        assert_eq!(0.0, ds.get_weighted_bin_count(0));
+    }
+
+    #[test]
+    fn bias_cutoff() {
+        // reduced bias u = 0, 2.0, 8.0, 18.0, 32.1 for x=4.5, 3.5, ... 0.5
+        let ds = build_hist_set();
+        let h = Histogram::new(18, vec![1.0, 0.0, 0.0, 5.0, 12.0]);
+        let ds = Dataset::new_with_bias_cutoff(Dataset::new_with_histograms(ds, vec![h]), 5.0);
+        assert_delta!(0.134_722_337_796, ds.get_bias(3, 0), 0.000_000_01);
+        assert_eq!(1.0, ds.get_bias(4, 0));
+        assert_eq!(0.0, ds.get_bias(2, 0));
+        assert_eq!(f64::NEG_INFINITY, ds.get_log_bias(1, 0));
+        assert_eq!(0, ds.bin_bias_entries(2).count());
+
+        // bin 0 is beyond the cutoff, but has samples of the window
+        let bins: Vec<usize> = ds.window_bias_entries(0).map(|(bin, _, _)| bin).collect();
+        assert_eq!(vec![0, 3, 4], bins);
+        assert_delta!(ds.calc_bias(0, 0), ds.get_bias(0, 0), 1e-20);
+    }
+
+    #[test]
+    fn frame_bias() {
+        // per-frame bias energies of the window in bins 3 and 4, no static bias
+        let h = Histogram::new(17, vec![0.0, 0.0, 0.0, 5.0, 12.0]);
+        let ds = Dataset::new(5, vec![5], vec![1.0], vec![0.0], vec![5.0], vec![4.5], vec![0.0],
+            300.0*k_B, vec![h], vec![false]);
+        let ds = Dataset::new_with_frame_bias(ds, vec![Some(vec![(3, 1.5), (4, 0.0)])]);
+        assert_delta!(1.5, ds.calc_bias_energy(&[3.5], 0), 0.000_000_01);
+        assert_delta!(1.0, ds.get_bias(4, 0), 0.000_000_01);
+        assert_delta!((-1.5/ds.kT).exp(), ds.get_bias(3, 0), 0.000_000_01);
+
+        // the bias of bins without samples of the window is unknown
+        assert_eq!(f64::INFINITY, ds.calc_bias_energy(&[0.5], 0));
+        assert_eq!(0.0, ds.get_bias(0, 0));
+        let bins: Vec<usize> = ds.window_bias_entries(0).map(|(bin, _, _)| bin).collect();
+        assert_eq!(vec![3, 4], bins);
+
+        // the exact bias of a sample replaces the bin average
+        assert_delta!(2.0/ds.kT, ds.calc_reduced_sample_bias(&[3.5], 0, 2.0), 0.000_000_01);
+    }
+
+    #[test]
+    fn calc_bias_flat_bottom() {
+        let ds = build_hist_set();
+        let ds = Dataset::new_with_potentials(ds, vec![BiasPotential::FlatBottom { width: 1.0 }]);
+
+        // x=4.5, x0=4.5 and x=3.5, x0=4.5 are inside the flat bottom
+        assert_delta!(1.0, ds.calc_bias(4, 0), 0.000_000_01);
+        assert_delta!(1.0, ds.calc_bias(3, 0), 0.000_000_01);
+
+        // x=2.5, x0=4.5 -> U = 0.5*10*1^2
+        assert_delta!((-5.0/(300.0*k_B)).exp(), ds.calc_bias(2, 0), 0.000_000_01);
+        assert_delta!((-5.0/(300.0*k_B)).exp(), ds.get_bias(2, 0), 0.000_000_01);
+    }
+
+    #[test]
+    fn calc_bias_energy_cyclic_walls() {
+        let mut ds = build_hist_set();
+        ds.cyclic = vec![true];
+        ds.bias_pos = vec![1.0];
+        let ds = Dataset::new_with_potentials(ds, vec![BiasPotential::UpperWall { exponent: 2.0 }]);
+
+        // x=2.5, x0=1.0 -> wall is active
+        assert_delta!(22.5, ds.calc_bias_energy(&[2.5], 0), 0.000_000_01);
+        // x=0.5, x0=1.0 -> below the wall
+        assert_delta!(0.0, ds.calc_bias_energy(&[0.5], 0), 0.000_000_01);
+        // x=8.5, x0=1.0 -> periodic image at x=-0.5 is below the wall
+        assert_delta!(0.0, ds.calc_bias_energy(&[8.5], 0), 0.000_000_01);
+    }
+
+    // 2 coordinate bins x 2 energy bins, window 0 at the target temperature
+    // and window 1 at twice the target temperature.
+    fn build_multi_temperature_set() -> Dataset {
+        let h1 = Histogram::new(4, vec![1.0, 1.0, 1.0, 1.0]);
+        let h2 = Histogram::new(4, vec![1.0, 1.0, 1.0, 1.0]);
+        let ds = Dataset::new(4, vec![2, 2], vec![1.0, 10.0], vec![0.0, -10.0],
+            vec![2.0, 10.0], vec![0.5, 1.5], vec![0.0, 0.0], 1.0, vec![h1, h2], vec![false, false]);
+        Dataset::new_with_temperatures(ds, vec![1.0, 2.0])
+    }
+
+    #[test]
+    fn calc_bias_multi_temperature() {
+        let ds = build_multi_temperature_set();
+        assert_eq!(1, ds.coord_dimens());
+        assert_eq!(2, ds.num_coord_bins());
+
+        // no reweighting for the window at the target temperature
+        for bin in 0..4 {
+            assert_delta!(1.0, ds.get_bias(bin, 0), 0.000_000_01);
+        }
+        // bin 2: x=0.5, E=5 -> exp(-(1/2 - 1)*5)
+        assert_delta!((2.5f64).exp(), ds.get_bias(2, 1), 0.000_000_01);
+        assert_delta!(2.5, ds.get_log_bias(2, 1), 0.000_000_01);
+        // bin 1: x=1.5, E=-5 -> exp(-(1/2 - 1)*-5)
+        assert_delta!((-2.5f64).exp(), ds.get_bias(1, 1), 0.000_000_01);
+    }
+
+    #[test]
+    fn marginalize_energy() {
+        let ds = build_multi_temperature_set();
+        let P = ds.marginalize_energy(vec![0.1, 0.2, 0.3, 0.4]);
+        assert_eq!(2, P.len());
+        assert_delta!(0.4, P[0], 0.000_000_01);
+        assert_delta!(0.6, P[1], 0.000_000_01);
+
+        // datasets without energy dimension are unchanged
+        let ds = build_hist_set();
+        assert_eq!(5, ds.marginalize_energy(vec![0.2; 5]).len());
+    }
+
     #[test]
     fn get_x_for_bin() {
         let ds = build_hist_set();
-        let expected: Vec<f64> = vec![0,1,2,3,4,5,6,7,8].iter()
+        let expected: Vec<f64> = [0,1,2,3,4,5,6,7,8].iter()
                 .map(|x| *x as f64 + 0.5).collect();
         expected.iter().enumerate().for_each(|(i, exp)| {
             assert_approx_eq!(exp, &ds.get_coords_for_bin(i)[0]);
         })
     }
 
+    #[test]
+    fn get_bin_for_coords() {
+        let ds = Dataset::new(
+            5, // num bins
+            vec![5],
+            vec![1.0], // bin width
+            vec![0.0], // hist min
+            vec![5.0], // hist max
+            vec![4.5], // x0
+            vec![10.0], // fc
+            300.0*k_B, // kT
+            vec![build_hist()], // hists
+            vec![false] // cyclic
+        );
+        assert_eq!(Some(0), ds.get_bin_for_coords(&[0.0]));
+        assert_eq!(Some(3), ds.get_bin_for_coords(&[3.5]));
+        assert_eq!(Some(4), ds.get_bin_for_coords(&[4.99]));
+        assert_eq!(None, ds.get_bin_for_coords(&[-0.1]));
+        assert_eq!(None, ds.get_bin_for_coords(&[5.0]));
+    }
+
+    #[test]
+    fn get_bin_for_coords_cyclic() {
+        let h = Histogram::new(1, vec![0.0; 10]);
+        let ds = Dataset::new(10, vec![5, 2], vec![1.0, 1.0], vec![0.0, 0.0],
+            vec![5.0, 2.0], vec![1.0, 1.0], vec![1.0, 1.0], 300.0*k_B, vec![h],
+            vec![true, false]);
+        // upper boundary of the periodic dimension is the first bin
+        assert_eq!(Some(5), ds.get_bin_for_coords(&[5.0, 1.5]));
+        assert_eq!(Some(9), ds.get_bin_for_coords(&[4.5, 1.5]));
+        // upper boundary of the non-periodic dimension is outside
+        assert_eq!(None, ds.get_bin_for_coords(&[0.5, 2.0]));
+    }
+
+    #[test]
+    fn calc_bias_energy_mixed_cyclic() {
+        let h = Histogram::new(1, vec![0.0; 100]);
+        let ds = Dataset::new(100, vec![10, 10], vec![1.0, 1.0], vec![0.0, 0.0],
+            vec![10.0, 10.0], vec![0.5, 0.5], vec![2.0, 2.0], 300.0*k_B, vec![h],
+            vec![true, false]);
+        // x=9.5 is 1.0 away from x0=0.5 in the periodic dimension, but 9.0 in the other
+        assert_delta!(1.0, ds.calc_bias_energy(&[9.5, 0.5], 0), 0.000_000_01);
+        assert_delta!(81.0, ds.calc_bias_energy(&[0.5, 9.5], 0), 0.000_000_01);
+    }
+
     #[test]
     fn get_bin_count() {
         let ds = Dataset::new(
@@ -257,7 +745,7 @@ mod tests {
             vec![10.0, 10.0], // fc
             300.0*k_B, // kT
             vec![build_hist(), build_hist()], // hists
-            false // cyclic
+            vec![false] // cyclic
         );
         assert_delta!(2.0, ds.get_weighted_bin_count(0), 0.000_000_000_1);
         assert_delta!(2.0, ds.get_weighted_bin_count(1), 0.000_000_000_1);
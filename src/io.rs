@@ -1,14 +1,22 @@
 use super::histogram::Dataset;
-use super::histogram::Histogram;
-use super::Config;
-use super::correlation_analysis::{statistical_ineff, autocorrelation_time};
+use super::histogram::{count_bins, Histogram};
+use super::bias::BiasPotential;
+use super::thermodynamics::Thermodynamics;
+use super::error_analysis::Bootstrap;
+use super::{BootstrapScheme, Config, Method, WhamResult};
+use super::correlation_analysis::{statistical_ineff, autocorrelation_time, BlockingAnalysis};
+use super::statistics::log_sum_exp;
+use super::timeseries::{self, TimeseriesReader};
 use std::fs::OpenOptions;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader,BufWriter};
 use k_B;
 use std::path::Path;
+use std::collections::BTreeMap;
+use std::iter;
 use super::errors::*;
+use super::log::{self, LogLevel};
 use f64;
 
 // Returns the path to path2 relative to path1
@@ -20,19 +28,294 @@ fn get_relative_path(path1: &str, path2: &str) -> String {
     path1.parent().unwrap().join(path2).to_str().unwrap().to_string()
 }
 
-pub fn vprintln(s: String, verbose: bool) {
-    if verbose {
-        println!("{}", s);
+// A window of the input, described by a line of the metadata file or of the
+// GROMACS pull parameters
+struct WindowInput {
+    // path of the time series file
+    path: String,
+    // columns of the time series with the time and the reaction coordinates,
+    // followed by the per-frame bias energy if frame_bias is set
+    data_columns: Vec<usize>,
+    frame_bias: bool,
+    // periodic reaction coordinates according to the time series header
+    periodic: Vec<Option<Periodic>>,
+    // bias positions, force constants, temperature and options
+    columns: Vec<String>,
+    // line of the description for error messages
+    origin: String,
+}
+
+// Reads the windows of a metadata file. Each line holds the path of a time
+// series followed by the bias parameters. The columns of the time series are
+// selected by a columns=time,x_1,..,x_N option of the line or the selection
+// of all windows, and so is the column of per-frame biases (bias_column=).
+fn read_metadata_windows(metadata_file: &str, selection: &[String], bias_column: Option<&str>,
+        dimens: usize) -> Result<Vec<WindowInput>> {
+    let f = File::open(metadata_file).chain_err(|| "Failed to open metadata file")?;
+    let buf = BufReader::new(&f);
+
+    let mut windows = Vec::new();
+    for (line_num, l) in buf.lines().enumerate() {
+        let line = l.chain_err(|| "Failed to read line")?;
+
+        // skip comments and empty lines
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+
+        let mut split = line.split_whitespace().map(|val| val.to_string());
+        let path = get_relative_path(metadata_file, &split.next().unwrap_or_default());
+        let (column_options, columns): (Vec<String>, Vec<String>) = split
+            .partition(|val| val.starts_with("columns=") || val.starts_with("bias_column="));
+        let mut line_selection = selection.to_vec();
+        let mut line_bias_column = bias_column.map(|c| c.to_string());
+        for option in column_options {
+            match option.strip_prefix("columns=") {
+                Some(val) => line_selection = val.split(',').map(|c| c.to_string()).collect(),
+                None => line_bias_column = Some(option["bias_column=".len()..].to_string()),
+            }
+        }
+        let origin = format!("line {} of metadata file", line_num+1);
+        // the header is only read if columns are selected
+        let header = if line_selection.is_empty() && line_bias_column.is_none() {
+            TimeseriesHeader::default()
+        } else {
+            read_timeseries_header(&path)
+                .chain_err(|| format!("Failed to read the header of {} in {}", &path, origin))?
+        };
+        let (mut data_columns, periodic) = select_columns(&header, &path, &line_selection, dimens)
+            .chain_err(|| format!("Failed to select the columns of {} in {}", &path, origin))?;
+        if let Some(ref column) = line_bias_column {
+            data_columns.push(select_bias_column(&header, &path, column)
+                .chain_err(|| format!("Failed to select the bias column of {} in {}", &path, origin))?);
+        }
+        let frame_bias = line_bias_column.is_some();
+        windows.push(WindowInput { path, data_columns, frame_bias, periodic, columns, origin });
+    }
+    Ok(windows)
+}
+
+// Reads the windows of GROMACS pull code output, similar to gmx wham -ix/-it.
+// pull_files lists the pullx.xvg (or pullf.xvg) files of the windows and
+// pull_params the bias parameters of the windows in the same order, with
+// lines like the metadata file but without the path. The columns of pull
+// coordinates 1..dimens are found by the legends of the xvg files, unless
+// columns are selected.
+fn read_pull_windows(pull_files: &str, pull_params: &str, selection: &[String],
+        bias_column: Option<&str>, dimens: usize) -> Result<Vec<WindowInput>> {
+    let paths: Vec<String> = read_list(pull_files)
+        .chain_err(|| format!("Failed to read pull files {}", pull_files))?
+        .into_iter().map(|(_, line)| get_relative_path(pull_files, &line)).collect();
+    let params = read_list(pull_params)
+        .chain_err(|| format!("Failed to read pull parameters {}", pull_params))?;
+    if paths.len() != params.len() {
+        bail!(format!("{} lists {} pull files, but {} has parameters of {} windows.",
+            pull_files, paths.len(), pull_params, params.len()));
+    }
+
+    paths.into_iter().zip(params).map(|(path, (line_num, line))| {
+        let header = read_timeseries_header(&path)
+            .chain_err(|| format!("Failed to read the legend of {}", &path))?;
+        let mut data_columns = if selection.is_empty() {
+            find_xvg_pull_columns(&header, &path, dimens)
+        } else {
+            select_columns(&header, &path, selection, dimens).map(|(columns, _)| columns)
+        }.chain_err(|| format!("Failed to read the legend of {}", &path))?;
+        if let Some(column) = bias_column {
+            data_columns.push(select_bias_column(&header, &path, column)?);
+        }
+        Ok(WindowInput {
+            path,
+            data_columns,
+            frame_bias: bias_column.is_some(),
+            periodic: vec![None; dimens],
+            columns: line.split_whitespace().map(|val| val.to_string()).collect(),
+            origin: format!("line {} of pull parameters {}", line_num+1, pull_params),
+        })
+    }).collect()
+}
+
+// Lines of a list file with their line number. Comments and empty lines are
+// skipped.
+fn read_list(list_file: &str) -> Result<Vec<(usize, String)>> {
+    let f = File::open(list_file).chain_err(|| format!("Failed to open {}", list_file))?;
+    let mut lines = Vec::new();
+    for (line_num, l) in BufReader::new(&f).lines().enumerate() {
+        let line = l.chain_err(|| "Failed to read line")?;
+        let line = line.trim();
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        lines.push((line_num, line.to_string()));
+    }
+    Ok(lines)
+}
+
+// Column names and periodic columns given in the header of a time series
+#[derive(Default)]
+struct TimeseriesHeader {
+    // name of each column, e.g. from the FIELDS line of a PLUMED COLVAR file
+    // or the legends of an xvg file. Empty if the header has no names.
+    fields: Vec<String>,
+    periodic: Vec<Periodic>,
+}
+
+// A periodic column of a time series header with its period, if given
+#[derive(Debug,Clone,PartialEq)]
+struct Periodic {
+    name: String,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl TimeseriesHeader {
+    // Finds a column by its name, or by its index starting with 0 for the
+    // time if no column has this name.
+    fn column(&self, column: &str) -> Option<usize> {
+        self.fields.iter().position(|field| field == column)
+            .or_else(|| column.parse().ok())
+    }
+
+    fn periodic(&self, column: usize) -> Option<Periodic> {
+        let field = self.fields.get(column)?;
+        self.periodic.iter().find(|p| p.name == *field).cloned()
+    }
+
+    fn periodic_mut(&mut self, name: &str) -> &mut Periodic {
+        if let Some(idx) = self.periodic.iter().position(|p| p.name == name) {
+            return &mut self.periodic[idx]
+        }
+        self.periodic.push(Periodic { name: name.to_string(), min: None, max: None });
+        self.periodic.last_mut().unwrap()
+    }
+}
+
+// Reads the header of a time series before the first data line. PLUMED COLVAR
+// files name their columns with "#! FIELDS time phi psi" and mark periodic
+// ones with "#! SET periodic_phi true" or the period of periodic CVs, e.g.
+// "#! SET min_phi -pi" and "#! SET max_phi pi". In xvg files, the legend of
+// set sN names column N+1.
+fn read_timeseries_header(window_file: &str) -> Result<TimeseriesHeader> {
+    let mut buf = timeseries::open_file(window_file)?;
+
+    // NumPy arrays have no column names
+    let mut header = TimeseriesHeader::default();
+    if timeseries::is_npy(&mut buf)? {
+        return Ok(header)
+    }
+    for l in buf.lines() {
+        let line = l.chain_err(|| "Failed to read line")?;
+        if let Some(annotation) = line.strip_prefix("#!") {
+            let split: Vec<&str> = annotation.split_whitespace().collect();
+            match split.first() {
+                Some(&"FIELDS") if header.fields.is_empty() => {
+                    header.fields = split[1..].iter().map(|field| field.to_string()).collect();
+                },
+                Some(&"SET") if split.len() > 2 => {
+                    let (key, value) = (split[1], split[2]);
+                    if let Some(name) = key.strip_prefix("periodic_") {
+                        if value != "false" {
+                            header.periodic_mut(name);
+                        }
+                    } else if let Some(name) = key.strip_prefix("min_") {
+                        header.periodic_mut(name).min = parse_period_bound(value);
+                    } else if let Some(name) = key.strip_prefix("max_") {
+                        header.periodic_mut(name).max = parse_period_bound(value);
+                    }
+                },
+                _ => (),
+            }
+        } else if line.starts_with('@') {
+            if let Some((set, legend)) = parse_xvg_legend(&line) {
+                if header.fields.len() < set + 2 {
+                    header.fields.resize(set + 2, String::new());
+                }
+                header.fields[set + 1] = legend;
+            }
+        } else if !line.starts_with('#') && !line.trim().is_empty() {
+            break
+        }
+    }
+    Ok(header)
+}
+
+// Parses a boundary of a period as written by PLUMED, e.g. "-pi" or "3.14".
+fn parse_period_bound(value: &str) -> Option<f64> {
+    match value.to_ascii_lowercase().as_str() {
+        "pi" => Some(f64::consts::PI),
+        "-pi" => Some(-f64::consts::PI),
+        value => value.parse().ok(),
     }
 }
 
+// Resolves the selected columns of a time series, given by name or index in
+// its header. The first column is the time, followed by the reaction
+// coordinates (and the potential energy). Without selection, the reaction
+// coordinates follow the time. Returns the column indices and the selected
+// periodic reaction coordinates according to the header.
+fn select_columns(header: &TimeseriesHeader, window_file: &str, selection: &[String], dimens: usize)
+        -> Result<(Vec<usize>, Vec<Option<Periodic>>)> {
+    if selection.is_empty() {
+        return Ok(((0..dimens+1).collect(), vec![None; dimens]))
+    }
+    if selection.len() != dimens+1 {
+        bail!(format!("Expected {} columns (time and {} reaction coordinates), got {}.",
+            dimens+1, dimens, selection.len()));
+    }
+    let columns = selection.iter().map(|column| header.column(column)
+        .chain_err(|| format!("No column {} in {}.", column, window_file)))
+        .collect::<Result<Vec<usize>>>()?;
+    let periodic = columns[1..].iter().map(|c| header.periodic(*c)).collect();
+    Ok((columns, periodic))
+}
+
+// Finds the column of per-frame bias energies by name or index, e.g.
+// metad.bias or restraint.bias of a PLUMED COLVAR file.
+fn select_bias_column(header: &TimeseriesHeader, window_file: &str, column: &str) -> Result<usize> {
+    header.column(column)
+        .chain_err(|| format!("No column {} in {}.", column, window_file))
+}
+
+// Finds the columns of the time and pull coordinates 1..dimens in an xvg file
+// written by the GROMACS pull code. The legend of a pull coordinate value is
+// its number, while reference values, components and center of mass positions
+// have longer legends, e.g. "1 ref" or "1 dZ". Without legends, the
+// coordinates are expected to follow the time.
+fn find_xvg_pull_columns(header: &TimeseriesHeader, xvg_file: &str, dimens: usize) -> Result<Vec<usize>> {
+    if header.fields.is_empty() {
+        return Ok((0..dimens+1).collect())
+    }
+
+    let coords = (1..dimens+1).map(|coord| {
+        header.fields.iter().position(|legend| *legend == coord.to_string())
+            .chain_err(|| format!("No column of pull coordinate {} in the legend of {}.", coord, xvg_file))
+    });
+    iter::once(Ok(0)).chain(coords).collect()
+}
+
+// Parses a legend line of an xvg file, e.g. '@ s0 legend "1"', into the set
+// number and its legend.
+fn parse_xvg_legend(line: &str) -> Option<(usize, String)> {
+    let mut split = line[1..].split_whitespace();
+    let set = split.next()?;
+    if !set.starts_with('s') || split.next()? != "legend" {
+        return None
+    }
+    let set = set[1..].parse().ok()?;
+    let legend: Vec<&str> = split.collect();
+    Some((set, legend.join(" ").trim_matches('"').to_string()))
+}
+
 // Read input data into a histogram set by iterating over input files
 // given in the metadata file. This generates at least one Dataset,
 // or multiple Datasets if convdt is set in the config
 pub fn read_data(cfg: &Config) -> Result<Vec<Dataset>> {
     let mut bias_pos: Vec<f64> = Vec::new();
     let mut bias_fc: Vec<f64> = Vec::new();
+    let mut bias_potentials: Vec<BiasPotential> = Vec::new();
+    let mut temperatures: Vec<f64> = Vec::new();
     let mut timeseries_lengths: Vec<usize> = Vec::new();
+    let mut discarded_points: Vec<u32> = Vec::new();
     let mut paths = Vec::new();
 
     // Boundaries of individual histograms if convdt is set.
@@ -42,70 +325,170 @@ pub fn read_data(cfg: &Config) -> Result<Vec<Dataset>> {
     // for each timeseries, histograms are build for slices according to
     // start..convdt, start..2*convdt, ...
     let mut histograms =  vec![Vec::new(); dataset_boundaries.len()];
+    // per-frame biases of each window by bin, if given in the timeseries
+    let mut frame_biases = vec![Vec::new(); dataset_boundaries.len()];
+
+    // Timeseries are read and binned with the potential energy as additional
+    // dimension for multi-temperature datasets.
+    let mut hist_cfg = energy_config(cfg);
 
     let kT = cfg.temperature * k_B;
-    let bin_width: Vec<f64> = (0..cfg.dimens).map(|idx| {
-            (cfg.hist_max[idx] - cfg.hist_min[idx])/(cfg.num_bins[idx] as f64)
+    let bin_width: Vec<f64> = (0..hist_cfg.dimens).map(|idx| {
+            (hist_cfg.hist_max[idx] - hist_cfg.hist_min[idx])/(hist_cfg.num_bins[idx] as f64)
         }).collect();
-    let num_bins: usize = cfg.num_bins.iter().product();
-    let dimens_length = cfg.num_bins.clone();
-
-    let f = File::open(&cfg.metadata_file).chain_err(|| "Failed to open metadata file")?;
-    let buf = BufReader::new(&f);
+    let num_bins: usize = hist_cfg.num_bins.iter().product();
+    let dimens_length = hist_cfg.num_bins.clone();
 
-    // read each metadata file line and parse it
-    for (line_num,l) in buf.lines().enumerate() {
-        let line = l.chain_err(|| "Failed to read line")?;
-
-        // skip comments and empty lines
-        if line.starts_with('#') || line.is_empty() {
-            continue;
+    let windows = match cfg.pullx_files.as_ref().or(cfg.pullf_files.as_ref()) {
+        Some(pull_files) => {
+            if cfg.energy_bins > 0 {
+                bail!("GROMACS pull input has no potential energy for windows at different temperatures.")
+            }
+            let pull_params = cfg.pull_params.as_ref()
+                .chain_err(|| "GROMACS pull input requires the pull parameters (--it).")?;
+            read_pull_windows(pull_files, pull_params, &cfg.columns, cfg.bias_column.as_deref(), cfg.dimens)?
+        },
+        None => read_metadata_windows(&cfg.metadata_file, &cfg.columns, cfg.bias_column.as_deref(),
+            hist_cfg.dimens)?,
+    };
+
+    // periodic reaction coordinates of the time series headers are cyclic if
+    // the histogram spans their period
+    for window in &windows {
+        for (d, periodic) in window.periodic.iter().enumerate() {
+            let periodic = match *periodic {
+                Some(ref periodic) if !hist_cfg.cyclic[d] => periodic,
+                _ => continue,
+            };
+            let period_matches = |bound: Option<f64>, hist_bound: f64|
+                bound.is_none_or(|b| (b - hist_bound).abs() <= 0.5 * bin_width[d]);
+            if period_matches(periodic.min, hist_cfg.hist_min[d]) && period_matches(periodic.max, hist_cfg.hist_max[d]) {
+                verbose!("Dimension {} ({}) is periodic according to {}.", d+1, periodic.name, window.path);
+                hist_cfg.cyclic[d] = true;
+            } else {
+                info!("Warning: {} is periodic in {}, but the histogram does not span its period. \
+                    Dimension {} is not treated as cyclic.", periodic.name, window.path, d+1);
+            }
         }
+    }
+    let pull_forces = cfg.pullx_files.is_none() && cfg.pullf_files.is_some();
 
-        let split: Vec<&str> = line.split_whitespace().collect();
-        if split.len() < 1 + cfg.dimens * 2 {
-            bail!(format!("Wrong number of columns in line {} of metadata file. Empty Line?", line_num+1));
+    for window in windows {
+        let split = &window.columns;
+        if split.len() < cfg.dimens * 2 {
+            bail!(format!("Wrong number of columns in {}. Empty Line?", window.origin));
         }
 
         // parse bias force constants and positions
-        for val in split.iter().skip(1).take(cfg.dimens) {
-            let pos = val.parse()
-                .chain_err(|| format!("Failed to read bias position in line {} of metadata file", line_num+1))?;
-            bias_pos.push(pos);
+        let mut window_pos = Vec::new();
+        let mut window_fc = Vec::new();
+        for val in split.iter().take(cfg.dimens) {
+            let pos: f64 = val.parse()
+                .chain_err(|| format!("Failed to read bias position in {}", window.origin))?;
+            window_pos.push(pos);
         }
-        for val in split.iter().skip(1+cfg.dimens).take(cfg.dimens) {
-            let fc = val.parse()
-                .chain_err(|| format!("Failed to read bias fc in line {} of metadata file", line_num+1))?;
-            bias_fc.push(fc);
+        for val in split.iter().skip(cfg.dimens).take(cfg.dimens) {
+            let fc: f64 = val.parse()
+                .chain_err(|| format!("Failed to read bias fc in {}", window.origin))?;
+            window_fc.push(fc);
+        }
+
+        // parse the optional window temperature (T=<K>) and bias potential
+        // types given as key=value options after the force constants
+        let (temperature_options, options): (Vec<&str>, Vec<&str>) = split.iter().skip(2*cfg.dimens)
+            .filter(|val| val.contains('=')).map(|val| val.as_str())
+            .partition(|val| val.starts_with("T="));
+        let temperature = match temperature_options.last() {
+            Some(val) => val["T=".len()..].parse()
+                .chain_err(|| format!("Failed to read temperature in {}", window.origin))?,
+            None => cfg.temperature,
+        };
+        temperatures.push(temperature);
+
+        let potentials = parse_bias_options(&options, cfg.dimens)
+            .chain_err(|| format!("Failed to read bias options in {}", window.origin))?;
+        bias_potentials.extend(potentials);
+
+        if pull_forces && window_fc.contains(&0.0) {
+            bail!(format!("Cannot calculate positions from the forces in {} with a force constant of 0.", &window.path))
         }
 
         // parse histogram data
-        let path = get_relative_path(&cfg.metadata_file, split[0]);
+        let path = window.path;
         paths.push(path.clone());
-        let (timeseries, timeseries_initial_lengths) = read_window_file(&path, cfg)
+        if cfg.stream {
+            let pull = if pull_forces { Some((&window_pos[..], &window_fc[..])) } else { None };
+            let streamed = stream_window_file(&path, &window.data_columns, &hist_cfg, &dataset_boundaries,
+                pull, window.frame_bias, temperature * k_B)
+                .chain_err(|| format!("Failed to read time series from {}", &path))?;
+            bias_pos.extend(window_pos);
+            bias_fc.extend(window_fc);
+            timeseries_lengths.push(streamed.num_frames);
+            for (idx, (hist, frame_bias)) in streamed.histograms.into_iter().zip(streamed.frame_bias).enumerate() {
+                histograms[idx].push(hist);
+                frame_biases[idx].push(frame_bias);
+            }
+            verbose!("{}, {} data points added.", &path, histograms[num_datasets-1].last().unwrap().num_points);
+            discarded_points.push(streamed.discarded);
+            continue
+        }
+        let (mut timeseries, timeseries_initial_lengths) = read_window_file(&path, &window.data_columns, &hist_cfg)
             .chain_err(|| format!("Failed to read time series from {}", &path))?;
         timeseries_lengths.push(timeseries_initial_lengths);
 
+        // pull forces f = -fc*(x - x_0) are converted to positions
+        if pull_forces {
+            for (d, ts) in timeseries[1..cfg.dimens+1].iter_mut().enumerate() {
+                for x in ts.iter_mut() {
+                    *x = window_pos[d] - *x / window_fc[d];
+                }
+            }
+        }
+        bias_pos.extend(window_pos);
+        bias_fc.extend(window_fc);
+
+        // the block bootstrap resamples blocks of correlated frames
+        let inefficiency = if keep_frames(cfg) {
+            timeseries[1..hist_cfg.dimens+1].iter().map(|ts| statistical_ineff(ts)).fold(1.0, f64::max)
+        } else {
+            1.0
+        };
+
+        let mut discarded = 0;
         for (idx, interval) in dataset_boundaries.iter().enumerate() {
             // build histogram for slice start.._stop
             let (start, stop) = interval;
             let timeseries_mask: Vec<bool> = (0..timeseries[0].len()).map(|i| {
                 is_in_time_boundaries(timeseries[0][i], *start, *stop)
             }).collect();
-            let hist = build_histogram_from_timeseries(&timeseries, &timeseries_mask, cfg);
+            let (mut hist, num_discarded) = build_histogram_from_timeseries(&timeseries, &timeseries_mask, &hist_cfg);
+            hist.inefficiency = inefficiency;
             histograms[idx].push(hist);
+            frame_biases[idx].push(if window.frame_bias {
+                Some(frame_bias_by_bin(&timeseries, &timeseries_mask, &hist_cfg, temperature * k_B))
+            } else {
+                None
+            });
+            discarded = num_discarded;
 
             if (cfg.convdt == 0.00) || idx+1 == num_datasets {
-                vprintln(format!("{}, {} data points added.",
-                    &path, histograms[idx].last().unwrap().num_points), cfg.verbose);
+                verbose!("{}, {} data points added.",
+                    &path, histograms[idx].last().unwrap().num_points);
                 break
             }
         }
+        discarded_points.push(discarded);
+    }
+
+    if cfg.energy_bins == 0 && temperatures.iter().any(|t| (t - cfg.temperature).abs() > 1e-9) {
+        bail!("Windows at different temperatures require the potential energy in the timeseries \
+            and an energy histogram (--emin, --emax, --ebins).")
     }
+    let window_kT: Vec<f64> = temperatures.iter().map(|t| t * k_B).collect();
 
     // Datasets are created from histograms.
     // Empty histograms result in an error when its the final dataset, and a warning otherwise.
-    vprintln(format!("Generating {} datasets from histograms.", num_datasets), cfg.verbose);
+    verbose!("Generating {} datasets from histograms.", num_datasets);
     let datasets: Vec<Dataset> = histograms.into_iter().enumerate().map(|(dataset_idx, dataset_histograms)| {
         for (hs, path) in dataset_histograms.iter().zip(&paths) {
             if hs.num_points == 0 {
@@ -115,19 +498,32 @@ pub fn read_data(cfg: &Config) -> Result<Vec<Dataset>> {
                 if dataset_idx+1 == num_datasets {
                     let warning = warning + " This is the final dataset.";
                     if cfg.ignore_empty {
-                        eprintln!("{}", warning);
+                        info!("{}", warning);
                     } else {
                         bail!(warning);
                     }
                 } else {
-                    eprintln!("{}", warning);
+                    info!("{}", warning);
                 }
             }
         }
 
-        Ok(Dataset::new(num_bins, dimens_length.clone(), bin_width.clone(),
-            cfg.hist_min.clone(), cfg.hist_max.clone(), bias_pos.clone(),
-            bias_fc.clone(), kT, dataset_histograms, cfg.cyclic))
+        let mut ds = Dataset::new(num_bins, dimens_length.clone(), bin_width.clone(),
+            hist_cfg.hist_min.clone(), hist_cfg.hist_max.clone(), bias_pos.clone(),
+            bias_fc.clone(), kT, dataset_histograms, hist_cfg.cyclic.clone());
+        if cfg.energy_bins > 0 {
+            ds = Dataset::new_with_temperatures(ds, window_kT.clone());
+        }
+        if !bias_potentials.iter().all(|b| *b == BiasPotential::Harmonic) {
+            ds = Dataset::new_with_potentials(ds, bias_potentials.clone());
+        }
+        if frame_biases[dataset_idx].iter().any(|b| b.is_some()) {
+            ds = Dataset::new_with_frame_bias(ds, frame_biases[dataset_idx].clone());
+        }
+        if cfg.bias_cutoff > 0.0 {
+            ds = Dataset::new_with_bias_cutoff(ds, cfg.bias_cutoff);
+        }
+        Ok(ds)
     }).collect::<Result<Vec<Dataset>>>().chain_err(|| "Failed to create datasets.")?;
 
     if datasets.is_empty() {
@@ -136,35 +532,104 @@ pub fn read_data(cfg: &Config) -> Result<Vec<Dataset>> {
         bail!("Dataset has no associated data points.")
     } else {
         if datasets.len() > 1 {
-            println!("Datasets:");
-            println!("Dataset\t\tTime interval\t\tWindows\t\tN_total");
+            info!("Datasets:");
+            info!("Dataset\t\tTime interval\t\tWindows\t\tN_total");
             for (idx, dataset) in datasets.iter().enumerate() {
                 let n: u32 = dataset.histograms.iter().map(|h| h.num_points).sum();
                 let mut stop = cfg.start+cfg.convdt*(idx+1) as f64;
                 if stop > cfg.end {
                     stop = cfg.end;
                 }
-                println!("{:?}\t\t{:?}-{:?}\t\t{:?}\t\t{:?}", idx+1, cfg.start, stop, dataset.histograms.len(), n);
+                info!("{:?}\t\t{:?}-{:?}\t\t{:?}\t\t{:?}", idx+1, cfg.start, stop, dataset.histograms.len(), n);
             }
         }
 
         let histograms = &datasets.last().unwrap().histograms;
+        if discarded_points.iter().any(|n| *n > 0) {
+            info!("Data points outside of the histogram boundaries (discarded):");
+            info!("Window\t\tN_discarded\tN_discarded/N\tFile");
+            for (idx, (n, h)) in discarded_points.iter().zip(histograms.iter()).enumerate() {
+                if *n > 0 {
+                    info!("{:?}\t\t{:?}\t\t{:.4}\t\t{}",
+                        idx+1, n, *n as f64 / (*n + h.num_points) as f64, &paths[idx]);
+                }
+            }
+        }
         if cfg.uncorr {
-            println!("Timeseries Correlation:");
-            println!("Window\t\tN\t\tN_uncorr\tN/N_uncorr");
+            info!("Timeseries Correlation:");
+            info!("Window\t\tN\t\tN_uncorr\tN/N_uncorr");
             for (idx, (n, h)) in timeseries_lengths.iter().zip(histograms.iter()).enumerate() {
-                println!("{:?}\t\t{:?}\t\t{:?}\t\t{:.2}",
+                info!("{:?}\t\t{:?}\t\t{:?}\t\t{:.2}",
                     idx+1, n, h.num_points, h.num_points as f64 / *n as f64);
             }
             let total_n = timeseries_lengths.iter().sum::<usize>() as f64;
             let total_h = histograms.iter().map(|h| h.num_points).sum::<u32>() as f64;
-            println!("\t\t\t\t\tTotal:\t{:.2}", total_h/total_n);
+            info!("\t\t\t\t\tTotal:\t{:.2}", total_h/total_n);
         }
 
         Ok(datasets)
     }
 }
 
+// Returns the config used to read and bin timeseries. For multi-temperature
+// datasets, the potential energy follows the reaction coordinates in the
+// timeseries and is binned as an additional histogram dimension.
+fn energy_config(cfg: &Config) -> Config {
+    let mut hist_cfg = cfg.clone();
+    if cfg.energy_bins > 0 {
+        hist_cfg.dimens += 1;
+        hist_cfg.hist_min.push(cfg.energy_min);
+        hist_cfg.hist_max.push(cfg.energy_max);
+        hist_cfg.num_bins.push(cfg.energy_bins);
+        hist_cfg.cyclic.push(false);
+    }
+    hist_cfg
+}
+
+// Parses the bias potential options of a metadata line. Options are given as
+// key=value pairs after the bias force constants, e.g.
+// "bias=flat_bottom,harmonic width=0.5,0". Values are comma-separated with one
+// value per dimension, or a single value that applies to all dimensions.
+// Supported keys are bias (potential type), width (flat-bottom width) and
+// exp (wall exponent). Without options, all potentials are harmonic.
+fn parse_bias_options(options: &[&str], dimens: usize) -> Result<Vec<BiasPotential>> {
+    let mut names = vec!["harmonic".to_string(); dimens];
+    let mut widths: Vec<Option<f64>> = vec![None; dimens];
+    let mut exponents: Vec<Option<f64>> = vec![None; dimens];
+
+    for option in options {
+        let (key, value) = option.split_at(option.find('=').unwrap());
+        let values: Vec<&str> = value[1..].split(',').collect();
+        if values.len() != 1 && values.len() != dimens {
+            bail!(format!("Expected 1 or {} values for option {}", dimens, key));
+        }
+        let per_dimen = |d: usize| if values.len() == 1 { values[0] } else { values[d] };
+        match key {
+            "bias" => for (d, name) in names.iter_mut().enumerate() {
+                *name = per_dimen(d).to_string();
+            },
+            "width" | "exp" => for d in 0..dimens {
+                let val: f64 = per_dimen(d).parse()
+                    .chain_err(|| format!("Failed to parse value of option {}", key))?;
+                if key == "width" {
+                    widths[d] = Some(val);
+                } else {
+                    exponents[d] = Some(val);
+                }
+            },
+            _ => bail!(format!("Unknown option: {}", key)),
+        }
+    }
+
+    names.iter().enumerate().map(|(d, name)| {
+        let param = match name.to_ascii_lowercase().as_str() {
+            "flat_bottom" | "flatbottom" => widths[d],
+            _ => exponents[d],
+        };
+        BiasPotential::from_name(name, param)
+    }).collect()
+}
+
 // builds a time boundaries for datasets from convdt, start and end
 fn get_convdt_boundaries(start: f64, end: f64, convdt: f64) -> Vec<(f64, f64)> {
     if convdt == 0.0 {
@@ -182,39 +647,116 @@ fn get_convdt_boundaries(start: f64, end: f64, convdt: f64) -> Vec<(f64, f64)> {
     }
 }
 
-// build a histogram from a timeseries
-// mask is used to filter the timeseries for selected frames
+// Builds a histogram from the masked part of the timeseries. Samples of cyclic
+// dimensions are folded into the primary image [hist_min, hist_max) before
+// binning, while samples outside of non-periodic dimensions are discarded.
+// Returns the histogram and the number of discarded samples.
 fn build_histogram_from_timeseries(timeseries: &[Vec<f64>], mask: &[bool],
-    cfg: &Config) -> Histogram {
-
-    // total number of bins is the product of all dimensions length
-    let total_bins = cfg.num_bins.iter().product();
+    cfg: &Config) -> (Histogram, u32) {
 
     // bin width for each dimension: (max-min)/bins
     let bin_width: Vec<f64> = (0..cfg.dimens).map(|idx| {
         (cfg.hist_max[idx] - cfg.hist_min[idx])/(cfg.num_bins[idx] as f64)
     }).collect();
 
+    // binless estimators require the raw samples in addition to the histogram
+    let keep_samples = cfg.method == Method::Mbar;
+    let mut samples: Vec<f64> = Vec::new();
+    let mut frames: Vec<usize> = Vec::new();
+
     // build histogram for slice start..convdt_stop
-    let mut hist = vec![0.0; total_bins];
+    let mut counts: Vec<(usize, f64)> = Vec::new();
+    let mut sample_bias: Vec<f64> = Vec::new();
+    let mut discarded = 0;
     for i in (0..timeseries[0].len()).filter(|i| mask[*i]) {
-        let mut values: Vec<f64> = vec![f64::NAN; cfg.dimens+1];
-        for j in 0..values.len() {
-            values[j] = timeseries[j][i];
+        let (values, bin) = frame_bin(frame(timeseries, i, cfg), cfg, &bin_width);
+        if let Some(index) = bin {
+            counts.push((index, 1.0));
+            if keep_samples {
+                samples.extend_from_slice(&values[1..]);
+                // per-frame biases follow the reaction coordinates
+                if let Some(bias) = timeseries.get(cfg.dimens+1) {
+                    sample_bias.push(bias[i]);
+                }
+            }
+            if keep_frames(cfg) {
+                frames.push(index);
+            }
+        } else {
+            discarded += 1;
         }
+    }
 
-        if is_in_hist_boundaries(&values[1..], cfg) {
-            let bin_indeces: Vec<usize> = (0..cfg.dimens).map(|dimen: usize| {
-                let val = values[dimen+1];
-                ((val - cfg.hist_min[dimen]) / bin_width[dimen]) as usize
-            }).collect();
-            let index = flat_index(&bin_indeces, &cfg.num_bins);
-            hist[index] += 1.0;
-        }
+    let num_points = counts.len() as u32;
+    let mut histogram = Histogram::new_sparse(num_points, count_bins(counts));
+    histogram.samples = samples;
+    histogram.sample_bias = sample_bias;
+    histogram.frames = frames;
+    (histogram, discarded)
+}
+
+// time and coordinates of frame i of the timeseries
+fn frame(timeseries: &[Vec<f64>], i: usize, cfg: &Config) -> Vec<f64> {
+    timeseries[..cfg.dimens+1].iter().map(|ts| ts[i]).collect()
+}
+
+// Time and coordinates of a frame, with the coordinates of cyclic dimensions
+// folded into the histogram, and the flat bin index of the frame or None if it
+// is outside of the histogram boundaries.
+fn frame_bin(mut values: Vec<f64>, cfg: &Config, bin_width: &[f64]) -> (Vec<f64>, Option<usize>) {
+    for dimen in (0..cfg.dimens).filter(|d| cfg.cyclic[*d]) {
+        values[dimen+1] = wrap_periodic(values[dimen+1], cfg.hist_min[dimen], cfg.hist_max[dimen]);
+    }
+
+    if !is_in_hist_boundaries(&values[1..], cfg) {
+        return (values, None)
     }
+    let bin_indeces: Vec<usize> = (0..cfg.dimens).map(|dimen: usize| {
+        let val = values[dimen+1];
+        let idx = ((val - cfg.hist_min[dimen]) / bin_width[dimen]) as usize;
+        // samples at the upper boundary of cyclic dimensions belong to the first bin
+        if cfg.cyclic[dimen] {
+            idx % cfg.num_bins[dimen]
+        } else {
+            idx
+        }
+    }).collect();
+    let index = flat_index(&bin_indeces, &cfg.num_bins);
+    (values, Some(index))
+}
+
+// Combines the per-frame bias energies U_n in the column after the reaction
+// coordinates into the bias energy kT ln <exp(U_n/kT)> of each occupied bin.
+// This is the bias of a static potential in the limit of small bins. Returns
+// (bin, energy) sorted by bin.
+fn frame_bias_by_bin(timeseries: &[Vec<f64>], mask: &[bool], cfg: &Config, kT: f64) -> Vec<(usize, f64)> {
+    let bin_width: Vec<f64> = (0..cfg.dimens).map(|idx| {
+        (cfg.hist_max[idx] - cfg.hist_min[idx])/(cfg.num_bins[idx] as f64)
+    }).collect();
+    let bias = &timeseries[cfg.dimens+1];
+    let mut frames: Vec<(usize, f64)> = (0..timeseries[0].len()).filter(|i| mask[*i])
+        .filter_map(|i| frame_bin(frame(timeseries, i, cfg), cfg, &bin_width).1.map(|bin| (bin, bias[i]/kT)))
+        .collect();
+    frames.sort_by_key(|f| f.0);
+    frames.chunk_by(|a, b| a.0 == b.0).map(|bin_frames| {
+        let n = bin_frames.len() as f64;
+        (bin_frames[0].0, kT * (log_sum_exp(bin_frames.iter().map(|f| f.1)) - n.ln()))
+    }).collect()
+}
 
-    let num_points: f64 = hist.iter().sum();
-    Histogram::new(num_points as u32, hist)    
+// the bin of every frame is only required to resample frames in blocks
+fn keep_frames(cfg: &Config) -> bool {
+    cfg.bootstrap > 0 && cfg.bootstrap_scheme == BootstrapScheme::Block
+}
+
+// Folds a value of a periodic dimension into the primary image [min, max).
+// Values inside the boundaries are returned unchanged.
+fn wrap_periodic(val: f64, min: f64, max: f64) -> f64 {
+    if val >= min && val < max {
+        val
+    } else {
+        min + (val - min).rem_euclid(max - min)
+    }
 }
 
 // transforms a multidimensional index into a one dimensional index
@@ -228,10 +770,17 @@ fn flat_index(indeces: &[usize], lengths: &[usize]) -> usize {
     }).sum()
 }
 
-// returns true if the values are inside the histogram boundaries defined by cfg
+// returns true if the values are inside the histogram boundaries defined by cfg.
+// The upper boundary of cyclic dimensions is the periodic image of the lower one
+// and therefore inside the boundaries.
 fn is_in_hist_boundaries(values: &[f64], cfg: &Config) -> bool {
-    for dimen in 0..cfg.dimens {
-        if values[dimen] < cfg.hist_min[dimen] || values[dimen] >= cfg.hist_max[dimen] {
+    for (dimen, val) in values.iter().enumerate().take(cfg.dimens) {
+        let above = if cfg.cyclic[dimen] {
+            *val > cfg.hist_max[dimen]
+        } else {
+            *val >= cfg.hist_max[dimen]
+        };
+        if *val < cfg.hist_min[dimen] || above {
             return false
         }
     }
@@ -246,9 +795,10 @@ fn is_in_time_boundaries(time: f64, start: f64, end: f64) -> bool {
     false
 }
 
-// parse a time series file
-fn read_window_file(window_file: &str, cfg: &Config) -> Result<(Vec<Vec<f64>>, usize)> {
-    let mut timeseries: Vec<Vec<f64>> = read_timeseries(window_file, cfg)?;
+// Parses the time and the reaction coordinates in the given columns of a time
+// series file.
+fn read_window_file(window_file: &str, columns: &[usize], cfg: &Config) -> Result<(Vec<Vec<f64>>, usize)> {
+    let mut timeseries: Vec<Vec<f64>> = read_timeseries(window_file, columns)?;
 
     // filter the timeseries based on start/end parameters
     let time_series_mask: Vec<bool> = timeseries[0].iter()
@@ -265,7 +815,7 @@ fn read_window_file(window_file: &str, cfg: &Config) -> Result<(Vec<Vec<f64>>, u
 
     let timeseries_inital_length = timeseries[0].len();
     if cfg.uncorr {
-        timeseries = uncorrelate(timeseries, cfg);
+        timeseries = uncorrelate(timeseries, cfg.dimens);
     }
 
     if timeseries[0].is_empty() && !cfg.ignore_empty {
@@ -276,41 +826,16 @@ fn read_window_file(window_file: &str, cfg: &Config) -> Result<(Vec<Vec<f64>>, u
 }
 
 // Read a multidimensional timeseries
-// The resulting vector contains one vector per dimension
-fn read_timeseries(window_file: &str, cfg: &Config) -> Result<Vec<Vec<f64>>> {
-    let f = File::open(window_file)
-        .chain_err(|| format!("Failed to open sample data file {}.", window_file))?;
-    let mut buf = BufReader::new(&f);
-
-    let mut timeseries = vec![Vec::new(); cfg.dimens+1];
-
-    // read and parse each timeseries line
-    let mut line = String::new();
-    let mut linecount = 0;
-    while buf.read_line(&mut line).chain_err(|| "Failed to read line")? > 0 {
-        linecount += 1;
-
-        // skip comments and empty lines
-        if line.starts_with('#') || line.starts_with('@') || line.is_empty() {
-            line.clear();
-            continue;
-        }
-
-        {
-            let split: Vec<&str> = line.split_whitespace().collect();
-            if split.len() < cfg.dimens+1 {
-                bail!(format!("Wrong number of columns in line {} of window file {}. Empty Line?.", linecount, window_file));
-            }
-
-            for i in 0..cfg.dimens+1 {
-                timeseries[i].push(split[i].parse::<f64>()
-                    .chain_err(|| format!("Failed to parse line {} of window file {}.", linecount, window_file))?
-
-                );
-            }
+// The resulting vector contains one vector per column, i.e. the time and
+// one vector per dimension
+fn read_timeseries(window_file: &str, columns: &[usize]) -> Result<Vec<Vec<f64>>> {
+    let mut reader = TimeseriesReader::open(window_file, columns)?;
+    let mut timeseries = vec![Vec::new(); columns.len()];
+    let mut frame = vec![0.0; columns.len()];
+    while reader.next_frame(&mut frame)? {
+        for (ts, value) in timeseries.iter_mut().zip(&frame) {
+            ts.push(*value);
         }
-        
-        line.clear();
     }
     Ok(timeseries)
 }
@@ -318,9 +843,10 @@ fn read_timeseries(window_file: &str, cfg: &Config) -> Result<Vec<Vec<f64>>> {
 
 // calculates the inefficiency for every collective variable
 // filters the timeseries based on the highest inefficiency
-fn uncorrelate(timeseries: Vec<Vec<f64>>, cfg: &Config) -> Vec<Vec<f64>> {
-    // calculate inefficiencies and find the highest one
-    let gs: Vec<f64> = timeseries[1..].iter().map(|ts| statistical_ineff(ts)).collect();
+fn uncorrelate(timeseries: Vec<Vec<f64>>, dimens: usize) -> Vec<Vec<f64>> {
+    // calculate inefficiencies and find the highest one. Per-frame biases
+    // after the reaction coordinates are subsampled, but not analyzed.
+    let gs: Vec<f64> = timeseries[1..dimens+1].iter().map(|ts| statistical_ineff(ts)).collect();
     let mut max_g = 1.0;
     for g in gs {
         if g > max_g {
@@ -328,84 +854,386 @@ fn uncorrelate(timeseries: Vec<Vec<f64>>, cfg: &Config) -> Vec<Vec<f64>> {
         }
     }
 
-    // round g up
-    let mut trunc_g = max_g.trunc() as usize;
-    if (trunc_g as f64 - max_g).abs() > 0.000_000_000_1 {
-        trunc_g += 1;
-    }
+    let trunc_g = subsampling_step(max_g);
 
     // filter correlated samples from timeseries
     let prev_len = timeseries[0].len();
+    let dt = if prev_len > 1 { timeseries[0][1]-timeseries[0][0] } else { 0.0 };
     let timeseries = timeseries.into_iter().map(|ts| {
         ts.into_iter().step_by(trunc_g).collect::<Vec<f64>>()
     }).collect::<Vec<Vec<f64>>>();
 
     let new_len = timeseries[0].len();
-    if cfg.verbose {
-        let tau = autocorrelation_time(max_g)* (timeseries[0][1]-timeseries[0][0]);
-        vprintln(format!("{:?}/{:?} samples are uncorrelated. {:?} samples removed from timeseries (tau={:.5})", new_len, prev_len, prev_len-new_len, tau), true);
+    if log::enabled(LogLevel::Verbose) {
+        let tau = autocorrelation_time(max_g) * dt;
+        verbose!("{:?}/{:?} samples are uncorrelated. {:?} samples removed from timeseries (tau={:.5})", new_len, prev_len, prev_len-new_len, tau);
     }
     timeseries
 }
 
+// number of frames between uncorrelated samples, i.e. g rounded up
+fn subsampling_step(g: f64) -> usize {
+    let mut trunc_g = g.trunc() as usize;
+    if (trunc_g as f64 - g).abs() > 0.000_000_000_1 {
+        trunc_g += 1;
+    }
+    trunc_g
+}
+
+// Histograms of a window for each time interval of the datasets, with the
+// per-frame biases by bin if the window has a bias column.
+struct WindowHistograms {
+    histograms: Vec<Histogram>,
+    frame_bias: Vec<Option<Vec<(usize, f64)>>>,
+    // number of frames within the time boundaries before removing correlated ones
+    num_frames: usize,
+    // samples outside of the histogram boundaries in the last interval
+    discarded: u32,
+}
+
+// Histogram of a window that is built frame by frame. Counts and per-frame
+// biases are accumulated by bin, so that its memory grows with the number of
+// occupied bins instead of the number of frames.
+#[derive(Default)]
+struct HistogramBuilder {
+    counts: BTreeMap<usize, f64>,
+    // running log-sum-exp of the reduced per-frame biases U_n/kT of each bin
+    // as (max, sum of exp(U_n/kT - max))
+    frame_bias: BTreeMap<usize, (f64, f64)>,
+    samples: Vec<f64>,
+    sample_bias: Vec<f64>,
+    frames: Vec<usize>,
+    discarded: u32,
+}
+
+impl HistogramBuilder {
+    // Adds a frame with its folded values and bin, and its per-frame bias
+    // energy if the window has a bias column.
+    fn add(&mut self, values: &[f64], bin: Option<usize>, bias: Option<f64>, cfg: &Config, kT: f64) {
+        let bin = match bin {
+            Some(bin) => bin,
+            None => {
+                self.discarded += 1;
+                return
+            },
+        };
+        *self.counts.entry(bin).or_insert(0.0) += 1.0;
+        if cfg.method == Method::Mbar {
+            self.samples.extend_from_slice(&values[1..]);
+        }
+        if keep_frames(cfg) {
+            self.frames.push(bin);
+        }
+        if let Some(bias) = bias {
+            if cfg.method == Method::Mbar {
+                self.sample_bias.push(bias);
+            }
+            let u = bias / kT;
+            let (max, sum) = self.frame_bias.entry(bin).or_insert((u, 0.0));
+            if u <= *max {
+                *sum += (u - *max).exp();
+            } else {
+                *sum = *sum * (*max - u).exp() + 1.0;
+                *max = u;
+            }
+        }
+    }
+
+    // The histogram and the bias energies kT ln <exp(U_n/kT)> of the bins, if
+    // the frames have per-frame biases.
+    fn build(self, kT: f64, inefficiency: f64, frame_bias: bool) -> (Histogram, Option<Vec<(usize, f64)>>) {
+        let frame_bias = if frame_bias {
+            Some(self.frame_bias.iter().map(|(bin, (max, sum))| {
+                (*bin, kT * (max + sum.ln() - self.counts[bin].ln()))
+            }).collect())
+        } else {
+            None
+        };
+        let num_points = self.counts.values().sum::<f64>() as u32;
+        let mut histogram = Histogram::new_sparse(num_points, self.counts.into_iter().collect());
+        histogram.samples = self.samples;
+        histogram.sample_bias = self.sample_bias;
+        histogram.frames = self.frames;
+        histogram.inefficiency = inefficiency;
+        (histogram, frame_bias)
+    }
+}
+
+// Builds the histograms of a window for each time interval while its
+// timeseries is read, so that the timeseries never has to fit into memory.
+// Frames are filtered, subsampled and binned like read_window_file and
+// build_histogram_from_timeseries do for timeseries in memory. With uncorr,
+// the file is read twice: the first pass estimates the statistical
+// inefficiency by blocking analysis, the second keeps every g-th frame.
+// Pull forces are converted to positions with the window's (x_0, fc).
+fn stream_window_file(window_file: &str, columns: &[usize], cfg: &Config, intervals: &[(f64, f64)],
+        pull_forces: Option<(&[f64], &[f64])>, frame_bias: bool, kT: f64) -> Result<WindowHistograms> {
+    let bin_width: Vec<f64> = (0..cfg.dimens).map(|idx| {
+        (cfg.hist_max[idx] - cfg.hist_min[idx])/(cfg.num_bins[idx] as f64)
+    }).collect();
+    let step = if cfg.uncorr {
+        uncorrelated_step(window_file, &columns[..cfg.dimens+1], cfg)?
+    } else {
+        1
+    };
+
+    let mut builders: Vec<HistogramBuilder> = intervals.iter().map(|_| HistogramBuilder::default()).collect();
+    // the block bootstrap resamples blocks of correlated frames
+    let mut blocking = vec![BlockingAnalysis::new(); cfg.dimens];
+    let mut reader = TimeseriesReader::open(window_file, columns)?;
+    let mut frame = vec![0.0; columns.len()];
+    let mut num_frames = 0;
+    while reader.next_frame(&mut frame)? {
+        if !is_in_time_boundaries(frame[0], cfg.start, cfg.end) {
+            continue
+        }
+        num_frames += 1;
+        if (num_frames - 1) % step != 0 {
+            continue
+        }
+
+        // pull forces f = -fc*(x - x_0) are converted to positions
+        if let Some((pos, fc)) = pull_forces {
+            for d in 0..pos.len() {
+                frame[d+1] = pos[d] - frame[d+1] / fc[d];
+            }
+        }
+        if keep_frames(cfg) {
+            for (b, x) in blocking.iter_mut().zip(&frame[1..cfg.dimens+1]) {
+                b.push(*x);
+            }
+        }
+
+        // per-frame biases follow the reaction coordinates
+        let bias = frame.get(cfg.dimens+1).cloned();
+        let (values, bin) = frame_bin(frame[..cfg.dimens+1].to_vec(), cfg, &bin_width);
+        for (builder, (start, stop)) in builders.iter_mut().zip(intervals) {
+            if is_in_time_boundaries(frame[0], *start, *stop) {
+                builder.add(&values, bin, bias, cfg, kT);
+            }
+        }
+    }
+
+    if num_frames == 0 && !cfg.ignore_empty {
+        bail!("Time series is empty")
+    }
+
+    let inefficiency = blocking.iter().map(|b| b.statistical_ineff()).fold(1.0, f64::max);
+    let discarded = builders.last().map_or(0, |b| b.discarded);
+    let (histograms, frame_bias) = builders.into_iter()
+        .map(|builder| builder.build(kT, inefficiency, frame_bias))
+        .unzip();
+    Ok(WindowHistograms { histograms, frame_bias, num_frames, discarded })
+}
+
+// Reads the time and the reaction coordinates of a timeseries within the
+// time boundaries and returns the number of frames between uncorrelated
+// samples. The statistical inefficiency is estimated by blocking analysis of
+// each reaction coordinate, which does not store the timeseries.
+fn uncorrelated_step(window_file: &str, columns: &[usize], cfg: &Config) -> Result<usize> {
+    let mut blocking = vec![BlockingAnalysis::new(); cfg.dimens];
+    let mut times = Vec::new();
+    let mut reader = TimeseriesReader::open(window_file, columns)?;
+    let mut frame = vec![0.0; columns.len()];
+    while reader.next_frame(&mut frame)? {
+        if !is_in_time_boundaries(frame[0], cfg.start, cfg.end) {
+            continue
+        }
+        if times.len() < 2 {
+            times.push(frame[0]);
+        }
+        for (b, x) in blocking.iter_mut().zip(&frame[1..]) {
+            b.push(*x);
+        }
+    }
+
+    let max_g = blocking.iter().map(|b| b.statistical_ineff()).fold(1.0, f64::max);
+    let step = subsampling_step(max_g);
+    let prev_len = blocking.first().map_or(0, |b| b.len());
+    let new_len = prev_len.div_ceil(step);
+    if times.len() == 2 {
+        let tau = autocorrelation_time(max_g) * (times[1] - times[0]);
+        verbose!("{:?}/{:?} samples are uncorrelated. {:?} samples removed from timeseries (tau={:.5})",
+            new_len, prev_len, prev_len-new_len, tau);
+    }
+    Ok(step)
+}
+
 // Write WHAM calculation results to out_file.
-pub fn write_results(out_file: &str, append: bool, ds: &Dataset, free: &[f64],
-    free_std: &[f64], prob: &[f64], prob_std: &[f64], index: Option<usize>) -> Result<()> {
+pub fn write_results(out_file: &str, append: bool, result: &WhamResult) -> Result<()> {
 
     if !append && Path::new(out_file).exists() {
         std::fs::remove_file(out_file).chain_err(|| "Failed to delete file.")?;
     }
-    let output = OpenOptions::new().write(true)
+    let output = OpenOptions::new()
         .append(true)
         .create(true)
         .open(out_file)
         .chain_err(|| format!("Failed to create file with path {}", out_file))?;
     let mut buf = BufWriter::new(output);
 
-    let header: String = (0..ds.dimens_lengths.len()).map(|d| format!("coord{}", d+1))
+    let header: String = (0..result.shape.len()).map(|d| format!("coord{}", d+1))
         .collect::<Vec<String>>().join("    ");
-    if let Some(index) = index {
+    if let Some(index) = result.dataset {
         writeln!(buf, "#Dataset {}", index).unwrap();
     }
-    writeln!(buf, "#{}    Free Energy    +/-    Probability    +/-", header).unwrap();
+    // bootstrap confidence intervals are appended as additional columns
+    let (free_ci, prob_ci) = result.confidence_intervals();
+    if free_ci.is_empty() {
+        writeln!(buf, "#{}    Free Energy    +/-    Probability    +/-", header).unwrap();
+    } else {
+        writeln!(buf, "#{}    Free Energy    +/-    Probability    +/-    Free Energy CI low    \
+            Free Energy CI high    Probability CI low    Probability CI high", header).unwrap();
+    }
 
-    for bin in 0..free.len() {
-        let coords = ds.get_coords_for_bin(bin);
-        let coords_str: String = coords.iter().map(|c| {format!("{:8.6}    ", c)})
+    for bin in 0..result.free_energy.len() {
+        let coords_str: String = result.coords[bin].iter().map(|c| {format!("{:8.6}    ", c)})
             .collect::<Vec<String>>().join("\t");
-        writeln!(buf, "{}{:8.6}    {:8.6}    {:8.6}    {:8.6}", coords_str,
-            free[bin], free_std[bin], prob[bin], prob_std[bin])
+        let ci_str = if free_ci.is_empty() {
+            String::new()
+        } else {
+            format!("    {:8.6}    {:8.6}    {:8.6}    {:8.6}", free_ci[bin].0, free_ci[bin].1,
+                prob_ci[bin].0, prob_ci[bin].1)
+        };
+        writeln!(buf, "{}{:8.6}    {:8.6}    {:8.6}    {:8.6}{}", coords_str,
+            result.free_energy[bin], result.free_energy_std[bin], result.probability[bin],
+            result.probability_std[bin], ci_str)
+            .chain_err(|| "Failed to write to file.")?;
+    }
+    Ok(())
+}
+
+// Writes the thermodynamic properties of the temperature grid to a file.
+pub fn write_thermodynamics(out_file: &str, append: bool, thermo: &[Thermodynamics],
+    index: Option<usize>) -> Result<()> {
+
+    if !append && Path::new(out_file).exists() {
+        std::fs::remove_file(out_file).chain_err(|| "Failed to delete file.")?;
+    }
+    let output = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(out_file)
+        .chain_err(|| format!("Failed to create file with path {}", out_file))?;
+    let mut buf = BufWriter::new(output);
+
+    if let Some(index) = index {
+        writeln!(buf, "#Dataset {}", index).unwrap();
+    }
+    writeln!(buf, "#Temperature    Free Energy    Energy    Heat Capacity").unwrap();
+    for t in thermo {
+        writeln!(buf, "{:8.6}    {:8.6}    {:8.6}    {:8.6}", t.temperature,
+            t.free_energy, t.energy, t.heat_capacity)
+            .chain_err(|| "Failed to write to file.")?;
+    }
+    Ok(())
+}
+
+// Writes the bias offsets F of each window relative to the first window
+// together with their bootstrap errors and the convergence of the final
+// iteration (dF = |F - F_prev|).
+pub fn write_offsets(out_file: &str, append: bool, F: &[f64], F_std: &[f64], F_prev: &[f64],
+    iterations: usize, index: Option<usize>) -> Result<()> {
+
+    if !append && Path::new(out_file).exists() {
+        std::fs::remove_file(out_file).chain_err(|| "Failed to delete file.")?;
+    }
+    let output = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(out_file)
+        .chain_err(|| format!("Failed to create file with path {}", out_file))?;
+    let mut buf = BufWriter::new(output);
+
+    let dF: Vec<f64> = F.iter().zip(F_prev.iter()).map(|(f, f_prev)| (f - f_prev).abs()).collect();
+    let max_dF = dF.iter().cloned().fold(0.0, f64::max);
+
+    if let Some(index) = index {
+        writeln!(buf, "#Dataset {}", index).unwrap();
+    }
+    writeln!(buf, "#Iterations {}", iterations).unwrap();
+    writeln!(buf, "#Max dF {:e}", max_dF).unwrap();
+    writeln!(buf, "#Window    F    +/-    dF").unwrap();
+    for window in 0..F.len() {
+        writeln!(buf, "{}    {:8.6}    {:8.6}    {:e}", window, F[window] - F[0],
+            F_std[window], dF[window])
             .chain_err(|| "Failed to write to file.")?;
     }
     Ok(())
 }
 
+// Writes the free energy of each bin and the bias offsets F of each window
+// relative to the first window for every bootstrap run, one run per row.
+pub fn write_bootstrap_runs(out_file: &str, append: bool, bootstrap: &Bootstrap,
+    index: Option<usize>) -> Result<()> {
+
+    if !append && Path::new(out_file).exists() {
+        std::fs::remove_file(out_file).chain_err(|| "Failed to delete file.")?;
+    }
+    let output = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(out_file)
+        .chain_err(|| format!("Failed to create file with path {}", out_file))?;
+    let mut buf = BufWriter::new(output);
+
+    if let Some(index) = index {
+        writeln!(buf, "#Dataset {}", index).unwrap();
+    }
+    let header: Vec<String> = (0..bootstrap.free_energy_std.len()).map(|bin| format!("A{}", bin))
+        .chain((0..bootstrap.F_std.len()).map(|window| format!("F{}", window)))
+        .collect();
+    writeln!(buf, "#Run    {}", header.join("    ")).unwrap();
+    for (run, (free, F)) in bootstrap.free_energies.iter().zip(bootstrap.Fs.iter()).enumerate() {
+        let values: Vec<String> = free.iter().chain(F.iter()).map(|x| format!("{:8.6}", x)).collect();
+        writeln!(buf, "{}    {}", run, values.join("    "))
+            .chain_err(|| "Failed to write to file.")?;
+    }
+    Ok(())
+}
+
+// Reads bias offsets F from the second column of a file written by
+// `write_offsets`. For files with multiple datasets (convdt), the offsets of
+// the last dataset are returned.
+pub fn read_offsets(in_file: &str) -> Result<Vec<f64>> {
+    let f = File::open(in_file).chain_err(|| format!("Failed to open {}", in_file))?;
+    let mut F: Vec<f64> = Vec::new();
+    for (line_num, line) in BufReader::new(f).lines().enumerate() {
+        let line = line.chain_err(|| "Failed to read line.")?;
+        if line.starts_with("#Dataset") {
+            F.clear();
+            continue
+        }
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue
+        }
+        let offset: f64 = line.split_whitespace().nth(1)
+            .chain_err(|| format!("Missing bias offset in line {}", line_num+1))?
+            .parse()
+            .chain_err(|| format!("Failed to read bias offset in line {}", line_num+1))?;
+        F.push(offset);
+    }
+    Ok(F)
+}
+
 #[cfg(test)]
+#[allow(clippy::approx_constant)]
 mod tests {
     use super::*;
+    use super::super::BootstrapScheme;
     use assert_approx_eq::assert_approx_eq;
 
     fn cfg() -> Config {
         Config {
             metadata_file: "example/1d_cyclic/metadata.dat".to_string(),
-
             hist_min: vec![-3.14],
             hist_max: vec![3.14],
             num_bins: vec![10],
             dimens: 1,
-            verbose: false,
             tolerance: 0.0,
             max_iterations: 0,
-            temperature: 300.0,
-            cyclic: false,
-            output: "qwert".to_string(),
-            bootstrap: 0,
-            bootstrap_seed: 1234,
-            start: 0.0,
-            end: 1e+20,
-            uncorr: false,
-            convdt: 0.0,
-            ignore_empty: false,
+            cyclic: vec![false],
+            ..Default::default()
         }
     }
 
@@ -413,25 +1241,25 @@ mod tests {
     fn read_window_file() {
         let f = "example/1d_cyclic/COLVAR+0.0.xvg";
         let cfg = cfg();
-        let (timeseries, timeseries_inital_length) = super::read_window_file(&f, &cfg).unwrap();
+        let (timeseries, timeseries_inital_length) = super::read_window_file(f, &[0, 1], &cfg).unwrap();
         let mask = vec![true; timeseries[0].len()];
-        let h = build_histogram_from_timeseries(&timeseries, &mask, &cfg);
+        let (h, discarded) = build_histogram_from_timeseries(&timeseries, &mask, &cfg);
         println!("{:?}", h);
         assert_eq!(5000, timeseries_inital_length);
         assert_eq!(5000, h.num_points);
-        assert_approx_eq!(0.0, h.bins[2]);
-        assert_approx_eq!(11.0, h.bins[3]);
-        assert_approx_eq!(2236.0, h.bins[4]);
-        assert_approx_eq!(2714.0, h.bins[5]);
-        assert_approx_eq!(39.0, h.bins[6]);
-        assert_approx_eq!(0.0, h.bins[7]);
+        assert_eq!(0, discarded);
+        assert_approx_eq!(0.0, h.count(2));
+        assert_approx_eq!(11.0, h.count(3));
+        assert_approx_eq!(2236.0, h.count(4));
+        assert_approx_eq!(2714.0, h.count(5));
+        assert_approx_eq!(39.0, h.count(6));
+        assert_approx_eq!(0.0, h.count(7));
     }
 
     #[test]
     fn read_timeseries() {
         let f = "example/1d_cyclic/COLVAR+0.0.xvg";
-        let cfg = cfg();
-        let ts = super::read_timeseries(&f, &cfg).unwrap();
+        let ts = super::read_timeseries(f, &[0, 1]).unwrap();
         let expected = [
             -0.153_145,
             -0.377_860,
@@ -460,6 +1288,304 @@ mod tests {
         assert_eq!(25, ds.histograms.len())
     }
 
+    #[test]
+    fn read_data_bias_options() {
+        let mut cfg = cfg();
+        cfg.metadata_file = "tests/data/metadata_bias_options.dat".to_string();
+        let ds = &super::read_data(&cfg).unwrap()[0];
+        assert_eq!(4, ds.num_windows);
+
+        // flat-bottom window at x0=0.0 with width 0.5 and k=100
+        assert_approx_eq!(0.0, ds.calc_bias_energy(&[0.4], 0));
+        assert_approx_eq!(0.5, ds.calc_bias_energy(&[0.6], 0));
+        // harmonic window at x0=0.25 with k=100
+        assert_approx_eq!(0.5, ds.calc_bias_energy(&[0.35], 1));
+        // lower wall at 1.0 with exponent 4 and k=100
+        assert_approx_eq!(0.0, ds.calc_bias_energy(&[1.5], 2));
+        assert_approx_eq!(100.0, ds.calc_bias_energy(&[0.0], 2));
+        // linear bias at 0.75 with k=100
+        assert_approx_eq!(-25.0, ds.calc_bias_energy(&[0.5], 3));
+    }
+
+    #[test]
+    fn read_data_trailing_column() {
+        // trailing columns without key=value are ignored and do not set the
+        // window temperature
+        let mut cfg = cfg();
+        cfg.metadata_file = "tests/data/metadata_trailing_column.dat".to_string();
+        let ds = &super::read_data(&cfg).unwrap()[0];
+        assert_eq!(2, ds.num_windows);
+        assert!(!ds.energy_dimen);
+        assert_approx_eq!(cfg.temperature * k_B, ds.kT);
+        assert_approx_eq!(0.5, ds.calc_bias_energy(&[0.35], 1));
+    }
+
+    // GROMACS pull input of 4 windows in 2 dimensions
+    fn pull_cfg() -> Config {
+        let mut cfg = cfg();
+        cfg.metadata_file = String::new();
+        cfg.hist_min = vec![0.8, 1.3];
+        cfg.hist_max = vec![1.4, 1.9];
+        cfg.num_bins = vec![37, 37];
+        cfg.dimens = 2;
+        cfg.cyclic = vec![false, false];
+        cfg.pull_params = Some("tests/data/gromacs/pull-params.dat".to_string());
+        cfg
+    }
+
+    #[test]
+    fn read_data_pull() {
+        let mut cfg = pull_cfg();
+        cfg.pullx_files = Some("tests/data/gromacs/pullx-files.dat".to_string());
+        let pullx = &super::read_data(&cfg).unwrap()[0];
+        assert_eq!(4, pullx.num_windows);
+        assert!(pullx.histograms.iter().all(|h| h.num_points == 300));
+        // harmonic window 1 at (1.0, 1.7) with k=1000
+        assert_approx_eq!(5.0, pullx.calc_bias_energy(&[1.1, 1.7], 1));
+
+        // positions calculated from the pull forces are binned the same way
+        cfg.pullx_files = None;
+        cfg.pullf_files = Some("tests/data/gromacs/pullf-files.dat".to_string());
+        let pullf = &super::read_data(&cfg).unwrap()[0];
+        for (hx, hf) in pullx.histograms.iter().zip(&pullf.histograms) {
+            assert_eq!(hx.bins, hf.bins);
+        }
+    }
+
+    #[test]
+    fn select_columns() {
+        // without selection, the header is not needed
+        let (columns, periodic) = super::select_columns(&TimeseriesHeader::default(), "COLVAR", &[], 2).unwrap();
+        assert_eq!(vec![0, 1, 2], columns);
+        assert_eq!(vec![None, None], periodic);
+
+        let header = TimeseriesHeader {
+            fields: vec!["time".to_string(), "phi".to_string(), "psi".to_string()],
+            periodic: vec![Periodic { name: "psi".to_string(), min: None, max: None }],
+        };
+        let selection = vec!["time".to_string(), "psi".to_string()];
+        let (columns, periodic) = super::select_columns(&header, "COLVAR", &selection, 1).unwrap();
+        assert_eq!(vec![0, 2], columns);
+        assert_eq!("psi", periodic[0].as_ref().unwrap().name);
+        assert!(super::select_columns(&header, "COLVAR", &selection, 2).is_err());
+        assert_eq!(1, super::select_bias_column(&header, "COLVAR", "phi").unwrap());
+        assert!(super::select_bias_column(&header, "COLVAR", "bias").is_err());
+    }
+
+    #[test]
+    fn find_xvg_pull_columns() {
+        let find = |xvg_file: &str, dimens: usize| {
+            let header = super::read_timeseries_header(xvg_file).unwrap();
+            super::find_xvg_pull_columns(&header, xvg_file, dimens)
+        };
+        // positions are followed by reference values, e.g. "1 ref"
+        assert_eq!(vec![0, 1, 3], find("tests/data/gromacs/umbrella0_pullx.xvg", 2).unwrap());
+        assert_eq!(vec![0, 1, 2], find("tests/data/gromacs/umbrella0_pullf.xvg", 2).unwrap());
+        assert!(find("tests/data/gromacs/umbrella0_pullf.xvg", 3).is_err());
+        // without legends, the coordinates follow the time
+        assert_eq!(vec![0, 1], find("example/1d_cyclic/COLVAR+0.0.xvg", 1).unwrap());
+    }
+
+    #[test]
+    fn parse_xvg_legend() {
+        assert_eq!(Some((2, "1 ref".to_string())), super::parse_xvg_legend("@ s2 legend \"1 ref\""));
+        assert_eq!(None, super::parse_xvg_legend("@ legend on"));
+        assert_eq!(None, super::parse_xvg_legend("@    title \"Pull COM\""));
+    }
+
+    #[test]
+    fn read_data_plumed() {
+        // first 1000 frames of the example windows with additional columns
+        let mut cfg = cfg();
+        cfg.end = 19.99;
+        cfg.cyclic = vec![true];
+        cfg.metadata_file = "tests/data/plumed/metadata_reference.dat".to_string();
+        let reference = &super::read_data(&cfg).unwrap()[0];
+
+        // phi is selected by name, or by index in the last window, and is
+        // periodic according to the header
+        cfg.cyclic = vec![false];
+        cfg.metadata_file = "tests/data/plumed/metadata.dat".to_string();
+        cfg.columns = vec!["time".to_string(), "phi".to_string()];
+        let plumed = &super::read_data(&cfg).unwrap()[0];
+        assert_eq!(vec![true], plumed.cyclic);
+        for (h, expected) in plumed.histograms.iter().zip(&reference.histograms) {
+            assert_eq!(1000, h.num_points);
+            assert_eq!(expected.bins, h.bins);
+        }
+
+        // the period does not match the histogram
+        cfg.hist_max = vec![1.0];
+        let plumed = &super::read_data(&cfg).unwrap()[0];
+        assert_eq!(vec![false], plumed.cyclic);
+
+        cfg.columns = vec!["time".to_string(), "chi".to_string()];
+        assert!(super::read_data(&cfg).is_err());
+    }
+
+    #[test]
+    fn read_data_frame_bias() {
+        let mut cfg = cfg();
+        cfg.end = 19.99;
+        cfg.metadata_file = "tests/data/plumed/metadata_frame_bias.dat".to_string();
+        cfg.columns = vec!["time".to_string(), "phi".to_string()];
+        let ds = &super::read_data(&cfg).unwrap()[0];
+        cfg.metadata_file = "tests/data/plumed/metadata_reference.dat".to_string();
+        cfg.columns = Vec::new();
+        let reference = &super::read_data(&cfg).unwrap()[0];
+
+        // the per-frame bias of an occupied bin lies within the range of the
+        // static bias in the bin
+        let width = (cfg.hist_max[0] - cfg.hist_min[0]) / cfg.num_bins[0] as f64;
+        for (window, (h, expected)) in ds.histograms.iter().zip(&reference.histograms).enumerate() {
+            assert_eq!(expected.bins, h.bins);
+            for &(bin, _) in &h.bins {
+                let x = ds.get_coords_for_bin(bin)[0];
+                let static_bias: Vec<f64> = (0..=100)
+                    .map(|i| reference.calc_bias_energy(&[x + width * (i as f64 / 100.0 - 0.5)], window))
+                    .collect();
+                let lower = static_bias.iter().cloned().fold(f64::INFINITY, f64::min);
+                let upper = static_bias.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                let bias = ds.calc_bias_energy(&[x], window);
+                assert!(bias >= lower - 1e-9 && bias <= upper + 1e-9,
+                    "bias {} of window {} at {} not in [{}, {}]", bias, window, x, lower, upper);
+            }
+        }
+
+        // window 0 at x0=0 never samples phi=3
+        assert_eq!(f64::INFINITY, ds.calc_bias_energy(&[3.0], 0));
+        assert!(reference.calc_bias_energy(&[3.0], 0).is_finite());
+
+        // the exact bias of every sample is kept for MBAR
+        cfg.method = Method::Mbar;
+        cfg.metadata_file = "tests/data/plumed/metadata_frame_bias.dat".to_string();
+        cfg.columns = vec!["time".to_string(), "phi".to_string()];
+        let ds = &super::read_data(&cfg).unwrap()[0];
+        for h in &ds.histograms {
+            assert_eq!(h.samples.len(), h.sample_bias.len());
+        }
+    }
+
+    #[test]
+    fn read_data_compressed() {
+        // the same frames as text, gzip, xz and NumPy arrays
+        let mut cfg = cfg();
+        cfg.metadata_file = "tests/data/timeseries/metadata.dat".to_string();
+        let ds = &super::read_data(&cfg).unwrap()[0];
+        assert_eq!(6, ds.num_windows);
+        assert_eq!(vec![true], ds.cyclic);
+        for h in &ds.histograms {
+            assert_eq!(100, h.num_points);
+            assert_eq!(ds.histograms[0].bins, h.bins);
+        }
+    }
+
+    #[test]
+    fn read_timeseries_header() {
+        let header = super::read_timeseries_header("tests/data/plumed/COLVAR+0.0").unwrap();
+        assert_eq!(vec!["time", "restraint.bias", "phi", "psi"], header.fields);
+        assert_eq!(Some(2), header.column("phi"));
+        assert_eq!(Some(3), header.column("3"));
+        assert_eq!(None, header.column("chi"));
+        let phi = header.periodic(2).unwrap();
+        assert_eq!(Some(-f64::consts::PI), phi.min);
+        assert_eq!(Some(f64::consts::PI), phi.max);
+        assert_eq!(None, header.periodic(1));
+
+        let header = super::read_timeseries_header("tests/data/gromacs/umbrella0_pullx.xvg").unwrap();
+        assert_eq!(vec!["", "1", "1 ref", "2", "2 ref"], header.fields);
+        assert!(header.periodic.is_empty());
+    }
+
+    #[test]
+    fn parse_bias_options() {
+        let harmonic = super::parse_bias_options(&[], 2).unwrap();
+        assert_eq!(vec![BiasPotential::Harmonic; 2], harmonic);
+
+        let mixed = super::parse_bias_options(&["bias=flat_bottom,upper_wall", "width=0.5", "exp=4"], 2).unwrap();
+        assert_eq!(BiasPotential::FlatBottom { width: 0.5 }, mixed[0]);
+        assert_eq!(BiasPotential::UpperWall { exponent: 4.0 }, mixed[1]);
+
+        assert!(super::parse_bias_options(&["bias=flat_bottom"], 1).is_err());
+        assert!(super::parse_bias_options(&["bias=harmonic,linear,linear"], 2).is_err());
+        assert!(super::parse_bias_options(&["foo=1"], 1).is_err());
+        assert!(super::parse_bias_options(&["exp=x"], 1).is_err());
+    }
+
+    #[test]
+    fn is_in_hist_boundaries() {
+        let mut cfg = cfg();
+        cfg.dimens = 2;
+        cfg.hist_min = vec![-1.0, -1.0];
+        cfg.hist_max = vec![1.0, 1.0];
+        cfg.num_bins = vec![2, 2];
+        cfg.cyclic = vec![true, false];
+        assert!(super::is_in_hist_boundaries(&[0.0, 0.0], &cfg));
+        assert!(super::is_in_hist_boundaries(&[1.0, 0.0], &cfg));
+        assert!(!super::is_in_hist_boundaries(&[0.0, 1.0], &cfg));
+        assert!(!super::is_in_hist_boundaries(&[-1.1, 0.0], &cfg));
+    }
+
+    #[test]
+    fn build_histogram_from_timeseries_cyclic() {
+        let mut cfg = cfg();
+        cfg.dimens = 2;
+        cfg.hist_min = vec![-1.0, -1.0];
+        cfg.hist_max = vec![1.0, 1.0];
+        cfg.num_bins = vec![2, 2];
+        cfg.cyclic = vec![true, false];
+        // time, x, y
+        let timeseries = vec![vec![0.0, 1.0, 2.0], vec![1.0, -0.5, 0.5], vec![-0.5, -0.5, 1.0]];
+        let (h, discarded) = super::build_histogram_from_timeseries(&timeseries, &[true; 3], &cfg);
+        // x=1.0 is the periodic image of x=-1.0, y=1.0 is outside
+        assert_eq!(2, h.num_points);
+        assert_eq!(1, discarded);
+        assert_eq!(vec![(0, 2.0)], h.bins);
+        assert!(h.frames.is_empty());
+    }
+
+    #[test]
+    fn build_histogram_from_timeseries_frames() {
+        let mut cfg = cfg();
+        cfg.hist_min = vec![0.0];
+        cfg.hist_max = vec![4.0];
+        cfg.num_bins = vec![4];
+        cfg.cyclic = vec![false];
+        cfg.bootstrap = 10;
+        cfg.bootstrap_scheme = BootstrapScheme::Block;
+        let timeseries = vec![vec![0.0, 1.0, 2.0, 3.0], vec![3.5, 0.5, 5.0, 1.5]];
+        let (h, _) = super::build_histogram_from_timeseries(&timeseries, &[true; 4], &cfg);
+        // frames outside of the histogram are not part of the bootstrap
+        assert_eq!(vec![3, 0, 1], h.frames);
+    }
+
+    #[test]
+    fn build_histogram_from_timeseries_out_of_range() {
+        let mut cfg = cfg();
+        cfg.dimens = 2;
+        cfg.hist_min = vec![-1.0, -1.0];
+        cfg.hist_max = vec![1.0, 1.0];
+        cfg.num_bins = vec![2, 2];
+        cfg.cyclic = vec![true, false];
+        // x=1.5, x=-2.5 and x=3.5 are periodic images of x=-0.5, y=-1.5 is outside
+        let timeseries = vec![vec![0.0, 1.0, 2.0, 3.0], vec![1.5, -2.5, 3.5, 0.5],
+            vec![-0.5, -0.5, 0.5, -1.5]];
+        let (h, discarded) = super::build_histogram_from_timeseries(&timeseries, &[true; 4], &cfg);
+        assert_eq!(3, h.num_points);
+        assert_eq!(1, discarded);
+        assert_eq!(vec![(0, 2.0), (2, 1.0)], h.bins);
+    }
+
+    #[test]
+    fn wrap_periodic() {
+        assert_approx_eq!(-0.5, super::wrap_periodic(-0.5, -1.0, 1.0));
+        assert_approx_eq!(-0.5, super::wrap_periodic(1.5, -1.0, 1.0));
+        assert_approx_eq!(0.5, super::wrap_periodic(-3.5, -1.0, 1.0));
+        assert_approx_eq!(-1.0, super::wrap_periodic(1.0, -1.0, 1.0));
+        let pi = std::f64::consts::PI;
+        assert_approx_eq!(-0.5*pi, super::wrap_periodic(1.5*pi, -pi, pi));
+    }
+
     #[test]
     fn read_data_empty() {
         let mut cfg = cfg();
@@ -482,6 +1608,87 @@ mod tests {
         }
     }
 
+    // reads the datasets in memory and streamed, and compares their histograms
+    fn assert_stream_equal(cfg: &Config) -> (Vec<Dataset>, Vec<Dataset>) {
+        let mut cfg = cfg.clone();
+        let in_memory = super::read_data(&cfg).unwrap();
+        cfg.stream = true;
+        let streamed = super::read_data(&cfg).unwrap();
+        assert_eq!(in_memory.len(), streamed.len());
+        for (expected, ds) in in_memory.iter().zip(&streamed) {
+            assert_eq!(expected.cyclic, ds.cyclic);
+            for (e, h) in expected.histograms.iter().zip(&ds.histograms) {
+                assert_eq!(e.num_points, h.num_points);
+                assert_eq!(e.bins, h.bins);
+                assert_eq!(e.samples, h.samples);
+                assert_eq!(e.sample_bias, h.sample_bias);
+                assert_eq!(e.frames, h.frames);
+            }
+        }
+        (in_memory, streamed)
+    }
+
+    #[test]
+    fn read_data_stream() {
+        let mut cfg = cfg();
+        assert_stream_equal(&cfg);
+
+        // time intervals
+        cfg.metadata_file = "tests/data/metadata_convdt.dat".to_string();
+        cfg.convdt = 2.0;
+        cfg.start = 0.0;
+        cfg.end = 9.0;
+        assert_stream_equal(&cfg);
+
+        // pull forces and compressed input
+        assert_stream_equal(&Config { pullf_files: Some("tests/data/gromacs/pullf-files.dat".to_string()), ..pull_cfg() });
+        assert_stream_equal(&Config { metadata_file: "tests/data/timeseries/metadata.dat".to_string(), ..super::tests::cfg() });
+
+        // raw samples and per-frame biases
+        let mut cfg = super::tests::cfg();
+        cfg.method = Method::Mbar;
+        cfg.end = 19.99;
+        cfg.metadata_file = "tests/data/plumed/metadata_frame_bias.dat".to_string();
+        cfg.columns = vec!["time".to_string(), "phi".to_string()];
+        let (in_memory, streamed) = assert_stream_equal(&cfg);
+        let (expected, ds) = (&in_memory[0], &streamed[0]);
+        for (window, h) in ds.histograms.iter().enumerate() {
+            for &(bin, _) in &h.bins {
+                let x = ds.get_coords_for_bin(bin);
+                assert_approx_eq!(expected.calc_bias_energy(&x, window), ds.calc_bias_energy(&x, window), 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn read_data_stream_uncorr() {
+        let mut cfg = cfg();
+        cfg.uncorr = true;
+        let in_memory = &super::read_data(&cfg).unwrap()[0];
+        cfg.stream = true;
+        let streamed = &super::read_data(&cfg).unwrap()[0];
+        cfg.uncorr = false;
+        let all = &super::read_data(&cfg).unwrap()[0];
+
+        // every g-th frame is kept, with g estimated by blocking analysis. The
+        // estimates of single windows differ, but not in total.
+        for (h, all) in streamed.histograms.iter().zip(&all.histograms) {
+            assert!(h.num_points < all.num_points);
+        }
+        let total = |ds: &Dataset| ds.histograms.iter().map(|h| h.num_points).sum::<u32>() as f64;
+        let ratio = total(streamed) / total(in_memory);
+        assert!(ratio > 0.8 && ratio < 1.25, "{}", ratio);
+
+        // frames and inefficiencies for the block bootstrap
+        cfg.bootstrap = 10;
+        cfg.bootstrap_scheme = BootstrapScheme::Block;
+        let streamed = &super::read_data(&cfg).unwrap()[0];
+        for h in &streamed.histograms {
+            assert_eq!(h.num_points as usize, h.frames.len());
+            assert!(h.inefficiency > 1.0);
+        }
+    }
+
     // test if convdt results in correct parsing
     // 6 timeseries are loaded ranging from:
     // 1. 0-10, 500 datapoints
@@ -509,7 +1716,7 @@ mod tests {
             ds.histograms.iter().map(|h| h.num_points).sum()
         }).collect();
 
-        let expected_hist_points = vec![
+        let expected_hist_points = [
             300,  // 0-2: 100+100+100+0+0
             600,  // 0-4: 200+100+200+0+0+100
             900,  // 0-6: 300+100+250+50+0+200
@@ -541,7 +1748,7 @@ mod tests {
             ds.histograms.iter().map(|h| h.num_points).sum()
         }).collect();
 
-        let expected_hist_points = vec![
+        let expected_hist_points = [
             0,   // 0-2
             100, // 0-4
             200, // 0-6
@@ -553,14 +1760,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn read_offsets() {
+        let F = super::read_offsets("tests/data/offsets.dat").unwrap();
+        assert_eq!(vec![0.0, -3.5, 1.25], F);
+        assert!(super::read_offsets("tests/data/metadata_unparseable1.dat").is_err());
+    }
+
     #[test]
     fn get_relative_path() {
         let path1 = "path/to/some_file.dat";
         let path2 = "another_file.dat";
         let path3 = "subfolder/another_file.dat";
-        let relative2 = super::get_relative_path(&path1, &path2);
+        let relative2 = super::get_relative_path(path1, path2);
         assert_eq!("path/to/another_file.dat" ,relative2);
-        let relative3 = super::get_relative_path(&path1, &path3);
+        let relative3 = super::get_relative_path(path1, path3);
         assert_eq!("path/to/subfolder/another_file.dat" ,relative3);
     }
 
@@ -1,66 +1,322 @@
 #![allow(non_snake_case)]
+#![allow(clippy::too_many_arguments)]
 
 #[macro_use]
 extern crate error_chain;
 extern crate rand;
 extern crate rayon;
+extern crate flate2;
+extern crate xz2;
 #[cfg(test)]
 #[macro_use]
 extern crate assert_approx_eq;
 
 
+#[macro_use]
+pub mod log;
 pub mod io;
+pub mod timeseries;
 pub mod histogram;
 pub mod error_analysis;
 pub mod correlation_analysis;
 pub mod statistics;
+pub mod mbar;
+pub mod diis;
+pub mod bias;
+pub mod bias_cache;
+pub mod thermodynamics;
+pub mod output;
+pub mod builder;
+pub mod report;
 
 use histogram::Dataset;
+use error_analysis::{Bootstrap, Interval};
+use thermodynamics::Thermodynamics;
+use statistics::log_sum_exp;
 use std::f64;
 use std::fmt;
-use std::io::prelude::*;
 use rayon::prelude::*;
 
 // init error chain
 pub mod errors { error_chain!{} }
 use errors::*;
 
+// Result of the free energy estimators: the normalized bin probabilities P,
+// the bias offsets F, the bias offsets of the previous iteration F_prev (both
+// in kJ/mol) and the number of iterations until convergence.
+pub type Estimate = (Vec<f64>, Vec<f64>, Vec<f64>, usize);
+
 #[allow(non_upper_case_globals)]
 static k_B: f64 = 0.008_314_462_1; // kJ/mol*K
 
+// Free energy estimator used to solve for the window offsets.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Method {
+    // binned weighted histogram analysis method
+    Wham,
+    // binless multistate Bennett acceptance ratio
+    Mbar,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Method::Wham => write!(f, "wham"),
+            Method::Mbar => write!(f, "mbar"),
+        }
+    }
+}
+
+// Resampling scheme of the bootstrap error analysis.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BootstrapScheme {
+    // random (Dirichlet) weights of the windows
+    Bayesian,
+    // moving-block resampling of the frames within each window
+    Block,
+    // Poisson distributed weights of the frames within each window
+    Poisson,
+}
+
+impl fmt::Display for BootstrapScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootstrapScheme::Bayesian => write!(f, "bayesian"),
+            BootstrapScheme::Block => write!(f, "block"),
+            BootstrapScheme::Poisson => write!(f, "poisson"),
+        }
+    }
+}
+
+// File format of the PMF written to the output file.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum OutputFormat {
+    // whitespace separated text columns
+    Text,
+    // JSON document including config, bias offsets and convergence
+    Json,
+    // comma separated values with a header row
+    Csv,
+    // NumPy array of the free energy
+    Npy,
+    // NumPy archive of the PMF, probabilities, axes and bias offsets
+    Npz,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Npy => write!(f, "npy"),
+            OutputFormat::Npz => write!(f, "npz"),
+        }
+    }
+}
+
 // Application config
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Config {
     pub metadata_file: String,
     pub hist_min: Vec<f64>,
     pub hist_max: Vec<f64>,
     pub num_bins: Vec<usize>,
     pub dimens: usize,
-    pub verbose: bool,
     pub tolerance: f64,
     pub max_iterations: usize,
     pub temperature: f64,
-    pub cyclic: bool,
+    pub cyclic: Vec<bool>,
     pub output: String,
+    pub output_format: OutputFormat,
     pub bootstrap: usize,
     pub bootstrap_seed: u64,
+    pub bootstrap_scheme: BootstrapScheme,
     pub start: f64,
     pub end: f64,
     pub uncorr: bool,
     pub convdt: f64,
-    pub ignore_empty: bool
+    pub ignore_empty: bool,
+    pub method: Method,
+    pub logspace: bool,
+    pub diis: usize,
+    pub energy_min: f64,
+    pub energy_max: f64,
+    pub energy_bins: usize,
+    pub temperature_grid: Vec<f64>,
+    pub thermo_output: String,
+    pub offsets_output: Option<String>,
+    pub initial_offsets: Option<String>,
+    pub bootstrap_sd: bool,
+    pub bootstrap_ci: f64,
+    pub bootstrap_output: Option<String>,
+    pub bootstrap_retries: usize,
+    pub bias_cutoff: f64,
+    pub pullx_files: Option<String>,
+    pub pullf_files: Option<String>,
+    pub pull_params: Option<String>,
+    pub columns: Vec<String>,
+    pub bias_column: Option<String>,
+    pub stream: bool,
 }
 
 impl fmt::Display for Config {
      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
          write!(f, "Metadata={}, hist_min={:?}, hist_max={:?}, bins={:?}, 
-            verbose={}, tolerance={}, iterations={}, temperature={},
+            tolerance={}, iterations={}, temperature={},
             cyclic={:?}, uncorr={:?}, bootstrap={:?}, seed={:?},
-            uncorr={:?}, start={:?}, end={:?}, convdt={:?}, ignore_empty={:?}",
+            uncorr={:?}, start={:?}, end={:?}, convdt={:?}, ignore_empty={:?},
+            method={}, logspace={:?}, diis={:?}, energy_min={:?},
+            energy_max={:?}, energy_bins={:?}, temperature_grid={:?},
+            thermo_output={}, output_format={}, offsets_output={:?},
+            initial_offsets={:?}, bootstrap_scheme={}, bootstrap_sd={:?},
+            bootstrap_ci={:?}, bootstrap_output={:?}, bootstrap_retries={:?},
+            bias_cutoff={:?}, pullx_files={:?}, pullf_files={:?},
+            pull_params={:?}, columns={:?}, bias_column={:?},
+            stream={:?}",
             self.metadata_file, self.hist_min, self.hist_max, self.num_bins,
-            self.verbose, self.tolerance, self.max_iterations, self.temperature,
+            self.tolerance, self.max_iterations, self.temperature,
             self.cyclic, self.uncorr, self.bootstrap, self.bootstrap_seed,
-            self.uncorr, self.start, self.end, self.convdt, self.ignore_empty)
+            self.uncorr, self.start, self.end, self.convdt, self.ignore_empty,
+            self.method, self.logspace, self.diis, self.energy_min,
+            self.energy_max, self.energy_bins, self.temperature_grid,
+            self.thermo_output, self.output_format, self.offsets_output,
+            self.initial_offsets, self.bootstrap_scheme, self.bootstrap_sd,
+            self.bootstrap_ci, self.bootstrap_output, self.bootstrap_retries,
+            self.bias_cutoff, self.pullx_files, self.pullf_files,
+            self.pull_params, self.columns, self.bias_column,
+            self.stream)
+    }
+}
+
+// Defaults of the command line interface. The histogram and the temperature
+// are required on the command line and left empty (300 K) here. The bootstrap
+// seed is random on the command line and fixed here.
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            metadata_file: String::new(),
+            hist_min: Vec::new(),
+            hist_max: Vec::new(),
+            num_bins: Vec::new(),
+            dimens: 0,
+            tolerance: 0.000_001,
+            max_iterations: 100_000,
+            temperature: 300.0,
+            cyclic: Vec::new(),
+            output: "wham.out".to_string(),
+            output_format: OutputFormat::Text,
+            bootstrap: 0,
+            bootstrap_seed: 1234,
+            bootstrap_scheme: BootstrapScheme::Bayesian,
+            start: 0.0,
+            end: 1e+20,
+            uncorr: false,
+            convdt: 0.0,
+            ignore_empty: false,
+            method: Method::Wham,
+            logspace: false,
+            diis: 0,
+            energy_min: 0.0,
+            energy_max: 0.0,
+            energy_bins: 0,
+            temperature_grid: Vec::new(),
+            thermo_output: "thermo.out".to_string(),
+            offsets_output: None,
+            initial_offsets: None,
+            bootstrap_sd: false,
+            bootstrap_ci: 0.0,
+            bootstrap_output: None,
+            bootstrap_retries: 0,
+            bias_cutoff: 0.0,
+            pullx_files: None,
+            pullf_files: None,
+            pull_params: None,
+            columns: Vec::new(),
+            bias_column: None,
+            stream: false,
+        }
+    }
+}
+
+// Result of the free energy estimation of one dataset. Probabilities and free
+// energies are given for each bin of the reaction coordinate grid, bias offsets
+// for each window. Errors are 0 without bootstrapping.
+#[derive(Debug,Clone)]
+pub struct WhamResult {
+    // index of the dataset if the timeseries are sliced with convdt
+    pub dataset: Option<usize>,
+    // time interval of the timeseries, None for datasets built without files
+    pub interval: Option<(f64, f64)>,
+    // number of data points of each window inside the histogram boundaries
+    pub num_points: Vec<u32>,
+    // number of bins in each reaction coordinate dimension
+    pub shape: Vec<usize>,
+    // bin centers
+    pub coords: Vec<Vec<f64>>,
+    pub probability: Vec<f64>,
+    pub probability_std: Vec<f64>,
+    // free energy in kJ/mol relative to its minimum
+    pub free_energy: Vec<f64>,
+    pub free_energy_std: Vec<f64>,
+    // bias offsets of the final and the previous iteration in kJ/mol
+    pub F: Vec<f64>,
+    pub F_prev: Vec<f64>,
+    pub F_std: Vec<f64>,
+    pub iterations: usize,
+    pub converged: bool,
+    // confidence intervals and bootstrapping runs if bootstrapping was performed
+    pub bootstrap: Option<Bootstrap>,
+    // temperature reweighting of multi-temperature datasets
+    pub thermodynamics: Vec<Thermodynamics>,
+}
+
+impl WhamResult {
+    // Collects the result from the marginalized probabilities P and the bias
+    // offsets of a dataset. Convergence is checked with the given tolerance.
+    pub fn new(ds: &Dataset, P: Vec<f64>, F: Vec<f64>, F_prev: Vec<f64>, iterations: usize,
+            tolerance: f64, bootstrap: Option<Bootstrap>) -> WhamResult {
+        let (probability_std, free_energy_std, F_std) = match bootstrap {
+            Some(ref b) => (b.P_std.clone(), b.free_energy_std.clone(), b.F_std.clone()),
+            None => (vec![0.0; P.len()], vec![0.0; P.len()], vec![0.0; F.len()]),
+        };
+        let coords = (0..ds.num_coord_bins())
+            .map(|bin| ds.get_coords_for_bin(bin).into_iter().take(ds.coord_dimens()).collect())
+            .collect();
+        WhamResult {
+            dataset: None,
+            interval: None,
+            num_points: ds.histograms.iter().map(|h| h.num_points).collect(),
+            shape: ds.dimens_lengths.iter().take(ds.coord_dimens()).cloned().collect(),
+            coords,
+            free_energy: calc_free_energy(ds, &P),
+            probability: P,
+            probability_std,
+            free_energy_std,
+            converged: is_converged(&F_prev, &F, tolerance),
+            F,
+            F_prev,
+            F_std,
+            iterations,
+            bootstrap,
+            thermodynamics: Vec::new(),
+        }
+    }
+
+    // percentile confidence intervals of the free energy and the probability,
+    // empty unless requested for bootstrapping
+    pub fn confidence_intervals(&self) -> (&[Interval], &[Interval]) {
+        match self.bootstrap {
+            Some(ref b) => (&b.free_energy_ci, &b.P_ci),
+            None => (&[], &[]),
+        }
+    }
+
+    // largest change of the bias offsets in the final iteration
+    pub fn max_dF(&self) -> f64 {
+        self.F.iter().zip(self.F_prev.iter())
+            .map(|(f, f_prev)| (f - f_prev).abs())
+            .fold(0.0, f64::max)
     }
 }
 
@@ -81,13 +337,16 @@ fn is_converged(old_F: &[f64], new_F: &[f64], tolerance: f64) -> bool {
 // P(x) = \frac {\sum_{i=1}^N{n_i(x)}}
 //              {\sum_{i=1}^N{  N_i exp(\beta [F_i - U_{bias,i}(x)])}}
 fn calc_bin_probability(bin: usize, dataset: &Dataset, F: &[f64]) -> f64 {
-    let mut denom_sum: f64 = 0.0;
     let bin_count: f64 = dataset.get_weighted_bin_count(bin);
-    for (window, h) in dataset.histograms.iter().enumerate() {
-        let bias = dataset.get_bias(bin, window);
-        denom_sum += (dataset.weights[window] * h.num_points as f64)
-                    * bias * F[window];
+    // bins without samples may have no bias factors left in a sparse cache
+    if bin_count == 0.0 {
+        return 0.0
     }
+    let denom_sum: f64 = dataset.bin_bias_entries(bin)
+        .map(|(window, bias, _)| {
+            let h = &dataset.histograms[window];
+            (dataset.weights[window] * h.num_points as f64) * bias * F[window]
+        }).sum();
     bin_count / denom_sum
 }
 
@@ -96,25 +355,38 @@ fn calc_bin_probability(bin: usize, dataset: &Dataset, F: &[f64]) -> f64 {
 // exp(F/kT) is not required in intermediate steps so we save some time by not
 // calculating it for every iteration. 
 // F_i = - 1/\beta ln[\sum_{X_{bins}}{P(x)exp(-\beta U_{bias,i}(x))}]
+// For windows at different temperatures, the bins span the reaction coordinate
+// and the potential energy E, and the cached bias factor of window i becomes
+// exp(-\beta_i U_{bias,i}(x) - (\beta_i - \beta)E), with the target temperature \beta.
+// Both WHAM equations then yield P(x,E) at the target temperature.
 fn calc_window_F(window: usize, dataset: &Dataset, P: &[f64]) -> f64 {
-    let f: f64 = (0..dataset.num_bins).zip(P.iter()) // zip bins and P
-        .map(|bin_and_prob: (usize, &f64)| {
-            let bias = dataset.get_bias(bin_and_prob.0, window);
-            bin_and_prob.1 * bias
-        }).sum();
+    let f: f64 = dataset.window_bias_entries(window)
+        .map(|(bin, bias, _)| P[bin] * bias)
+        .sum();
     1.0/f
 }
 
+// Writes the values of the occupied bins to all bins of P. Empty bins, which
+// are skipped by the WHAM iteration, are set to empty (0 for P, -inf for ln P).
+fn scatter_occupied(dataset: &Dataset, occupied: Vec<f64>, empty: f64, P: &mut Vec<f64>) {
+    P.clear();
+    P.resize(dataset.num_bins, empty);
+    for ((bin, _), p) in dataset.occupied_bins().iter().zip(occupied) {
+        P[*bin] = p;
+    }
+}
+
 // One full WHAM iteration: calculation of new probabilities P and new bias
 // offsets F based on previous bias offsets F_prev. This updates the values in
 // vectors F and P.
 fn perform_wham_iteration(dataset: &Dataset, F_prev: &[f64], F: &mut Vec<f64>, P: &mut Vec<f64>) {
     // Update P
-    // evaluate first WHAM equation for each bin to
+    // evaluate first WHAM equation for each occupied bin to
     // estimate probabilities based on previous offsets (F_prev))
-    (0..dataset.num_bins).into_par_iter()
-        .map(|bin| { calc_bin_probability(bin, dataset, F_prev) })
-        .collect_into_vec(P);
+    let P_occupied: Vec<f64> = dataset.occupied_bins().par_iter()
+        .map(|(bin, _)| { calc_bin_probability(*bin, dataset, F_prev) })
+        .collect();
+    scatter_occupied(dataset, P_occupied, 0.0, P);
 
     // Update F
     // evaluate second WHAM equation for each window to
@@ -124,16 +396,166 @@ fn perform_wham_iteration(dataset: &Dataset, F_prev: &[f64], F: &mut Vec<f64>, P
         .collect_into_vec(F);
 }
 
+// Log-space variant of `calc_bin_probability`. Returns ln P(x) based on the
+// reduced bias offsets f = F/kT. All terms of the denominator are summed in
+// log-space, so that neither exp(F/kT) nor exp(-U/kT) is ever evaluated:
+// ln P(x) = ln[\sum_{i=1}^N{n_i(x)}]
+//           - ln[\sum_{i=1}^N{exp(ln N_i + f_i - \beta U_{bias,i}(x))}]
+fn calc_bin_log_probability(bin: usize, dataset: &Dataset, f: &[f64]) -> f64 {
+    let bin_count: f64 = dataset.get_weighted_bin_count(bin);
+    if bin_count == 0.0 {
+        return f64::NEG_INFINITY
+    }
+    let log_denom = log_sum_exp(dataset.bin_bias_entries(bin).map(|(window, _, log_bias)| {
+        let h = &dataset.histograms[window];
+        (dataset.weights[window] * h.num_points as f64).ln()
+            + f[window] + log_bias
+    }));
+    bin_count.ln() - log_denom
+}
+
+// Log-space variant of `calc_window_F`. Returns the reduced bias offset
+// f_i = F_i/kT based on the logarithmic bin probabilities ln P:
+// f_i = - ln[\sum_{X_{bins}}{exp(ln P(x) - \beta U_{bias,i}(x))}]
+fn calc_window_log_F(window: usize, dataset: &Dataset, lnP: &[f64]) -> f64 {
+    -log_sum_exp(dataset.window_bias_entries(window).map(|(bin, _, log_bias)| {
+        lnP[bin] + log_bias
+    }))
+}
+
+// One full WHAM iteration in log-space. Same as `perform_wham_iteration`, but
+// updates the reduced bias offsets f and logarithmic probabilities ln P.
+fn perform_wham_iteration_log(dataset: &Dataset, f_prev: &[f64], f: &mut Vec<f64>, lnP: &mut Vec<f64>) {
+    let lnP_occupied: Vec<f64> = dataset.occupied_bins().par_iter()
+        .map(|(bin, _)| { calc_bin_log_probability(*bin, dataset, f_prev) })
+        .collect();
+    scatter_occupied(dataset, lnP_occupied, f64::NEG_INFINITY, lnP);
+
+    (0..dataset.num_windows).into_par_iter()
+        .map(|window| { calc_window_log_F(window, dataset, lnP) })
+        .collect_into_vec(f);
+}
+
+// One full WHAM iteration on the reduced bias offsets f = F/kT. Updates f
+// and the bin probabilities P, which hold ln P(x) if logspace is set. Without
+// logspace, the direct WHAM equations are evaluated on exp(f).
+fn perform_wham_iteration_reduced(dataset: &Dataset, logspace: bool,
+        f_prev: &[f64], f: &mut Vec<f64>, P: &mut Vec<f64>) {
+    if logspace {
+        perform_wham_iteration_log(dataset, f_prev, f, P);
+    } else {
+        let F_prev: Vec<f64> = f_prev.iter().map(|x| x.exp()).collect();
+        perform_wham_iteration(dataset, &F_prev, f, P);
+        for x in f.iter_mut() { *x = x.ln() }
+    }
+}
+
+// Reduced bias offsets f = F/kT to start the iteration from. Without initial
+// offsets F_init (in kJ/mol), all windows start at f = 0. Initial offsets are
+// shifted so that the offset of the first window is 0, because F is only
+// defined up to a constant.
+fn initial_reduced_offsets(dataset: &Dataset, F_init: Option<&[f64]>) -> Result<Vec<f64>> {
+    match F_init {
+        Some(F_init) => {
+            if F_init.len() != dataset.num_windows {
+                bail!(format!("Expected {} initial bias offsets but got {}.",
+                    dataset.num_windows, F_init.len()));
+            }
+            Ok(F_init.iter().map(|F| (F - F_init[0]) / dataset.kT).collect())
+        },
+        None => Ok(vec![0.0; dataset.num_windows]),
+    }
+}
+
+// Full WHAM calculation on the reduced bias offsets f = F/kT. This is used
+// for log-space WHAM, which does not suffer from overflow of exp(F/kT) or
+// underflow of exp(-U/kT) for stiff biases or low temperatures, and for
+// DIIS accelerated WHAM, which extrapolates f from previous iterations.
+fn perform_wham_reduced(cfg: &Config, dataset: &Dataset, logspace: bool, F_init: Option<&[f64]>)
+        -> Result<Estimate> {
+    // bin probability (or ln P in log-space)
+    let mut P: Vec<f64> = vec![f64::NAN; dataset.num_bins];
+    // reduced bias offset F/kT
+    let mut f: Vec<f64> = initial_reduced_offsets(dataset, F_init)?;
+    // previous reduced bias offset
+    let mut f_prev: Vec<f64> = vec![f64::NAN; dataset.num_windows];
+
+    let mut diis = diis::Diis::new(cfg.diis);
+    let mut iteration = 0;
+    let mut converged = false;
+
+    // perform WHAM until convergence
+    while !converged && iteration < cfg.max_iterations {
+        iteration += 1;
+        f_prev.copy_from_slice(&f);
+        perform_wham_iteration_reduced(dataset, logspace, &f_prev, &mut f, &mut P);
+
+        // convergence check on F = kT * f before extrapolation
+        let F: Vec<f64> = f.iter().map(|x| x * dataset.kT).collect();
+        let F_prev: Vec<f64> = f_prev.iter().map(|x| x * dataset.kT).collect();
+        converged = is_converged(&F_prev, &F, cfg.tolerance);
+
+        if iteration % 10 == 0 {
+            verbose!("Iteration {}: dF={}", &iteration, &diff_avg(&F_prev, &F));
+        } else {
+            debug!("Iteration {}: dF={}", &iteration, &diff_avg(&F_prev, &F));
+        }
+
+        if !converged {
+            diis.extrapolate(&f_prev, &mut f);
+        }
+    }
+
+    if converged {
+        verbose!("WHAM converged after {} iterations.", iteration);
+    } else {
+        verbose!("WHAM not converged after {} iterations.", iteration);
+    }
+
+    // Normalize P to sum(P) = 1.0. In log-space, the largest probability is
+    // shifted to ln P = 0 before exponentiation to avoid overflow.
+    if logspace {
+        let lnP_max = P.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        for p in P.iter_mut() {
+            *p = (*p - lnP_max).exp();
+        }
+    }
+    let P_sum: f64 = P.iter().sum();
+    for p in P.iter_mut() {
+        *p /= P_sum;
+    }
+
+    let F = f.iter().map(|x| x * dataset.kT).collect();
+    let F_prev = f_prev.iter().map(|x| x * dataset.kT).collect();
+    Ok((P, F, F_prev, iteration))
+}
+
 // Full WHAM calculation. Calls `perform_wham_iteration` until convergence
-// criteria are met or max iterations reached.
-pub fn perform_wham(cfg: &Config, dataset: &Dataset)
-        -> Result<(Vec<f64>, Vec<f64>, Vec<f64>)> {
+// criteria are met or max iterations reached. WHAM is performed in log-space
+// if this is requested by the config or if the bias factors underflow.
+// If DIIS is enabled, the bias offsets are extrapolated between iterations.
+// The iteration starts from the bias offsets F_init (in kJ/mol) if given, e.g.
+// the converged offsets of a similar dataset, and from F = 0 otherwise.
+// Returns the normalized bin probabilities P, the bias offsets F, the
+// bias offsets of the previous iteration (both in kJ/mol) and the number of
+// iterations. If max iterations are reached, the estimate of the last
+// iteration is returned and it is up to the caller to check convergence of F
+// and F_prev.
+pub fn perform_wham(cfg: &Config, dataset: &Dataset, F_init: Option<&[f64]>)
+        -> Result<Estimate> {
+    let logspace = cfg.logspace || dataset.bias_underflows();
+    if logspace || cfg.diis > 0 {
+        return perform_wham_reduced(cfg, dataset, logspace, F_init)
+    }
+
     // allocate required vectors.
 
     // bin probability
     let mut P: Vec<f64> = vec![f64::NAN; dataset.num_bins];
     // bias offset exp(F/kT)
-    let mut F: Vec<f64> = vec![1.0; dataset.num_windows];
+    let mut F: Vec<f64> = initial_reduced_offsets(dataset, F_init)?.iter()
+        .map(|f| f.exp())
+        .collect();
     // previous bias offset
     let mut F_prev: Vec<f64> = vec![f64::NAN; dataset.num_windows];
     // temp storage for F
@@ -150,22 +572,20 @@ pub fn perform_wham(cfg: &Config, dataset: &Dataset)
         F_prev.copy_from_slice(&F);
 
         // perform wham iteration (this updates F and P).
-        perform_wham_iteration(&dataset, &F_prev, &mut F, &mut P);
+        perform_wham_iteration(dataset, &F_prev, &mut F, &mut P);
 
-        // convergence check
-        if iteration % 10 == 0 {
+        // convergence check every 10 iterations and after the last one
+        if iteration % 10 == 0 || iteration == cfg.max_iterations {
             // This backups exp(F/kT) in a temporary vector and calculates
             // true F and F_prev for convergence. Finally, F is restored.
             // F_prev does not need to be restored because its overwritten
             // for the next iteration.
             F_tmp.copy_from_slice(&F);
-            for f in F.iter_mut() { *f = -dataset.kT * f.ln() }
-            for f in F_prev.iter_mut() { *f = -dataset.kT * f.ln() }
+            for f in F.iter_mut() { *f = dataset.kT * f.ln() }
+            for f in F_prev.iter_mut() { *f = dataset.kT * f.ln() }
             converged = is_converged(&F_prev, &F, cfg.tolerance);
 
-            if cfg.verbose {
-                println!("Iteration {}: dF={}", &iteration, &diff_avg(&F_prev, &F));
-            }
+            verbose!("Iteration {}: dF={}", &iteration, &diff_avg(&F_prev, &F));
             F.copy_from_slice(&F_tmp);
         }
     }
@@ -176,52 +596,111 @@ pub fn perform_wham(cfg: &Config, dataset: &Dataset)
         *p /= P_sum; 
     }
 
-    if iteration == cfg.max_iterations {
-        bail!("WHAM not converged! (max iterations reached)");
+    if converged {
+        verbose!("WHAM converged after {} iterations.", iteration);
+    } else {
+        verbose!("WHAM not converged after {} iterations.", iteration);
     }
 
-    Ok((P, F, F_prev))
+    // F holds exp(F/kT) while F_prev has already been converted to F
+    // during the final convergence check.
+    for f in F.iter_mut() { *f = dataset.kT * f.ln() }
+
+    Ok((P, F, F_prev, iteration))
+}
+
+// Solve for the bin probabilities and bias offsets with the estimator
+// selected in the config. Both estimators share the return values of
+// `perform_wham`. The probabilities cover the full histogram grid, including
+// the potential energy of multi-temperature datasets. The estimators start
+// from the initial bias offsets F_init if given.
+pub fn perform_estimation_full(cfg: &Config, dataset: &Dataset, F_init: Option<&[f64]>)
+        -> Result<Estimate> {
+    match cfg.method {
+        Method::Wham => perform_wham(cfg, dataset, F_init),
+        Method::Mbar => mbar::perform_mbar(cfg, dataset, F_init),
+    }
+}
+
+// Same as `perform_estimation_full`, but the probabilities of multi-temperature
+// datasets are marginalized over the potential energy.
+pub fn perform_estimation(cfg: &Config, dataset: &Dataset, F_init: Option<&[f64]>)
+        -> Result<Estimate> {
+    let (P, F, F_prev, iterations) = perform_estimation_full(cfg, dataset, F_init)?;
+    Ok((dataset.marginalize_energy(P), F, F_prev, iterations))
 }
 
-pub fn run(cfg: &Config) -> Result<()>{
-    println!("Supplied WHAM options: {}", &cfg);
+// Reads the datasets of the config and estimates the free energy of each.
+// Output files are written by the reporters in `report`.
+pub fn run(cfg: &Config) -> Result<Vec<WhamResult>> {
+    info!("Supplied WHAM options: {}", &cfg);
 
-    println!("Reading input files.");
-    let datasets = io::read_data(&cfg).chain_err(|| "Failed to read data.")?;
+    info!("Reading input files.");
+    let datasets = io::read_data(cfg).chain_err(|| "Failed to read data.")?;
 
+    // initial bias offsets of the estimator. Subsequent datasets (convdt) start
+    // from the converged offsets of the previous one.
+    let mut F_init: Option<Vec<f64>> = match cfg.initial_offsets {
+        Some(ref path) => Some(io::read_offsets(path)
+            .chain_err(|| "Failed to read initial bias offsets.")?),
+        None => None,
+    };
+
+    let mut results = Vec::new();
     for (idx, dataset) in datasets.iter().enumerate() {
         if datasets.len() > 1 {
-            println!("Dataset {}/{}: {}", idx+1, datasets.len(), &dataset);
+            info!("Dataset {}/{}: {}", idx+1, datasets.len(), &dataset);
         }
         else {
-            println!("{}", &dataset);
+            info!("{}", &dataset);
+        }
+        if cfg.method == Method::Wham && !cfg.logspace && dataset.bias_underflows() {
+            info!("Bias factors exp(-U/kT) underflow. Performing WHAM in log-space.");
+        }
+        let (P, F, F_prev, iterations) = perform_estimation_full(cfg, dataset, F_init.as_deref())?;
+        let converged = is_converged(&F_prev, &F, cfg.tolerance);
+        if converged {
+            info!("{} converged.", cfg.method.to_string().to_uppercase());
+        } else {
+            info!("{} not converged after {} iterations.", cfg.method.to_string().to_uppercase(), iterations);
         }
-        let (P, F, F_prev) = perform_wham(&cfg, &dataset)?;
-        println!("WHAM converged.");
 
-        let (P_std, free_energy_std) = if cfg.bootstrap > 0 {
-            println!("Bootstrapping..");      
-            error_analysis::run_bootstrap(&cfg, dataset.clone(), cfg.bootstrap)
+        // reweight to the temperature grid before marginalizing the energy
+        let thermodynamics = if !cfg.temperature_grid.is_empty() {
+            info!("Reweighting to {} temperatures.", cfg.temperature_grid.len());
+            thermodynamics::reweight_temperatures(dataset, &P, &cfg.temperature_grid)?
         } else {
-            (vec![0.0; P.len()], vec![0.0; P.len()])
+            Vec::new()
         };
+        let P = dataset.marginalize_energy(P);
 
-        // calculate free energy and dump state
-        println!("Finished. Dumping PMF");
-        let free_energy = calc_free_energy(&dataset, &P);
-
-        dump_state(&dataset, &F, &F_prev, &P, &P_std, &free_energy, &free_energy_std);
-        let append = idx > 0 && datasets.len() > 1;
-        let index = if datasets.len() > 1 {
-            Some(idx)
+        // bootstrap runs start from the converged offsets
+        let bootstrap = if cfg.bootstrap > 0 && converged {
+            info!("Bootstrapping..");
+            let bootstrap = error_analysis::run_bootstrap(cfg, dataset.clone(), cfg.bootstrap, &F)
+                .chain_err(|| "Bootstrapping failed.")?;
+            if bootstrap.failed > 0 {
+                info!("{} of {} bootstrap runs did not converge and were discarded.",
+                    bootstrap.failed, cfg.bootstrap);
+            }
+            Some(bootstrap)
         } else {
             None
         };
-        io::write_results(&cfg.output, append, &dataset, &free_energy, &free_energy_std, &P, &P_std, index)
-            .chain_err(|| "Could not write results to output file")?;
+        F_init = Some(F.clone());
+
+        let mut result = WhamResult::new(dataset, P, F, F_prev, iterations, cfg.tolerance, bootstrap);
+        result.thermodynamics = thermodynamics;
+        result.interval = Some(if datasets.len() > 1 {
+            result.dataset = Some(idx);
+            (cfg.start, (cfg.start + cfg.convdt * (idx+1) as f64).min(cfg.end))
+        } else {
+            (cfg.start, cfg.end)
+        });
+        results.push(result);
     }
-    
-    Ok(())
+
+    Ok(results)
 }
 
 
@@ -254,32 +733,11 @@ fn calc_free_energy(dataset: &Dataset, P: &[f64]) -> Vec<f64> {
     free_energy
 }
 
-// Print the current WHAM iteration state. Dumps the PMF and associated vectors 
-fn dump_state(dataset: &Dataset, F: &[f64], F_prev: &[f64], P: &[f64],
-    P_std: &[f64], A: &[f64], A_std: &[f64]) {
-    // TODO fix output of F/F_prev
-    let out = std::io::stdout();
-    let mut lock = out.lock();
-    writeln!(lock, "# PMF").unwrap();
-    writeln!(lock, "#bin\t\tFree Energy\t\t+/-\t\tP(x)\t\t+/-").unwrap();
-    for bin in 0..dataset.num_bins {
-        writeln!(lock, "{:9.5}\t{:9.5}\t{:9.5}\t{:9.5}\t{:9.5}",
-            bin, A[bin], A_std[bin], P[bin], P_std[bin]).unwrap();
-    }
-    writeln!(lock, "# Bias offsets").unwrap();
-    writeln!(lock, "#Window\t\tF\t\tF_prev").unwrap();
-    for window in 0..dataset.num_windows {
-        writeln!(lock, "{}\t{:9.5}\t{:8.8}",
-            window, F[window], (F[window]-F_prev[window]).abs()).unwrap();
-    }
-}
-
-
 #[cfg(test)]
 mod tests {
     use super::histogram::{Dataset,Histogram};
     use std::f64;
-    use super::k_B;
+    use super::{k_B, Config, Method};
 
     macro_rules! assert_delta {
         ($x:expr, $y:expr, $d:expr) => {
@@ -288,11 +746,35 @@ mod tests {
     }
 
 
+    fn cfg() -> Config {
+        Config {
+            hist_min: vec![0.0],
+            hist_max: vec![4.0],
+            num_bins: vec![5],
+            dimens: 1,
+            cyclic: vec![false],
+            ..Default::default()
+        }
+    }
+
+    // dataset with force constants so stiff that exp(-U/kT) underflows
+    // for bins far away from the bias positions
+    fn create_stiff_test_dataset() -> Dataset {
+        let mut bins1 = vec![0.0; 100];
+        let mut bins2 = vec![0.0; 100];
+        bins1[23..26].copy_from_slice(&[2.0, 6.0, 2.0]);
+        bins2[24..27].copy_from_slice(&[2.0, 6.0, 2.0]);
+        let h1 = Histogram::new(10, bins1);
+        let h2 = Histogram::new(10, bins2);
+        Dataset::new(100, vec![100], vec![0.1], vec![0.0], vec![10.0],
+                     vec![2.45, 2.55], vec![500.0, 500.0], 300.0*k_B, vec![h1, h2], vec![false])
+    }
+
     fn create_test_dataset() -> Dataset {
         let h1 = Histogram::new(10, vec![0.0, 1.0, 1.0, 8.0, 0.0]);
         let h2 = Histogram::new(10, vec![0.0, 0.0, 8.0, 1.0, 1.0]);
         Dataset::new(5, vec![5], vec![1.0], vec![0.0], vec![4.0],
-                     vec![1.0, 1.0], vec![10.0, 10.0], 300.0*k_B, vec![h1, h2], false)
+                     vec![1.0, 1.0], vec![10.0, 10.0], 300.0*k_B, vec![h1, h2], vec![false])
     }
 
     #[test]
@@ -312,8 +794,8 @@ mod tests {
     fn calc_bin_probability() {
         let dataset = create_test_dataset();
         let F = vec![1.0; dataset.num_bins]  ;
-        let expected = vec!(0.0, 0.082_529_668_703_131_6, 40.923_558_470_974_93,
-                            124_226.700_033_77, 2_308_526_035.528_374_7);
+        let expected = [0.0, 0.082_529_668_703_131_6, 40.923_558_470_974_93,
+                            124_226.700_033_77, 2_308_526_035.528_374_7];
         expected.iter().enumerate().for_each(|(i, exp)| {
             let p = super::calc_bin_probability(i, &dataset, &F);
             assert_delta!(exp, p, 0.000_000_1);
@@ -324,13 +806,175 @@ mod tests {
     fn calc_bias_offset() {
         let dataset = create_test_dataset();
         let probability = vec!(0.0, 0.1, 0.2, 0.3, 0.4);
-        let expected = vec!(15.927_477_169_990_633, 15.927_477_169_990_633);
+        let expected = [15.927_477_169_990_633, 15.927_477_169_990_633];
         expected.iter().enumerate().for_each(|(i, exp)| {
             let F = super::calc_window_F(i, &dataset, &probability);
             assert_delta!(exp, F, 0.000_000_1);
         })
     }
 
+    #[test]
+    fn calc_bin_log_probability() {
+        let dataset = create_test_dataset();
+        let f = vec![0.0; dataset.num_windows];
+        let expected: Vec<f64> = vec!(0.0, 0.082_529_668_703_131_6, 40.923_558_470_974_93,
+                            124_226.700_033_77, 2_308_526_035.528_374_7);
+        assert_eq!(f64::NEG_INFINITY, super::calc_bin_log_probability(0, &dataset, &f));
+        expected.iter().enumerate().skip(1).for_each(|(i, exp)| {
+            let lnp = super::calc_bin_log_probability(i, &dataset, &f);
+            assert_delta!(exp.ln(), lnp, 0.000_000_1);
+        })
+    }
+
+    #[test]
+    fn calc_window_log_F() {
+        let dataset = create_test_dataset();
+        let lnP: Vec<f64> = [0.0_f64, 0.1, 0.2, 0.3, 0.4].iter().map(|p| p.ln()).collect();
+        let expected = 15.927_477_169_990_633_f64.ln();
+        for window in 0..dataset.num_windows {
+            let f = super::calc_window_log_F(window, &dataset, &lnP);
+            assert_delta!(expected, f, 0.000_000_1);
+        }
+    }
+
+    #[test]
+    fn perform_wham_logspace() {
+        let mut cfg = cfg();
+        let dataset = create_test_dataset();
+        let (P, F, _, _) = super::perform_wham(&cfg, &dataset, None).unwrap();
+        cfg.logspace = true;
+        let (P_log, F_log, _, _) = super::perform_wham(&cfg, &dataset, None).unwrap();
+        for (p, p_log) in P.iter().zip(P_log.iter()) {
+            assert_delta!(p, p_log, 0.000_001);
+        }
+        assert_delta!(F[1]-F[0], F_log[1]-F_log[0], 0.000_01);
+    }
+
+    #[test]
+    fn perform_wham_diis() {
+        let mut cfg = cfg();
+        let dataset = create_test_dataset();
+        let (P, F, _, _) = super::perform_wham(&cfg, &dataset, None).unwrap();
+        for logspace in &[false, true] {
+            cfg.diis = 5;
+            cfg.logspace = *logspace;
+            let (P_diis, F_diis, _, _) = super::perform_wham(&cfg, &dataset, None).unwrap();
+            for (p, p_diis) in P.iter().zip(P_diis.iter()) {
+                assert_delta!(p, p_diis, 0.000_001);
+            }
+            assert_delta!(F[1]-F[0], F_diis[1]-F_diis[0], 0.000_01);
+        }
+    }
+
+    // starting from the converged offsets reproduces the result in fewer iterations
+    #[test]
+    fn perform_wham_initial_offsets() {
+        let mut cfg = cfg();
+        let dataset = create_test_dataset();
+        for diis in &[0, 5] {
+            cfg.diis = *diis;
+            let (P, F, _, iterations) = super::perform_wham(&cfg, &dataset, None).unwrap();
+            let (P_init, F_init, _, iterations_init) =
+                super::perform_wham(&cfg, &dataset, Some(&F)).unwrap();
+            for (p, p_init) in P.iter().zip(P_init.iter()) {
+                assert_delta!(p, p_init, 0.000_001);
+            }
+            assert_delta!(F[1]-F[0], F_init[1]-F_init[0], 0.000_01);
+            assert!(iterations_init <= iterations);
+        }
+        assert!(super::perform_wham(&cfg, &dataset, Some(&[0.0])).is_err());
+    }
+
+    // the last iteration is returned if max iterations are reached
+    #[test]
+    fn perform_wham_not_converged() {
+        let mut cfg = cfg();
+        cfg.max_iterations = 3;
+        let ds = create_test_dataset();
+        let dataset = Dataset::new(5, vec![5], vec![1.0], vec![0.0], vec![4.0],
+            vec![1.0, 3.0], vec![10.0, 10.0], 300.0*k_B, ds.histograms, vec![false]);
+        let (P, F, F_prev, iterations) = super::perform_wham(&cfg, &dataset, None).unwrap();
+        assert_eq!(3, iterations);
+        assert_approx_eq!(1.0, P.iter().sum::<f64>());
+        assert!(!super::is_converged(&F_prev, &F, cfg.tolerance));
+
+        // F_prev is in kJ/mol like in log-space
+        cfg.logspace = true;
+        let (_, F_log, F_prev_log, _) = super::perform_wham(&cfg, &dataset, None).unwrap();
+        for i in 0..F.len() {
+            assert_approx_eq!(F[i] - F[0], F_log[i] - F_log[0]);
+            assert_approx_eq!(F_prev[i] - F_prev[0], F_prev_log[i] - F_prev_log[0]);
+        }
+    }
+
+    #[test]
+    fn perform_wham_bias_cutoff() {
+        let cfg = cfg();
+        let dataset = create_stiff_test_dataset();
+        let (P, F, _, _) = super::perform_wham(&cfg, &dataset, None).unwrap();
+
+        // dropping bias factors below exp(-30) leaves the result unchanged and
+        // removes the underflowing values
+        let sparse = Dataset::new_with_bias_cutoff(dataset, 30.0);
+        assert!(!sparse.bias_underflows());
+        assert!(sparse.window_bias_entries(0).count() < 20);
+        let (P_sparse, F_sparse, _, _) = super::perform_wham(&cfg, &sparse, None).unwrap();
+        for (p, p_sparse) in P.iter().zip(P_sparse.iter()) {
+            assert_delta!(p, p_sparse, 0.000_001);
+        }
+        assert_delta!(F[1]-F[0], F_sparse[1]-F_sparse[0], 0.000_001);
+    }
+
+    #[test]
+    fn perform_wham_stiff() {
+        let cfg = cfg();
+        let dataset = create_stiff_test_dataset();
+        assert!(dataset.bias_underflows());
+        assert!(!create_test_dataset().bias_underflows());
+
+        // underflowing bias factors automatically switch to log-space
+        let (P, F, _, _) = super::perform_wham(&cfg, &dataset, None).unwrap();
+        assert_delta!(1.0, P.iter().sum::<f64>(), 0.000_001);
+        assert!(P.iter().all(|p| p.is_finite()));
+        assert!(F.iter().all(|f| f.is_finite()));
+        assert_delta!(0.0, P[0], 0.000_001);
+        assert!(P[23..27].iter().all(|p| *p > 0.0));
+    }
+
+    // Windows at 300-400 K sample x with V(x) = 5x^2 and E = V(x). The PMF
+    // at 300 K is V(x) up to a constant.
+    #[test]
+    fn perform_estimation_multi_temperature() {
+        let mut cfg = cfg();
+        cfg.metadata_file = "tests/data/metadata_multi_temperature.dat".to_string();
+        cfg.hist_min = vec![-1.5];
+        cfg.hist_max = vec![1.5];
+        cfg.num_bins = vec![30];
+        cfg.cyclic = vec![false];
+        cfg.tolerance = 0.000_01;
+        cfg.energy_min = 0.0;
+        cfg.energy_max = 12.0;
+        cfg.energy_bins = 24;
+        // MBAR requires the samples, which are only kept by read_data for MBAR
+        cfg.method = Method::Mbar;
+        let dataset = &super::io::read_data(&cfg).unwrap()[0];
+        assert!(dataset.energy_dimen);
+        assert_eq!(30*24, dataset.num_bins);
+
+        for method in &[Method::Wham, Method::Mbar] {
+            cfg.method = *method;
+            let (P, _, _, _) = super::perform_estimation(&cfg, dataset, None).unwrap();
+            assert_eq!(30, P.len());
+            let A = super::calc_free_energy(dataset, &P);
+            let x = |bin: usize| dataset.get_coords_for_bin(bin)[0];
+            // compare relative to the center bin x=0.05 in the sampled range |x| < 1
+            for bin in 6..24 {
+                let expected = 5.0 * (x(bin).powi(2) - x(15).powi(2));
+                assert_delta!(expected, A[bin] - A[15], 0.5);
+            }
+        }
+    }
+
     #[test]
     fn perform_wham_iteration() {
         let dataset = create_test_dataset();
@@ -338,9 +982,9 @@ mod tests {
         let mut F = vec![f64::NAN; dataset.num_windows];
         let mut P =  vec![f64::NAN; dataset.num_bins];
         super::perform_wham_iteration(&dataset, &prev_F, &mut F, &mut P);
-        let expected_F = vec!(1.0, 1.0);
-        let expected_P = vec!(0.0, 0.082_529_668_703_131_6, 40.923_558_470_974_93,
-                            124_226.700_033_77, 2_308_526_035.528_374_7);
+        let expected_F = [1.0, 1.0];
+        let expected_P = [0.0, 0.082_529_668_703_131_6, 40.923_558_470_974_93,
+                            124_226.700_033_77, 2_308_526_035.528_374_7];
         for bin in 0..dataset.num_bins {
             assert_delta!(expected_P[bin], P[bin], 0.01)
         }
@@ -349,4 +993,37 @@ mod tests {
         }
         
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn run_results() {
+        let mut cfg = cfg();
+        cfg.metadata_file = "example/1d_cyclic/metadata.dat".to_string();
+        cfg.hist_min = vec![-f64::consts::PI];
+        cfg.hist_max = vec![f64::consts::PI];
+        cfg.num_bins = vec![20];
+        cfg.cyclic = vec![true];
+        cfg.tolerance = 0.001;
+        cfg.end = 10.0;
+        cfg.convdt = 5.0;
+        let results = super::run(&cfg).unwrap();
+        assert_eq!(2, results.len());
+        for (idx, result) in results.iter().enumerate() {
+            assert_eq!(Some(idx), result.dataset);
+            assert_eq!(Some((0.0, 5.0 * (idx+1) as f64)), result.interval);
+            assert!(result.converged);
+            assert!(result.max_dF() < cfg.tolerance);
+            assert_eq!(vec![20], result.shape);
+            assert_eq!(20, result.coords.len());
+            assert_delta!(1.0, result.probability.iter().sum::<f64>(), 1e-9);
+            assert!(result.bootstrap.is_none());
+            assert_eq!(vec![0.0; 25], result.F_std);
+        }
+        let points = |r: &super::WhamResult| r.num_points.iter().sum::<u32>();
+        assert!(points(&results[0]) < points(&results[1]));
+
+        // non-converged results are returned to the caller
+        cfg.max_iterations = 5;
+        let results = super::run(&cfg).unwrap();
+        assert!(results.iter().all(|result| !result.converged && result.iterations == 5));
+    }
+}
@@ -0,0 +1,193 @@
+// Logging facade for diagnostics and progress reports. Messages are written
+// to stderr by default, so they do not mix with data written to stdout.
+// Embedding applications can capture or silence them with `set_logger`,
+// `set_progress` and `set_level`.
+use std::fmt;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Verbosity of the diagnostics. Each level includes the messages of the
+// levels before it.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum LogLevel {
+    // no diagnostics at all
+    Quiet,
+    // progress of the calculation and warnings
+    Normal,
+    // additional details, e.g. the convergence of the estimators
+    Verbose,
+    // every iteration of the estimators
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogLevel::Quiet => write!(f, "quiet"),
+            LogLevel::Normal => write!(f, "normal"),
+            LogLevel::Verbose => write!(f, "verbose"),
+            LogLevel::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+// Receives diagnostics that pass the level set by `set_level`.
+pub trait Logger: Send + Sync {
+    fn log(&self, level: LogLevel, message: &str);
+}
+
+// Receives progress reports of long running tasks, e.g. bootstrapping.
+// Updates may be reported from multiple threads and out of order.
+pub trait Progress: Send + Sync {
+    // done of total steps of the task are finished
+    fn update(&self, task: &str, done: usize, total: usize);
+
+    // the task is finished
+    fn finish(&self, _task: &str) {}
+}
+
+// Default logger. Writes all messages to stderr.
+pub struct StderrLogger;
+
+impl Logger for StderrLogger {
+    fn log(&self, _level: LogLevel, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+// Default progress report. Logs each step as "task done/total".
+pub struct LogProgress;
+
+impl Progress for LogProgress {
+    fn update(&self, task: &str, done: usize, total: usize) {
+        log(LogLevel::Normal, &format!("{} {}/{}", task, done, total));
+    }
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Normal as usize);
+static LOGGER: RwLock<Option<Box<dyn Logger>>> = RwLock::new(None);
+static PROGRESS: RwLock<Option<Box<dyn Progress>>> = RwLock::new(None);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Quiet,
+        1 => LogLevel::Normal,
+        2 => LogLevel::Verbose,
+        _ => LogLevel::Debug,
+    }
+}
+
+// Replaces the logger for all subsequent messages.
+pub fn set_logger(logger: Box<dyn Logger>) {
+    *LOGGER.write().unwrap() = Some(logger);
+}
+
+// Replaces the progress report for all subsequent tasks.
+pub fn set_progress(progress: Box<dyn Progress>) {
+    *PROGRESS.write().unwrap() = Some(progress);
+}
+
+// Returns true if messages of the given level are logged.
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::Quiet && level <= self::level()
+}
+
+pub fn log(level: LogLevel, message: &str) {
+    if !enabled(level) {
+        return
+    }
+    match *LOGGER.read().unwrap() {
+        Some(ref logger) => logger.log(level, message),
+        None => StderrLogger.log(level, message),
+    }
+}
+
+// Reports the progress of a task. Progress is shown at the normal level.
+pub fn progress(task: &str, done: usize, total: usize) {
+    if !enabled(LogLevel::Normal) {
+        return
+    }
+    match *PROGRESS.read().unwrap() {
+        Some(ref progress) => progress.update(task, done, total),
+        None => LogProgress.update(task, done, total),
+    }
+}
+
+pub fn finish(task: &str) {
+    if !enabled(LogLevel::Normal) {
+        return
+    }
+    match *PROGRESS.read().unwrap() {
+        Some(ref progress) => progress.finish(task),
+        None => LogProgress.finish(task),
+    }
+}
+
+// Shorthands for formatted messages. The message is only formatted if the
+// level is enabled.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Normal) {
+            $crate::log::log($crate::log::LogLevel::Normal, &format!($($arg)*));
+        }
+    }
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Verbose) {
+            $crate::log::log($crate::log::LogLevel::Verbose, &format!($($arg)*));
+        }
+    }
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Debug) {
+            $crate::log::log($crate::log::LogLevel::Debug, &format!($($arg)*));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Capture(Arc<Mutex<Vec<(LogLevel, String)>>>);
+
+    impl Logger for Capture {
+        fn log(&self, level: LogLevel, message: &str) {
+            self.0.lock().unwrap().push((level, message.to_string()));
+        }
+    }
+
+    #[test]
+    fn levels() {
+        assert!(!enabled(LogLevel::Quiet));
+        assert!(LogLevel::Quiet < LogLevel::Normal);
+        assert!(LogLevel::Verbose < LogLevel::Debug);
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        set_logger(Box::new(Capture(messages.clone())));
+        set_level(LogLevel::Verbose);
+        assert_eq!(LogLevel::Verbose, level());
+        info!("log test {}", 1);
+        verbose!("log test {}", 2);
+        debug!("log test {}", 3);
+        set_level(LogLevel::Normal);
+        set_logger(Box::new(StderrLogger));
+
+        // other tests may log concurrently
+        let messages: Vec<(LogLevel, String)> = messages.lock().unwrap().iter()
+            .filter(|(_, m)| m.starts_with("log test"))
+            .cloned()
+            .collect();
+        assert_eq!(vec![(LogLevel::Normal, "log test 1".to_string()),
+            (LogLevel::Verbose, "log test 2".to_string())], messages);
+    }
+}
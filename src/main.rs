@@ -7,16 +7,60 @@ extern crate error_chain;
 
 use rand::prelude::*;
 use clap::App;
-use wham::Config;
+use wham::{BootstrapScheme, Config, Method, OutputFormat};
 use wham::errors::*;
+use wham::log::{self, LogLevel, Progress};
+use std::io::{IsTerminal, Write};
 use std::process;
+use std::sync::Mutex;
+
+// Progress bar on stderr for interactive sessions
+struct ProgressBar {
+    // number of finished steps already drawn
+    drawn: Mutex<usize>,
+}
+
+impl Progress for ProgressBar {
+    fn update(&self, task: &str, done: usize, total: usize) {
+        // updates from parallel runs may arrive out of order
+        let mut drawn = self.drawn.lock().unwrap();
+        if done <= *drawn {
+            return
+        }
+        *drawn = done;
+        let width = 40;
+        let filled = width * done / total.max(1);
+        eprint!("\r{} [{}{}] {}/{}", task, "#".repeat(filled), " ".repeat(width - filled.min(width)), done, total);
+        std::io::stderr().flush().unwrap_or(());
+    }
+
+    fn finish(&self, _task: &str) {
+        let mut drawn = self.drawn.lock().unwrap();
+        if *drawn > 0 {
+            eprintln!();
+        }
+        *drawn = 0;
+    }
+}
 
 // Parse command line arguments into a Config struct
 fn cli() -> Result<Config> {
     let yaml = load_yaml!("cli.yml");
-    let matches = App::from_yaml(yaml).get_matches();
-    let metadata_file = matches.value_of("metadata").unwrap().to_string();
-    let verbose: bool = matches.is_present("verbose");
+    // -vv increases the verbosity further, which the YAML definition cannot express
+    let matches = App::from_yaml(yaml)
+        .mut_arg("verbose", |arg| arg.multiple_occurrences(true))
+        .get_matches();
+    let metadata_file = matches.value_of("metadata").unwrap_or("").to_string();
+    let log_level = if matches.is_present("quiet") {
+        LogLevel::Quiet
+    } else {
+        match matches.occurrences_of("verbose") {
+            0 => LogLevel::Normal,
+            1 => LogLevel::Verbose,
+            _ => LogLevel::Debug,
+        }
+    };
+    log::set_level(log_level);
     let temperature: f64 = matches.value_of("temperature").unwrap().parse()
         .chain_err(|| "Cannot read temperature.")?;
     let tolerance: f64 = matches.value_of("tolerance").unwrap_or("0.000001").parse()
@@ -24,30 +68,36 @@ fn cli() -> Result<Config> {
     let max_iterations: usize = matches.value_of("iterations").unwrap_or("100000").parse()
         .chain_err(|| "Cannot parse iterations.")?;
     let output = matches.value_of("output").unwrap_or("wham.out").to_string();
-    let cyclic: bool = matches.is_present("cyclic");
+    let output_format = match matches.value_of("output_format").unwrap_or("text") {
+        "json" => OutputFormat::Json,
+        "csv" => OutputFormat::Csv,
+        "npy" => OutputFormat::Npy,
+        "npz" => OutputFormat::Npz,
+        _ => OutputFormat::Text,
+    };
 
-    let hist_min: Vec<f64> = matches.value_of("min_hist").unwrap()
-        .split(',').map(|x| {
-            if x.to_ascii_lowercase() == "pi" {
+    let hist_min: Vec<f64> = matches.value_of("min_hist").unwrap_or("")
+        .split_terminator(',').map(|x| {
+            if x.eq_ignore_ascii_case("pi") {
                 std::f64::consts::PI
-            } else if x.to_ascii_lowercase() == "-pi" {
+            } else if x.eq_ignore_ascii_case("-pi") {
                 -std::f64::consts::PI
             } else {
                 x.parse().unwrap()
             }
         }).collect();
-    let hist_max: Vec<f64> = matches.value_of("max_hist").unwrap()
-        .split(',').map(|x| {
-            if x.to_ascii_lowercase() == "pi" {
+    let hist_max: Vec<f64> = matches.value_of("max_hist").unwrap_or("")
+        .split_terminator(',').map(|x| {
+            if x.eq_ignore_ascii_case("pi") {
                 std::f64::consts::PI
-            } else if x.to_ascii_lowercase() == "-pi" {
+            } else if x.eq_ignore_ascii_case("-pi") {
                 -std::f64::consts::PI
             } else {
                 x.parse().unwrap()
             }
         }).collect();
-    let num_bins: Vec<usize> = matches.value_of("bins").unwrap()
-        .split(',').map(|x| { x.parse().unwrap() }).collect();
+    let num_bins: Vec<usize> = matches.value_of("bins").unwrap_or("")
+        .split_terminator(',').map(|x| { x.parse().unwrap() }).collect();
     let bootstrap: usize = matches.value_of("bootstrap").unwrap_or("0").parse()
         .chain_err(|| "Cannot parse bootstrap iteration.")?;
     let bootstrap_seed: u64 = matches.value_of("bootstrap_seed")
@@ -56,6 +106,25 @@ fn cli() -> Result<Config> {
             &rng.gen::<u32>().to_string()
         }).parse()
         .chain_err(|| "Cannot parse bootstrap iteration.")?;
+    let bootstrap_scheme = match matches.value_of("bootstrap_scheme").unwrap_or("bayesian") {
+        "block" => BootstrapScheme::Block,
+        "poisson" => BootstrapScheme::Poisson,
+        _ => BootstrapScheme::Bayesian,
+    };
+    let bootstrap_sd: bool = matches.is_present("bootstrap_sd");
+    let bootstrap_ci: f64 = matches.value_of("bootstrap_ci").unwrap_or("0").parse()
+        .chain_err(|| "Cannot parse bootstrap confidence level.")?;
+    if !(0.0..100.0).contains(&bootstrap_ci) {
+        bail!("--bt_ci requires a confidence level between 0 and 100.")
+    }
+    let bootstrap_output = matches.value_of("bootstrap_output").map(|x| x.to_string());
+    let bootstrap_retries: usize = matches.value_of("bootstrap_retries").unwrap_or("0").parse()
+        .chain_err(|| "Cannot parse bootstrap retries.")?;
+    let bias_cutoff: f64 = matches.value_of("bias_cutoff").unwrap_or("0").parse()
+        .chain_err(|| "Cannot parse bias cutoff.")?;
+    if bias_cutoff < 0.0 {
+        bail!("--bias_cutoff must not be negative.")
+    }
     let start: f64 = matches.value_of("start").unwrap_or("0").parse()
         .chain_err(|| "Cannot parse start time.")?;
     let end: f64 = matches.value_of("end").unwrap_or("1e+20").parse()
@@ -70,6 +139,19 @@ fn cli() -> Result<Config> {
     }
 
     let dimens = num_bins.len();
+
+    // --cyclic without values makes all dimensions periodic
+    let cyclic: Vec<bool> = match matches.value_of("cyclic") {
+        Some(flags) => flags.split(',').map(|x| match x {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => bail!(format!("Cannot parse cyclic flag {}.", x)),
+        }).collect::<Result<Vec<bool>>>()?,
+        None => vec![matches.is_present("cyclic"); dimens],
+    };
+    if cyclic.len() != dimens {
+        bail!(format!("Expected {} cyclic flags but got {}.", dimens, cyclic.len()))
+    }
     if matches.is_present("convdt") && (!matches.is_present("start") || !matches.is_present("end")) {
         bail!("--convdt requires --start and --end to be set.")
     }
@@ -78,15 +160,72 @@ fn cli() -> Result<Config> {
 
     let ignore_empty: bool = matches.is_present("ignore_empty");
 
+    let method = match matches.value_of("method").unwrap_or("wham") {
+        "mbar" => Method::Mbar,
+        _ => Method::Wham,
+    };
+
+    let logspace: bool = matches.is_present("logspace");
+    let diis: usize = matches.value_of("diis").unwrap_or("0").parse()
+        .chain_err(|| "Cannot parse DIIS history.")?;
+
+    let energy_bins: usize = matches.value_of("energy_bins").unwrap_or("0").parse()
+        .chain_err(|| "Cannot parse energy bins.")?;
+    let energy_min: f64 = matches.value_of("energy_min").unwrap_or("0").parse()
+        .chain_err(|| "Cannot parse energy minimum.")?;
+    let energy_max: f64 = matches.value_of("energy_max").unwrap_or("0").parse()
+        .chain_err(|| "Cannot parse energy maximum.")?;
+    if energy_bins > 0 && energy_min >= energy_max {
+        bail!("--ebins requires --emin to be smaller than --emax.")
+    }
+
+    let temperature_grid: Vec<f64> = match matches.value_of("temperature_grid") {
+        Some(grid) => {
+            let values: Vec<&str> = grid.split(',').collect();
+            if values.len() != 3 {
+                bail!("--tgrid requires TMIN,TMAX,STEPS.")
+            }
+            let t_min: f64 = values[0].parse().chain_err(|| "Cannot parse minimum temperature.")?;
+            let t_max: f64 = values[1].parse().chain_err(|| "Cannot parse maximum temperature.")?;
+            let steps: usize = values[2].parse().chain_err(|| "Cannot parse temperature steps.")?;
+            wham::thermodynamics::temperature_grid(t_min, t_max, steps)
+        },
+        None => Vec::new(),
+    };
+    let thermo_output = matches.value_of("thermo_output").unwrap_or("thermo.out").to_string();
+    let offsets_output = matches.value_of("offsets_output").map(|x| x.to_string());
+    let initial_offsets = matches.value_of("initial_offsets").map(|x| x.to_string());
+    let pullx_files = matches.value_of("pullx_files").map(|x| x.to_string());
+    let pullf_files = matches.value_of("pullf_files").map(|x| x.to_string());
+    let pull_params = matches.value_of("pull_params").map(|x| x.to_string());
+    let columns: Vec<String> = matches.value_of("columns").unwrap_or("")
+        .split_terminator(',').map(|x| x.to_string()).collect();
+    let bias_column = matches.value_of("bias_column").map(|x| x.to_string());
+    let stream = matches.is_present("stream");
+
     Ok(wham::Config{metadata_file, hist_min, hist_max, num_bins, dimens,
-        verbose, tolerance, max_iterations, temperature, cyclic, output,
-        bootstrap, bootstrap_seed, start, end, uncorr, convdt, ignore_empty})
+        tolerance, max_iterations, temperature, cyclic, output,
+        output_format, bootstrap, bootstrap_seed, bootstrap_scheme, start, end, uncorr, convdt,
+        ignore_empty, method, logspace, diis, energy_min, energy_max, energy_bins, temperature_grid,
+        thermo_output, offsets_output, initial_offsets, bootstrap_sd, bootstrap_ci, bootstrap_output,
+        bootstrap_retries, bias_cutoff, pullx_files, pullf_files, pull_params,
+        columns, bias_column, stream})
 }
 
 fn main() {
 
     let cfg = cli().expect("Failed to parse CLI.");
-    if let Err(error) = wham::run(&cfg) {
+    if std::io::stderr().is_terminal() {
+        log::set_progress(Box::new(ProgressBar { drawn: Mutex::new(0) }));
+    }
+    let result = wham::run(&cfg).and_then(|results| {
+        // results are only written if every dataset converged
+        if results.iter().any(|result| !result.converged) {
+            bail!(format!("{} not converged! (max iterations reached)", cfg.method.to_string().to_uppercase()));
+        }
+        wham::report::report(&cfg, &results)
+    });
+    if let Err(error) = result {
         eprintln!("Error: {}", error);
 
         for e in error.iter().skip(1) {
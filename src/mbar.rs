@@ -0,0 +1,257 @@
+use super::histogram::Dataset;
+use super::{Config, Estimate, initial_reduced_offsets};
+use super::errors::*;
+use super::statistics::log_sum_exp;
+use super::diis::Diis;
+use std::f64;
+use rayon::prelude::*;
+
+// Multistate Bennett acceptance ratio (MBAR) estimator. In contrast to WHAM,
+// MBAR does not bin the samples to estimate the window offsets. Instead, every
+// sample is reweighted individually. The PMF is then obtained by binning the
+// unbiased sample weights into the bins of the dataset.
+// For details, see "Shirts, M.R. and Chodera, J.D. (2008). Statistically
+// optimal analysis of samples from multiple equilibrium states, JCP"
+
+// Collects references to all samples of the dataset together with the
+// index of the window they were drawn from.
+fn collect_samples(dataset: &Dataset) -> Vec<(usize, &[f64])> {
+    let dimens = dataset.dimens_lengths.len();
+    dataset.histograms.iter().enumerate().flat_map(|(window, h)| {
+        h.samples.chunks(dimens).map(move |sample| (window, sample))
+    }).collect()
+}
+
+// Per-frame bias energy of each sample in the order of collect_samples, or
+// None for samples of windows without per-frame biases.
+fn collect_sample_bias(dataset: &Dataset) -> Vec<Option<f64>> {
+    let dimens = dataset.dimens_lengths.len();
+    dataset.histograms.iter().flat_map(|h| {
+        let num_samples = h.samples.len() / dimens;
+        (0..num_samples).map(move |i| h.sample_bias.get(i).cloned())
+    }).collect()
+}
+
+// Reduced bias potentials u_k(x_n) = U_k(x_n)/kT_k of all windows k for every
+// sample n. The potentials are stored sample-major, i.e. u[n*K + k]. The
+// per-frame bias of a sample is used for the window it was drawn from.
+fn reduced_bias_matrix(dataset: &Dataset, samples: &[(usize, &[f64])]) -> Vec<f64> {
+    let num_windows = dataset.num_windows;
+    let sample_bias = collect_sample_bias(dataset);
+    let mut u = vec![0.0; samples.len() * num_windows];
+    u.par_chunks_mut(num_windows).zip(samples.par_iter()).zip(sample_bias.par_iter())
+        .for_each(|((u_n, (sample_window, sample)), frame_bias)| {
+            for (window, uk) in u_n.iter_mut().enumerate() {
+                *uk = match *frame_bias {
+                    Some(b) if window == *sample_window => dataset.calc_reduced_sample_bias(sample, window, b),
+                    _ => dataset.calc_reduced_bias(sample, window),
+                };
+            }
+        });
+    u
+}
+
+// Logarithm of the MBAR denominator for a sample:
+// ln[\sum_{k=1}^K{N_k exp(f_k - u_k(x_n))}]
+fn log_denominator(ln_N: &[f64], f: &[f64], u_n: &[f64]) -> f64 {
+    log_sum_exp((0..f.len()).map(|k| ln_N[k] + f[k] - u_n[k]))
+}
+
+// One self-consistent MBAR iteration. Evaluates the MBAR equation for each
+// window based on the previous reduced offsets f_prev and stores the result in f.
+// f_i = -ln[\sum_{n=1}^N{w_n exp(-u_i(x_n)) / \sum_{k=1}^K{N_k exp(f_k - u_k(x_n))}}]
+// w_n is the weight of the window that generated sample n. The offsets are
+// shifted so that f_0 = 0.
+fn perform_mbar_iteration(dataset: &Dataset, samples: &[(usize, &[f64])], u: &[f64],
+        ln_N: &[f64], f_prev: &[f64], f: &mut [f64]) {
+    let num_windows = dataset.num_windows;
+    let sums = u.par_chunks(num_windows).zip(samples.par_iter())
+        .fold(|| vec![0.0; num_windows], |mut acc, (u_n, (window, _))| {
+            let log_denom = log_denominator(ln_N, f_prev, u_n);
+            let weight = dataset.weights[*window];
+            for (a, uk) in acc.iter_mut().zip(u_n.iter()) {
+                *a += weight * (-uk - log_denom).exp();
+            }
+            acc
+        })
+        .reduce(|| vec![0.0; num_windows], |mut a, b| {
+            for (ai, bi) in a.iter_mut().zip(b.iter()) {
+                *ai += bi;
+            }
+            a
+        });
+
+    for (fi, s) in f.iter_mut().zip(sums.iter()) {
+        *fi = -s.ln();
+    }
+    let f0 = f[0];
+    for fi in f.iter_mut() {
+        *fi -= f0;
+    }
+}
+
+// Unbiased probability of each bin. The weight of a sample in the unbiased
+// state is w_n / \sum_{k=1}^K{N_k exp(f_k - u_k(x_n))}
+fn calc_probabilities(dataset: &Dataset, samples: &[(usize, &[f64])], u: &[f64],
+        ln_N: &[f64], f: &[f64]) -> Vec<f64> {
+    let num_windows = dataset.num_windows;
+    let mut P = vec![0.0; dataset.num_bins];
+    for (u_n, (window, sample)) in u.chunks(num_windows).zip(samples.iter()) {
+        if let Some(bin) = dataset.get_bin_for_coords(sample) {
+            let log_denom = log_denominator(ln_N, f, u_n);
+            P[bin] += dataset.weights[*window] * (-log_denom).exp();
+        }
+    }
+    let P_sum: f64 = P.iter().sum();
+    for p in P.iter_mut() {
+        *p /= P_sum;
+    }
+    P
+}
+
+// Full MBAR calculation. Iterates the self-consistent MBAR equations until
+// the offsets change less than the tolerance or max iterations are reached.
+// The iteration starts from the bias offsets F_init if given.
+// Returns the normalized bin probabilities P, the bias offsets F, the bias
+// offsets of the previous iteration (both in kJ/mol) and the number of
+// iterations, in line with `perform_wham`. Estimates that did not converge
+// within max iterations are returned as well.
+pub fn perform_mbar(cfg: &Config, dataset: &Dataset, F_init: Option<&[f64]>)
+        -> Result<Estimate> {
+    let samples = collect_samples(dataset);
+    if samples.is_empty() {
+        bail!("MBAR requires the samples of each window but the dataset has none.");
+    }
+    let u = reduced_bias_matrix(dataset, &samples);
+
+    // logarithm of the (weighted) number of samples of each window.
+    // Empty windows are -inf and therefore do not contribute.
+    let ln_N: Vec<f64> = dataset.histograms.iter().zip(dataset.weights.iter())
+        .map(|(h, w)| (w * h.num_points as f64).ln())
+        .collect();
+
+    // reduced bias offsets f = F/kT
+    let mut f: Vec<f64> = initial_reduced_offsets(dataset, F_init)?;
+    let mut f_prev: Vec<f64> = vec![f64::NAN; dataset.num_windows];
+
+    let mut diis = Diis::new(cfg.diis);
+    let mut iteration = 0;
+    let mut converged = false;
+    while !converged && iteration < cfg.max_iterations {
+        iteration += 1;
+        f_prev.copy_from_slice(&f);
+        perform_mbar_iteration(dataset, &samples, &u, &ln_N, &f_prev, &mut f);
+
+        let max_diff = f.iter().zip(f_prev.iter())
+            .map(|(a, b)| ((a - b) * dataset.kT).abs())
+            .fold(0.0, f64::max);
+        converged = max_diff < cfg.tolerance;
+
+        if iteration % 10 == 0 {
+            verbose!("Iteration {}: dF={}", &iteration, &max_diff);
+        } else {
+            debug!("Iteration {}: dF={}", &iteration, &max_diff);
+        }
+
+        if !converged {
+            diis.extrapolate(&f_prev, &mut f);
+        }
+    }
+
+    if converged {
+        verbose!("MBAR converged after {} iterations.", iteration);
+    } else {
+        verbose!("MBAR not converged after {} iterations.", iteration);
+    }
+
+    let P = calc_probabilities(dataset, &samples, &u, &ln_N, &f);
+    let F = f.iter().map(|fi| fi * dataset.kT).collect();
+    let F_prev = f_prev.iter().map(|fi| fi * dataset.kT).collect();
+    Ok((P, F, F_prev, iteration))
+}
+
+#[cfg(test)]
+#[allow(clippy::approx_constant)]
+mod tests {
+    use super::super::histogram::{Dataset,Histogram};
+    use super::super::{Config, Method, k_B, perform_wham};
+    use assert_approx_eq::assert_approx_eq;
+
+    fn cfg() -> Config {
+        Config {
+            metadata_file: "example/1d_cyclic/metadata.dat".to_string(),
+            hist_min: vec![-3.14],
+            hist_max: vec![3.14],
+            num_bins: vec![10],
+            dimens: 1,
+            cyclic: vec![true],
+            method: Method::Mbar,
+            ..Default::default()
+        }
+    }
+
+    // two windows with samples at the bin centers of a 1D histogram
+    fn create_test_dataset() -> Dataset {
+        let h1 = Histogram::new_with_samples(4, vec![1.0, 2.0, 1.0, 0.0],
+            vec![0.5, 1.5, 1.5, 2.5]);
+        let h2 = Histogram::new_with_samples(4, vec![0.0, 1.0, 2.0, 1.0],
+            vec![1.5, 2.5, 2.5, 3.5]);
+        Dataset::new(4, vec![4], vec![1.0], vec![0.0], vec![4.0],
+            vec![1.0, 3.0], vec![1.0, 1.0], 300.0*k_B, vec![h1, h2], vec![false])
+    }
+
+    // if all samples are located at bin centers, MBAR and WHAM are identical
+    #[test]
+    fn perform_mbar_bin_centers() {
+        let mut cfg = cfg();
+        cfg.cyclic = vec![false];
+        let ds = create_test_dataset();
+        let (P_mbar, F_mbar, _, _) = super::perform_mbar(&cfg, &ds, None).unwrap();
+        let (P_wham, F_wham, _, _) = perform_wham(&cfg, &ds, None).unwrap();
+        for (p_mbar, p_wham) in P_mbar.iter().zip(P_wham.iter()) {
+            assert_approx_eq!(p_mbar, p_wham, 0.000_01);
+        }
+        // offsets are only defined up to a constant
+        let dF_mbar = F_mbar[1] - F_mbar[0];
+        let dF_wham = F_wham[1] - F_wham[0];
+        assert_approx_eq!(dF_mbar, dF_wham, 0.000_01);
+    }
+
+    #[test]
+    fn perform_mbar_diis() {
+        let mut cfg = cfg();
+        cfg.cyclic = vec![false];
+        let ds = create_test_dataset();
+        let (P, F, _, _) = super::perform_mbar(&cfg, &ds, None).unwrap();
+        cfg.diis = 5;
+        let (P_diis, F_diis, _, _) = super::perform_mbar(&cfg, &ds, None).unwrap();
+        for (p, p_diis) in P.iter().zip(P_diis.iter()) {
+            assert_approx_eq!(p, p_diis, 0.000_01);
+        }
+        assert_approx_eq!(F[1] - F[0], F_diis[1] - F_diis[0], 0.000_01);
+    }
+
+    #[test]
+    fn perform_mbar_no_samples() {
+        let cfg = cfg();
+        let h = Histogram::new(1, vec![1.0, 0.0, 0.0, 0.0]);
+        let ds = Dataset::new(4, vec![4], vec![1.0], vec![0.0], vec![4.0],
+            vec![1.0], vec![1.0], 300.0*k_B, vec![h], vec![false]);
+        assert!(super::perform_mbar(&cfg, &ds, None).is_err());
+    }
+
+    // MBAR and WHAM should agree on the 1D example with fine bins
+    #[test]
+    fn perform_mbar_1d_cyclic() {
+        let mut cfg = cfg();
+        cfg.num_bins = vec![100];
+        cfg.end = 10.0;
+        cfg.tolerance = 0.001;
+        let ds = &super::super::io::read_data(&cfg).unwrap()[0];
+        let (P_mbar, _, _, _) = super::perform_mbar(&cfg, ds, None).unwrap();
+        let (P_wham, _, _, _) = perform_wham(&cfg, ds, None).unwrap();
+        for (p_mbar, p_wham) in P_mbar.iter().zip(P_wham.iter()) {
+            assert_approx_eq!(p_mbar, p_wham, 0.001);
+        }
+    }
+}
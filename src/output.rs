@@ -0,0 +1,429 @@
+use super::{Config, OutputFormat, WhamResult};
+use super::errors::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+use std::f64;
+
+// Machine-readable output formats of the WHAM results. The PMF is stored as
+// flat arrays over the histogram bins, where the first dimension varies
+// fastest (see `Dataset::get_coords_for_bin`). Arrays of .npy files are
+// therefore written in Fortran order with shape dimens_lengths, so that
+// A[i, j] is the free energy of bin i of the first and j of the second
+// dimension after loading with numpy.load().
+
+// Writes the results of a dataset in the configured structured output format.
+// For multiple datasets (convdt), each dataset is written to its own file with
+// the dataset index appended to the file name, e.g. wham_0.json.
+pub fn write_output(cfg: &Config, result: &WhamResult) -> Result<()> {
+    let out_file = dataset_path(&cfg.output, result.dataset)?;
+    let f = File::create(&out_file)
+        .chain_err(|| format!("Failed to create file with path {}", out_file))?;
+    let mut buf = BufWriter::new(f);
+    let shape = &result.shape;
+    match cfg.output_format {
+        OutputFormat::Json => writeln!(buf, "{}", results_json(cfg, result)),
+        OutputFormat::Csv => write_csv(&mut buf, result),
+        OutputFormat::Npy => write_npy(&mut buf, &result.free_energy, shape),
+        OutputFormat::Npz => {
+            let mut arrays: Vec<(String, Vec<u8>)> = Vec::new();
+            for (name, data) in &[("free_energy", &result.free_energy),
+                    ("free_energy_std", &result.free_energy_std), ("probability", &result.probability),
+                    ("probability_std", &result.probability_std)] {
+                arrays.push((name.to_string(), npy_bytes(data, shape)));
+            }
+            let (free_ci, prob_ci) = result.confidence_intervals();
+            if !free_ci.is_empty() {
+                let (free_low, free_high): (Vec<f64>, Vec<f64>) = free_ci.iter().cloned().unzip();
+                let (prob_low, prob_high): (Vec<f64>, Vec<f64>) = prob_ci.iter().cloned().unzip();
+                for (name, data) in &[("free_energy_ci_low", free_low), ("free_energy_ci_high", free_high),
+                        ("probability_ci_low", prob_low), ("probability_ci_high", prob_high)] {
+                    arrays.push((name.to_string(), npy_bytes(data, shape)));
+                }
+            }
+            for (d, axis) in coord_axes(result).iter().enumerate() {
+                arrays.push((format!("coord{}", d+1), npy_bytes(axis, &[axis.len()])));
+            }
+            arrays.push(("F".to_string(), npy_bytes(&result.F, &[result.F.len()])));
+            arrays.push(("F_prev".to_string(), npy_bytes(&result.F_prev, &[result.F_prev.len()])));
+            write_npz(&mut buf, &arrays)
+        },
+        OutputFormat::Text => bail!("Text output is written by io::write_results."),
+    }.chain_err(|| "Failed to write to file.")?;
+    Ok(())
+}
+
+// Appends the dataset index to the file name if given: wham.json -> wham_1.json
+fn dataset_path(out_file: &str, index: Option<usize>) -> Result<String> {
+    match index {
+        Some(index) => {
+            let path = Path::new(out_file);
+            let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => stem,
+                None => bail!(format!("Invalid output file name {}.", out_file)),
+            };
+            let name = match path.extension() {
+                Some(ext) => match ext.to_str() {
+                    Some(ext) => format!("{}_{}.{}", stem, index, ext),
+                    None => bail!(format!("Invalid output file name {}.", out_file)),
+                },
+                None => format!("{}_{}", stem, index),
+            };
+            match path.with_file_name(name).to_str() {
+                Some(path) => Ok(path.to_string()),
+                None => bail!(format!("Invalid output file name {}.", out_file)),
+            }
+        },
+        None => Ok(out_file.to_string()),
+    }
+}
+
+// bin centers along each reaction coordinate dimension
+fn coord_axes(result: &WhamResult) -> Vec<Vec<f64>> {
+    let mut stride = 1;
+    (0..result.shape.len()).map(|d| {
+        let axis = (0..result.shape[d])
+            .map(|i| result.coords[i * stride][d])
+            .collect();
+        stride *= result.shape[d];
+        axis
+    }).collect()
+}
+
+fn write_csv<W: Write>(buf: &mut W, result: &WhamResult) -> std::io::Result<()> {
+    let header: Vec<String> = (0..result.shape.len()).map(|d| format!("coord{}", d+1)).collect();
+    let (free_ci, prob_ci) = result.confidence_intervals();
+    let ci_header = if free_ci.is_empty() {
+        ""
+    } else {
+        ",free_energy_ci_low,free_energy_ci_high,probability_ci_low,probability_ci_high"
+    };
+    writeln!(buf, "{},free_energy,free_energy_std,probability,probability_std{}", header.join(","),
+        ci_header)?;
+    for bin in 0..result.free_energy.len() {
+        let coords: Vec<String> = result.coords[bin].iter().map(|c| c.to_string()).collect();
+        let ci = if free_ci.is_empty() {
+            String::new()
+        } else {
+            format!(",{},{},{},{}", free_ci[bin].0, free_ci[bin].1, prob_ci[bin].0, prob_ci[bin].1)
+        };
+        writeln!(buf, "{},{},{},{},{}{}", coords.join(","), result.free_energy[bin],
+            result.free_energy_std[bin], result.probability[bin], result.probability_std[bin], ci)?;
+    }
+    Ok(())
+}
+
+// JSON number. JSON has no representation of inf and NaN, e.g. the free
+// energy of empty bins, so these are written as null.
+fn json_number(x: f64) -> String {
+    if x.is_finite() {
+        format!("{:?}", x)
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_optional_string(s: &Option<String>) -> String {
+    s.as_ref().map_or("null".to_string(), |s| json_string(s))
+}
+
+fn json_array<T: ToString>(values: &[T]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn json_numbers(values: &[f64]) -> String {
+    let values: Vec<String> = values.iter().map(|x| json_number(*x)).collect();
+    json_array(&values)
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn config_json(cfg: &Config) -> String {
+    let fields = vec![
+        ("metadata_file", json_string(&cfg.metadata_file)),
+        ("hist_min", json_numbers(&cfg.hist_min)),
+        ("hist_max", json_numbers(&cfg.hist_max)),
+        ("num_bins", json_array(&cfg.num_bins)),
+        ("dimens", cfg.dimens.to_string()),
+        ("tolerance", json_number(cfg.tolerance)),
+        ("max_iterations", cfg.max_iterations.to_string()),
+        ("temperature", json_number(cfg.temperature)),
+        ("cyclic", json_array(&cfg.cyclic)),
+        ("bootstrap", cfg.bootstrap.to_string()),
+        ("bootstrap_seed", cfg.bootstrap_seed.to_string()),
+        ("bootstrap_scheme", json_string(&cfg.bootstrap_scheme.to_string())),
+        ("bootstrap_sd", cfg.bootstrap_sd.to_string()),
+        ("bootstrap_ci", json_number(cfg.bootstrap_ci)),
+        ("bootstrap_retries", cfg.bootstrap_retries.to_string()),
+        ("start", json_number(cfg.start)),
+        ("end", json_number(cfg.end)),
+        ("uncorr", cfg.uncorr.to_string()),
+        ("convdt", json_number(cfg.convdt)),
+        ("method", json_string(&cfg.method.to_string())),
+        ("logspace", cfg.logspace.to_string()),
+        ("diis", cfg.diis.to_string()),
+        ("bias_cutoff", json_number(cfg.bias_cutoff)),
+        ("pullx_files", json_optional_string(&cfg.pullx_files)),
+        ("pullf_files", json_optional_string(&cfg.pullf_files)),
+        ("pull_params", json_optional_string(&cfg.pull_params)),
+        ("columns", json_array(&cfg.columns.iter().map(|c| json_string(c)).collect::<Vec<String>>())),
+        ("bias_column", json_optional_string(&cfg.bias_column)),
+        ("stream", cfg.stream.to_string()),
+        ("energy_min", json_number(cfg.energy_min)),
+        ("energy_max", json_number(cfg.energy_max)),
+        ("energy_bins", cfg.energy_bins.to_string()),
+    ];
+    let fields: Vec<String> = fields.iter()
+        .map(|(key, value)| format!("    {}: {}", json_string(key), value))
+        .collect();
+    format!("{{\n{}\n  }}", fields.join(",\n"))
+}
+
+// Serializes config, bias offsets, convergence and the PMF grid into JSON.
+fn results_json(cfg: &Config, result: &WhamResult) -> String {
+    let axes: Vec<String> = coord_axes(result).iter().map(|axis| json_numbers(axis)).collect();
+    let dataset = match result.dataset {
+        Some(index) => index.to_string(),
+        None => "null".to_string(),
+    };
+    // [low, high] pairs of the confidence intervals or null without --bt_ci
+    let intervals = |ci: &[(f64, f64)]| if ci.is_empty() {
+        "null".to_string()
+    } else {
+        let pairs: Vec<String> = ci.iter().map(|&(low, high)| json_numbers(&[low, high])).collect();
+        json_array(&pairs)
+    };
+    let (free_ci, prob_ci) = result.confidence_intervals();
+    format!("{{\n  \"config\": {},\n  \"dataset\": {},\n  \"convergence\": {{\n    \
+        \"converged\": {},\n    \"iterations\": {},\n    \"tolerance\": {},\n    \
+        \"max_dF\": {}\n  }},\n  \"windows\": {{\n    \
+        \"F\": {},\n    \"F_prev\": {}\n  }},\n  \"pmf\": {{\n    \"shape\": {},\n    \
+        \"coords\": {},\n    \"free_energy\": {},\n    \"free_energy_std\": {},\n    \
+        \"probability\": {},\n    \"probability_std\": {},\n    \"free_energy_ci\": {},\n    \
+        \"probability_ci\": {}\n  }}\n}}",
+        config_json(cfg), dataset, result.converged, result.iterations, json_number(cfg.tolerance),
+        json_number(result.max_dF()), json_numbers(&result.F), json_numbers(&result.F_prev),
+        json_array(&result.shape), json_array(&axes), json_numbers(&result.free_energy),
+        json_numbers(&result.free_energy_std), json_numbers(&result.probability),
+        json_numbers(&result.probability_std), intervals(free_ci), intervals(prob_ci))
+}
+
+// Serializes an f64 array in the NumPy .npy format (version 1.0). The header
+// is padded with spaces so that the data starts at a multiple of 64 bytes.
+fn npy_bytes(data: &[f64], shape: &[usize]) -> Vec<u8> {
+    let shape_str = match shape.len() {
+        1 => format!("({},)", shape[0]),
+        _ => format!("({})", shape.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(", ")),
+    };
+    let mut header = format!("{{'descr': '<f8', 'fortran_order': True, 'shape': {}, }}", shape_str);
+    // magic (6) + version (2) + header length (2) + header + newline
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(10 + header.len() + 8 * data.len());
+    bytes.extend_from_slice(b"\x93NUMPY\x01\x00");
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for x in data {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    bytes
+}
+
+fn write_npy<W: Write>(buf: &mut W, data: &[f64], shape: &[usize]) -> std::io::Result<()> {
+    buf.write_all(&npy_bytes(data, shape))
+}
+
+// CRC-32 (IEEE) checksum as required by the zip format
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// Writes the named arrays as an uncompressed zip archive of .npy files, which
+// is the .npz format of numpy.savez().
+fn write_npz<W: Write>(buf: &mut W, arrays: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+    let mut offset: u32 = 0;
+    let mut central_directory: Vec<u8> = Vec::new();
+    for (name, data) in arrays {
+        let name = format!("{}.npy", name);
+        let crc = crc32(data);
+        let size = data.len() as u32;
+
+        // local file header, stored without compression
+        let mut header: Vec<u8> = Vec::new();
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        header.extend_from_slice(&0u16.to_le_bytes()); // flags
+        header.extend_from_slice(&0u16.to_le_bytes()); // compression: stored
+        header.extend_from_slice(&0u16.to_le_bytes()); // modification time
+        header.extend_from_slice(&0x21u16.to_le_bytes()); // modification date: 1980-01-01
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes()); // compressed size
+        header.extend_from_slice(&size.to_le_bytes()); // uncompressed size
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        header.extend_from_slice(name.as_bytes());
+        buf.write_all(&header)?;
+        buf.write_all(data)?;
+
+        // central directory entry referencing the local header
+        central_directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central_directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        central_directory.extend_from_slice(&header[4..30]);
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        central_directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        central_directory.extend_from_slice(&offset.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+
+        offset += (header.len() + data.len()) as u32;
+    }
+    buf.write_all(&central_directory)?;
+
+    // end of central directory record
+    let mut end: Vec<u8> = Vec::new();
+    end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // disk number
+    end.extend_from_slice(&0u16.to_le_bytes()); // disk with central directory
+    end.extend_from_slice(&(arrays.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(arrays.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+    end.extend_from_slice(&offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    buf.write_all(&end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::histogram::{Dataset,Histogram};
+    use super::super::error_analysis::Bootstrap;
+    use super::super::{k_B, WhamResult};
+
+    fn build_dataset() -> Dataset {
+        let h = Histogram::new(6, vec![1.0; 6]);
+        Dataset::new(6, vec![3, 2], vec![1.0, 1.0], vec![0.0, 0.0], vec![3.0, 2.0],
+            vec![1.0, 1.0], vec![1.0, 1.0], 300.0*k_B, vec![h], vec![false, false])
+    }
+
+    fn build_result() -> WhamResult {
+        let P = vec![0.1, 0.2, 0.3, 0.1, 0.2, 0.1];
+        let mut result = WhamResult::new(&build_dataset(), P, vec![0.0], vec![0.0], 10, 0.1, None);
+        result.free_energy = vec![0.0; 6];
+        result
+    }
+
+    #[test]
+    fn dataset_path() {
+        assert_eq!("wham.json", super::dataset_path("wham.json", None).unwrap());
+        assert_eq!("out/wham_2.json", super::dataset_path("out/wham.json", Some(2)).unwrap());
+        assert_eq!("wham_0", super::dataset_path("wham", Some(0)).unwrap());
+        for out_file in &["..", "/", "out/.."] {
+            assert!(super::dataset_path(out_file, Some(0)).is_err());
+        }
+    }
+
+    #[test]
+    fn coord_axes() {
+        let axes = super::coord_axes(&build_result());
+        assert_eq!(vec![vec![0.5, 1.5, 2.5], vec![0.5, 1.5]], axes);
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!("1.5", super::json_number(1.5));
+        assert_eq!("null", super::json_number(f64::INFINITY));
+        assert_eq!("[1.0, null]", super::json_numbers(&[1.0, f64::NAN]));
+        assert_eq!("\"a\\\"b\\\\c\\n\"", super::json_string("a\"b\\c\n"));
+    }
+
+    #[test]
+    fn write_csv() {
+        let mut result = build_result();
+        let mut buf: Vec<u8> = Vec::new();
+        super::write_csv(&mut buf, &result).unwrap();
+        let csv = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(7, lines.len());
+        assert_eq!("coord1,coord2,free_energy,free_energy_std,probability,probability_std", lines[0]);
+        assert_eq!("1.5,0.5,0,0,0.2,0", lines[2]);
+        assert_eq!("0.5,1.5,0,0,0.1,0", lines[4]);
+
+        let mut bootstrap = Bootstrap::none(6, 1);
+        bootstrap.free_energy_ci = vec![(-0.5, 0.5); 6];
+        bootstrap.P_ci = vec![(0.1, 0.3); 6];
+        result.bootstrap = Some(bootstrap);
+        let mut buf: Vec<u8> = Vec::new();
+        super::write_csv(&mut buf, &result).unwrap();
+        let csv = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].ends_with(",free_energy_ci_low,free_energy_ci_high,probability_ci_low,probability_ci_high"));
+        assert_eq!("1.5,0.5,0,0,0.2,0,-0.5,0.5,0.1,0.3", lines[2]);
+    }
+
+    #[test]
+    fn npy_bytes() {
+        let bytes = super::npy_bytes(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[3, 2]);
+        assert_eq!(b"\x93NUMPY\x01\x00", &bytes[0..8]);
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!(0, (10 + header_len) % 64);
+        let header = String::from_utf8(bytes[10..10+header_len].to_vec()).unwrap();
+        assert!(header.starts_with("{'descr': '<f8', 'fortran_order': True, 'shape': (3, 2), }"));
+        assert!(header.ends_with('\n'));
+        assert_eq!(10 + header_len + 48, bytes.len());
+        let mut first = [0u8; 8];
+        first.copy_from_slice(&bytes[10+header_len..18+header_len]);
+        assert_eq!(1.0, f64::from_le_bytes(first));
+
+        let bytes = super::npy_bytes(&[1.0], &[1]);
+        let header = String::from_utf8(bytes[10..74].to_vec()).unwrap();
+        assert!(header.contains("'shape': (1,)"));
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(0xCBF4_3926, super::crc32(b"123456789"));
+        assert_eq!(0, super::crc32(b""));
+    }
+
+    #[test]
+    fn write_npz() {
+        let arrays = vec![("a".to_string(), vec![1, 2, 3]), ("b".to_string(), vec![4, 5])];
+        let mut buf: Vec<u8> = Vec::new();
+        super::write_npz(&mut buf, &arrays).unwrap();
+        // 2 local headers (30 + 5) with data, 2 central entries (46 + 5), end record (22)
+        assert_eq!(2*35 + 5 + 2*51 + 22, buf.len());
+        assert_eq!(&[0x50, 0x4b, 0x03, 0x04], &buf[0..4]);
+        assert_eq!(b"a.npy", &buf[30..35]);
+        let end = &buf[buf.len()-22..];
+        assert_eq!(&[0x50, 0x4b, 0x05, 0x06], &end[0..4]);
+        assert_eq!(2, u16::from_le_bytes([end[10], end[11]]));
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::prelude::*;
+use super::{Config, OutputFormat, WhamResult};
+use super::io;
+use super::output;
+use super::errors::*;
+
+// Prints the results and writes the output files requested in the config.
+// Multiple datasets (convdt) are appended to the same text files.
+pub fn report(cfg: &Config, results: &[WhamResult]) -> Result<()> {
+    for (idx, result) in results.iter().enumerate() {
+        let append = idx > 0 && results.len() > 1;
+        write_files(cfg, result, append)?;
+    }
+    Ok(())
+}
+
+// Writes the output files of a single dataset. With append, text files are
+// extended instead of replaced.
+pub fn write_files(cfg: &Config, result: &WhamResult, append: bool) -> Result<()> {
+    if !cfg.temperature_grid.is_empty() {
+        io::write_thermodynamics(&cfg.thermo_output, append, &result.thermodynamics, result.dataset)
+            .chain_err(|| "Could not write thermodynamics to output file")?;
+    }
+    if let Some(ref offsets_output) = cfg.offsets_output {
+        io::write_offsets(offsets_output, append, &result.F, &result.F_std, &result.F_prev,
+            result.iterations, result.dataset)
+            .chain_err(|| "Could not write bias offsets to output file")?;
+    }
+    if let (Some(ref bootstrap_output), Some(ref bootstrap)) = (&cfg.bootstrap_output, &result.bootstrap) {
+        io::write_bootstrap_runs(bootstrap_output, append, bootstrap, result.dataset)
+            .chain_err(|| "Could not write bootstrap runs to output file")?;
+    }
+
+    // without reaction coordinates, there is no PMF
+    if cfg.dimens == 0 {
+        return Ok(())
+    }
+
+    info!("Finished. Dumping PMF");
+    print_state(result);
+    if cfg.output_format == OutputFormat::Text {
+        io::write_results(&cfg.output, append, result)
+            .chain_err(|| "Could not write results to output file")?;
+    } else {
+        output::write_output(cfg, result)
+            .chain_err(|| "Could not write results to output file")?;
+    }
+    Ok(())
+}
+
+// Print the final WHAM state. Dumps the PMF and associated vectors
+pub fn print_state(result: &WhamResult) {
+    let out = std::io::stdout();
+    let mut lock = out.lock();
+    writeln!(lock, "# PMF").unwrap();
+    writeln!(lock, "#bin\t\tFree Energy\t\t+/-\t\tP(x)\t\t+/-").unwrap();
+    for bin in 0..result.probability.len() {
+        writeln!(lock, "{:9.5}\t{:9.5}\t{:9.5}\t{:9.5}\t{:9.5}", bin, result.free_energy[bin],
+            result.free_energy_std[bin], result.probability[bin], result.probability_std[bin]).unwrap();
+    }
+    writeln!(lock, "# Bias offsets").unwrap();
+    writeln!(lock, "#Window\t\tF\t\tdF").unwrap();
+    for window in 0..result.F.len() {
+        writeln!(lock, "{}\t{:9.5}\t{:8.8}",
+            window, result.F[window], (result.F[window]-result.F_prev[window]).abs()).unwrap();
+    }
+}
@@ -20,7 +20,45 @@ pub fn sd(x: &[f64]) -> f64 {
     (1.0/(n-1.0) * sum).sqrt()
 }
 
+// q-th percentile (0-100) of x with linear interpolation between the closest
+// ranks, the default of numpy.percentile
+pub fn percentile(x: &[f64], q: f64) -> f64 {
+    let mut sorted = x.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let pos = q / 100.0 * (sorted.len() - 1) as f64;
+    let lower = pos.floor() as usize;
+    let upper = pos.ceil() as usize;
+    // avoids inf - inf for empty bins
+    if lower == upper || sorted[lower] == sorted[upper] {
+        return sorted[lower];
+    }
+    sorted[lower] + (pos - lower as f64) * (sorted[upper] - sorted[lower])
+}
+
+// numerically stable evaluation of ln(sum(exp(x))). The sum is accumulated
+// relative to the running maximum, so large or small exponents neither
+// overflow nor underflow.
+pub fn log_sum_exp<I: IntoIterator<Item=f64>>(x: I) -> f64 {
+    let mut max = f64::NEG_INFINITY;
+    let mut sum = 0.0;
+    // for_each instead of a for loop lets iterators with a specialized fold
+    // (e.g. the bias cache entries) run their own loop
+    x.into_iter().for_each(|xi| {
+        if xi == f64::NEG_INFINITY {
+            return;
+        }
+        if xi <= max {
+            sum += (xi - max).exp();
+        } else {
+            sum = sum * (max - xi).exp() + 1.0;
+            max = xi;
+        }
+    });
+    max + sum.ln()
+}
+
 #[cfg(test)]
+#[allow(clippy::approx_constant)]
 mod tests {
     use assert_approx_eq::assert_approx_eq;
 
@@ -48,6 +86,20 @@ mod tests {
         assert_approx_eq!(m, expected, 0.000_001);
     }
 
+    #[test]
+    fn log_sum_exp() {
+        let x = vec![1000.0, 1000.0];
+        assert_approx_eq!(1000.0 + 2.0_f64.ln(), super::log_sum_exp(x));
+        let x = vec![f64::NEG_INFINITY, 0.0];
+        assert_approx_eq!(0.0, super::log_sum_exp(x));
+        let x = vec![-1000.0, 0.0, -1000.0];
+        assert_approx_eq!(0.0, super::log_sum_exp(x));
+        let x = vec![1.0, 2.0, 3.0];
+        assert_approx_eq!((1_f64.exp() + 2_f64.exp() + 3_f64.exp()).ln(), super::log_sum_exp(x));
+        let x = vec![f64::NEG_INFINITY];
+        assert_eq!(f64::NEG_INFINITY, super::log_sum_exp(x));
+    }
+
     #[test]
     fn sd() {
         let ds = dataset();
@@ -56,4 +108,16 @@ mod tests {
         let m = super::sd(&ds);
         assert_approx_eq!(m, expected, 0.000_001);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn percentile() {
+        let x = [3.0, 1.0, 4.0, 2.0, 5.0];
+        assert_approx_eq!(super::percentile(&x, 0.0), 1.0);
+        assert_approx_eq!(super::percentile(&x, 50.0), 3.0);
+        assert_approx_eq!(super::percentile(&x, 100.0), 5.0);
+        assert_approx_eq!(super::percentile(&x, 2.5), 1.1);
+        assert_approx_eq!(super::percentile(&x, 97.5), 4.9);
+        let empty = [f64::INFINITY, f64::INFINITY];
+        assert_eq!(super::percentile(&empty, 50.0), f64::INFINITY);
+    }
+}
@@ -0,0 +1,141 @@
+use super::histogram::Dataset;
+use super::statistics::log_sum_exp;
+use super::errors::*;
+use k_B;
+
+// Thermodynamic properties of a multi-temperature dataset at one temperature
+// of the reweighting grid.
+#[derive(Debug,Clone)]
+pub struct Thermodynamics {
+    // temperature in Kelvin
+    pub temperature: f64,
+
+    // free energy relative to the target temperature T_0 of the dataset:
+    // F(T) = -kT ln[Z(T)/Z(T_0)] in kJ/mol
+    pub free_energy: f64,
+
+    // mean potential energy <E> in kJ/mol
+    pub energy: f64,
+
+    // heat capacity Cv = (<E^2> - <E>^2)/(k_B T^2) in kJ/mol/K
+    pub heat_capacity: f64,
+}
+
+// Returns num_temperatures equally spaced temperatures from t_min to t_max.
+pub fn temperature_grid(t_min: f64, t_max: f64, num_temperatures: usize) -> Vec<f64> {
+    if num_temperatures < 2 {
+        return vec![t_min]
+    }
+    let dt = (t_max - t_min) / (num_temperatures - 1) as f64;
+    (0..num_temperatures).map(|i| t_min + dt * i as f64).collect()
+}
+
+// Reweights the probabilities P of a multi-temperature dataset from its target
+// temperature T_0 to each given temperature T. P covers the full histogram
+// grid including the potential energy. The probability of a bin at T is
+// P_T(x,E) = P(x,E) exp(-(1/kT - 1/kT_0)E) / [Z(T)/Z(T_0)]
+// For details, see "Chodera, J.D. et al. (2007). Use of the weighted histogram
+// analysis method for the analysis of simulated and parallel tempering
+// simulations, JCTC"
+pub fn reweight_temperatures(dataset: &Dataset, P: &[f64], temperatures: &[f64])
+        -> Result<Vec<Thermodynamics>> {
+    if !dataset.energy_dimen {
+        bail!("Temperature reweighting requires the potential energy of the samples.");
+    }
+    let energy_dimen = dataset.dimens_lengths.len() - 1;
+    let energies: Vec<f64> = (0..dataset.num_bins)
+        .map(|bin| dataset.get_coords_for_bin(bin)[energy_dimen])
+        .collect();
+    let ln_P: Vec<f64> = P.iter().map(|p| p.ln()).collect();
+
+    Ok(temperatures.iter().map(|T| {
+        let kT = k_B * T;
+        let dbeta = 1.0/kT - 1.0/dataset.kT;
+        let ln_w: Vec<f64> = ln_P.iter().zip(energies.iter())
+            .map(|(lp, E)| lp - dbeta * E)
+            .collect();
+        let ln_Z = log_sum_exp(ln_w.iter().cloned());
+        let p_T: Vec<f64> = ln_w.iter().map(|lw| (lw - ln_Z).exp()).collect();
+
+        let energy: f64 = p_T.iter().zip(energies.iter()).map(|(p, E)| p * E).sum();
+        let variance: f64 = p_T.iter().zip(energies.iter())
+            .map(|(p, E)| p * (E - energy) * (E - energy))
+            .sum();
+
+        Thermodynamics {
+            temperature: *T,
+            free_energy: -kT * ln_Z,
+            energy,
+            heat_capacity: variance / (k_B * T * T),
+        }
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::histogram::{Dataset,Histogram};
+    use super::super::{Config, k_B, perform_estimation_full};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn temperature_grid() {
+        let grid = super::temperature_grid(300.0, 400.0, 5);
+        assert_eq!(vec![300.0, 325.0, 350.0, 375.0, 400.0], grid);
+        assert_eq!(vec![300.0], super::temperature_grid(300.0, 400.0, 1));
+    }
+
+    // two-level system with energies 0.5 and 1.5 kJ/mol that are equally
+    // populated at 300 K
+    #[test]
+    fn reweight_two_level() {
+        let kT0 = 300.0 * k_B;
+        let h = Histogram::new(2, vec![1.0, 1.0]);
+        let ds = Dataset::new(2, vec![2], vec![1.0], vec![0.0], vec![2.0],
+            vec![], vec![], kT0, vec![h], vec![false]);
+        let ds = Dataset::new_with_temperatures(ds, vec![kT0]);
+        let thermo = super::reweight_temperatures(&ds, &[0.5, 0.5], &[300.0, 200.0]).unwrap();
+
+        assert_approx_eq!(0.0, thermo[0].free_energy);
+        assert_approx_eq!(1.0, thermo[0].energy);
+        assert_approx_eq!(0.25 / (k_B * 300.0 * 300.0), thermo[0].heat_capacity);
+
+        // population ratio r of the upper and lower level at 200 K
+        let kT = 200.0 * k_B;
+        let dbeta = 1.0/kT - 1.0/kT0;
+        let r = (-dbeta).exp();
+        let Z = 0.5 * (-dbeta * 0.5).exp() + 0.5 * (-dbeta * 1.5).exp();
+        assert_approx_eq!(-kT * Z.ln(), thermo[1].free_energy);
+        assert_approx_eq!(0.5 + r / (1.0 + r), thermo[1].energy);
+        assert_approx_eq!(r / (1.0 + r).powi(2) / (k_B * 200.0 * 200.0), thermo[1].heat_capacity);
+    }
+
+    #[test]
+    fn reweight_without_energy() {
+        let h = Histogram::new(2, vec![1.0, 1.0]);
+        let ds = Dataset::new(2, vec![2], vec![1.0], vec![0.0], vec![2.0],
+            vec![1.0], vec![1.0], 300.0 * k_B, vec![h], vec![false]);
+        assert!(super::reweight_temperatures(&ds, &[0.5, 0.5], &[300.0]).is_err());
+    }
+
+    // Parallel tempering of a 20-dimensional harmonic oscillator at 300-400 K.
+    // The potential energy is gamma distributed with <E> = 10 k_B T and the
+    // heat capacity is Cv = 10 k_B.
+    #[test]
+    fn reweight_parallel_tempering() {
+        let cfg = Config {
+            metadata_file: "tests/data/metadata_parallel_tempering.dat".to_string(),
+            tolerance: 0.000_01,
+            energy_max: 100.0,
+            energy_bins: 200,
+            ..Default::default()
+        };
+        let ds = &super::super::io::read_data(&cfg).unwrap()[0];
+        let (P, _, _, _) = perform_estimation_full(&cfg, ds, None).unwrap();
+        let temperatures = super::temperature_grid(300.0, 400.0, 5);
+        let thermo = super::reweight_temperatures(ds, &P, &temperatures).unwrap();
+        for t in thermo {
+            assert_approx_eq!(10.0 * k_B * t.temperature, t.energy, 0.5);
+            assert_approx_eq!(10.0 * k_B, t.heat_capacity, 0.01);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+// Readers of time series files. Text files with whitespace separated columns
+// (e.g. PLUMED COLVAR or xvg files) and NumPy .npy arrays of shape
+// (frames, columns) are supported. Both may be compressed with gzip or xz,
+// which is detected by the magic bytes of the file, so that compressed
+// archives do not have to be extracted before the analysis.
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use super::errors::*;
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+// Opens a file for reading and decompresses gzip and xz files on the fly.
+pub fn open_file(path: &str) -> Result<Box<dyn BufRead>> {
+    let f = File::open(path)
+        .chain_err(|| format!("Failed to open sample data file {}.", path))?;
+    let mut buf = BufReader::new(f);
+    let magic = peek(&mut buf, XZ_MAGIC.len())
+        .chain_err(|| format!("Failed to read {}.", path))?;
+    Ok(if magic.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(MultiGzDecoder::new(buf)))
+    } else if magic.starts_with(XZ_MAGIC) {
+        Box::new(BufReader::new(XzDecoder::new_multi_decoder(buf)))
+    } else {
+        Box::new(buf)
+    })
+}
+
+// Returns true if the (decompressed) data is a NumPy array.
+pub fn is_npy(buf: &mut dyn BufRead) -> Result<bool> {
+    Ok(peek(buf, NPY_MAGIC.len()).chain_err(|| "Failed to read file")? == NPY_MAGIC)
+}
+
+// Returns the first bytes of a reader without consuming them. Fewer bytes are
+// returned for shorter files.
+fn peek(buf: &mut dyn BufRead, len: usize) -> ::std::io::Result<Vec<u8>> {
+    let data = buf.fill_buf()?;
+    Ok(data[..len.min(data.len())].to_vec())
+}
+
+// Reads the frames of a time series file one by one, so that the file does
+// not have to fit into memory. Only the selected columns of each frame are
+// parsed.
+pub struct TimeseriesReader {
+    path: String,
+    columns: Vec<usize>,
+    frames: Frames,
+}
+
+enum Frames {
+    Text {
+        buf: Box<dyn BufRead>,
+        line: String,
+        line_num: usize,
+        num_columns: usize,
+    },
+    // C-ordered array, read row by row
+    Rows {
+        buf: Box<dyn BufRead>,
+        dtype: NpyType,
+        row: Vec<u8>,
+        num_rows: usize,
+        row_num: usize,
+    },
+    // Fortran-ordered array. Its values are stored column by column, so the
+    // selected columns are read at once.
+    Loaded {
+        columns: Vec<Vec<f64>>,
+        row_num: usize,
+    },
+}
+
+impl TimeseriesReader {
+    // Opens a time series and selects its columns by index.
+    pub fn open(path: &str, columns: &[usize]) -> Result<TimeseriesReader> {
+        let mut buf = open_file(path)?;
+        let num_columns = columns.iter().max().map_or(0, |c| c+1);
+        let frames = if is_npy(&mut buf)? {
+            let header = read_npy_header(&mut buf)
+                .chain_err(|| format!("Failed to read NumPy header of {}.", path))?;
+            if header.num_columns < num_columns {
+                bail!(format!("Wrong number of columns in window file {}. Expected at least {}, got {}.",
+                    path, num_columns, header.num_columns));
+            }
+            if header.fortran_order {
+                let columns = read_npy_columns(&mut buf, &header, columns)
+                    .chain_err(|| format!("Failed to read NumPy array of {}.", path))?;
+                Frames::Loaded { columns, row_num: 0 }
+            } else {
+                let row = vec![0; header.num_columns * header.dtype.size];
+                Frames::Rows { buf, dtype: header.dtype, row, num_rows: header.num_rows, row_num: 0 }
+            }
+        } else {
+            Frames::Text { buf, line: String::new(), line_num: 0, num_columns }
+        };
+        Ok(TimeseriesReader { path: path.to_string(), columns: columns.to_vec(), frames })
+    }
+
+    // Reads the selected columns of the next frame into frame. Returns false
+    // at the end of the time series.
+    pub fn next_frame(&mut self, frame: &mut [f64]) -> Result<bool> {
+        let path = &self.path;
+        let columns = &self.columns;
+        match self.frames {
+            Frames::Text { ref mut buf, ref mut line, ref mut line_num, num_columns } => {
+                loop {
+                    line.clear();
+                    if buf.read_line(line).chain_err(|| "Failed to read line")? == 0 {
+                        return Ok(false)
+                    }
+                    *line_num += 1;
+
+                    // skip comments and empty lines
+                    if !(line.starts_with('#') || line.starts_with('@') || line.is_empty()) {
+                        break
+                    }
+                }
+                let split: Vec<&str> = line.split_whitespace().collect();
+                if split.len() < num_columns {
+                    bail!(format!("Wrong number of columns in line {} of window file {}. Empty Line?.", line_num, path));
+                }
+                for (value, column) in frame.iter_mut().zip(columns) {
+                    *value = split[*column].parse::<f64>()
+                        .chain_err(|| format!("Failed to parse line {} of window file {}.", line_num, path))?;
+                }
+            },
+            Frames::Rows { ref mut buf, dtype, ref mut row, num_rows, ref mut row_num } => {
+                if *row_num == num_rows {
+                    return Ok(false)
+                }
+                buf.read_exact(row)
+                    .chain_err(|| format!("Failed to read row {} of window file {}.", row_num, path))?;
+                *row_num += 1;
+                for (value, column) in frame.iter_mut().zip(columns) {
+                    *value = dtype.parse(&row[column * dtype.size..(column+1) * dtype.size]);
+                }
+            },
+            Frames::Loaded { columns: ref loaded, ref mut row_num } => {
+                if loaded.first().is_none_or(|c| *row_num == c.len()) {
+                    return Ok(false)
+                }
+                for (value, column) in frame.iter_mut().zip(loaded) {
+                    *value = column[*row_num];
+                }
+                *row_num += 1;
+            },
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum NpyKind {
+    Float,
+    Int,
+    Uint,
+}
+
+// Data type of the values of a NumPy array, e.g. '<f8' for little-endian
+// doubles.
+#[derive(Debug,Clone,Copy,PartialEq)]
+struct NpyType {
+    kind: NpyKind,
+    size: usize,
+    big_endian: bool,
+}
+
+impl NpyType {
+    fn from_descr(descr: &str) -> Result<NpyType> {
+        let (endian, code) = descr.split_at(1.min(descr.len()));
+        let big_endian = match endian {
+            "<" | "|" => false,
+            ">" => true,
+            "=" => cfg!(target_endian = "big"),
+            _ => bail!(format!("Unsupported NumPy data type {}.", descr)),
+        };
+        let dtype = match code {
+            "f4" => NpyType { kind: NpyKind::Float, size: 4, big_endian },
+            "f8" => NpyType { kind: NpyKind::Float, size: 8, big_endian },
+            "i1" => NpyType { kind: NpyKind::Int, size: 1, big_endian },
+            "i2" => NpyType { kind: NpyKind::Int, size: 2, big_endian },
+            "i4" => NpyType { kind: NpyKind::Int, size: 4, big_endian },
+            "i8" => NpyType { kind: NpyKind::Int, size: 8, big_endian },
+            "u1" => NpyType { kind: NpyKind::Uint, size: 1, big_endian },
+            "u2" => NpyType { kind: NpyKind::Uint, size: 2, big_endian },
+            "u4" => NpyType { kind: NpyKind::Uint, size: 4, big_endian },
+            "u8" => NpyType { kind: NpyKind::Uint, size: 8, big_endian },
+            _ => bail!(format!("Unsupported NumPy data type {}. Expected a float or integer type.", descr)),
+        };
+        Ok(dtype)
+    }
+
+    // value of a single array element
+    fn parse(&self, bytes: &[u8]) -> f64 {
+        let mut raw = [0; 8];
+        if self.big_endian {
+            raw[8-self.size..].copy_from_slice(bytes);
+            self.convert(u64::from_be_bytes(raw))
+        } else {
+            raw[..self.size].copy_from_slice(bytes);
+            self.convert(u64::from_le_bytes(raw))
+        }
+    }
+
+    // converts the raw bits of an element
+    fn convert(&self, value: u64) -> f64 {
+        let bits = self.size as u32 * 8;
+        match self.kind {
+            NpyKind::Float if self.size == 4 => f64::from(f32::from_bits(value as u32)),
+            NpyKind::Float => f64::from_bits(value),
+            // sign extension of smaller integers
+            NpyKind::Int => ((value << (64 - bits)) as i64 >> (64 - bits)) as f64,
+            NpyKind::Uint => value as f64,
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+struct NpyHeader {
+    dtype: NpyType,
+    fortran_order: bool,
+    num_rows: usize,
+    num_columns: usize,
+}
+
+// Reads the header of a NumPy array after the magic string, e.g.
+// "{'descr': '<f8', 'fortran_order': False, 'shape': (1000, 3), }". One
+// dimensional arrays are a single column.
+fn read_npy_header(buf: &mut dyn BufRead) -> Result<NpyHeader> {
+    let mut preamble = [0; 8];
+    buf.read_exact(&mut preamble).chain_err(|| "Failed to read header")?;
+    let header_len = match preamble[6] {
+        1 => {
+            let mut len = [0; 2];
+            buf.read_exact(&mut len).chain_err(|| "Failed to read header")?;
+            u16::from_le_bytes(len) as usize
+        },
+        2 | 3 => {
+            let mut len = [0; 4];
+            buf.read_exact(&mut len).chain_err(|| "Failed to read header")?;
+            u32::from_le_bytes(len) as usize
+        },
+        version => bail!(format!("Unsupported NumPy format version {}.", version)),
+    };
+    let mut header = vec![0; header_len];
+    buf.read_exact(&mut header).chain_err(|| "Failed to read header")?;
+    parse_npy_header(&String::from_utf8_lossy(&header))
+}
+
+fn parse_npy_header(header: &str) -> Result<NpyHeader> {
+    let descr = npy_header_value(header, "descr")
+        .chain_err(|| "No descr in header")?;
+    let dtype = NpyType::from_descr(descr.trim_matches(|c| c == '\'' || c == '"'))?;
+    let fortran_order = npy_header_value(header, "fortran_order")
+        .chain_err(|| "No fortran_order in header")? == "True";
+    let shape = npy_header_value(header, "shape")
+        .chain_err(|| "No shape in header")?;
+    let shape = shape.trim_matches(|c| c == '(' || c == ')').split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim_end_matches('L').parse::<usize>()
+            .chain_err(|| format!("Failed to parse shape {}", shape)))
+        .collect::<Result<Vec<usize>>>()?;
+    let (num_rows, num_columns) = match shape.len() {
+        1 => (shape[0], 1),
+        2 => (shape[0], shape[1]),
+        _ => bail!(format!("Expected an array of shape (frames, columns), got {} dimensions.", shape.len())),
+    };
+    Ok(NpyHeader { dtype, fortran_order, num_rows, num_columns })
+}
+
+// Value of a key in the header dictionary. Tuples are returned with their
+// parentheses.
+fn npy_header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))
+        .or_else(|| header.find(&format!("\"{}\"", key)))?;
+    let value = header[start + key.len() + 2..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if value.starts_with('(') {
+        value.find(')')? + 1
+    } else {
+        value.find([',', '}']).unwrap_or(value.len())
+    };
+    Some(value[..end].trim())
+}
+
+// Reads the selected columns of a Fortran-ordered array.
+fn read_npy_columns(buf: &mut dyn BufRead, header: &NpyHeader, selection: &[usize])
+        -> Result<Vec<Vec<f64>>> {
+    let mut columns = vec![Vec::new(); selection.len()];
+    let mut column = vec![0; header.num_rows * header.dtype.size];
+    for c in 0..header.num_columns {
+        buf.read_exact(&mut column).chain_err(|| format!("Failed to read column {}", c))?;
+        for (values, selected) in columns.iter_mut().zip(selection) {
+            if *selected == c {
+                *values = column.chunks(header.dtype.size).map(|v| header.dtype.parse(v)).collect();
+            }
+        }
+    }
+    Ok(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+
+    // reads all frames of the selected columns
+    fn read(path: &str, columns: &[usize]) -> Vec<Vec<f64>> {
+        let mut reader = TimeseriesReader::open(path, columns).unwrap();
+        let mut frames = Vec::new();
+        let mut frame = vec![0.0; columns.len()];
+        while reader.next_frame(&mut frame).unwrap() {
+            frames.push(frame.clone());
+        }
+        frames
+    }
+
+    #[test]
+    fn compressed() {
+        let text = read("tests/data/timeseries/COLVAR", &[0, 2]);
+        assert_eq!(100, text.len());
+        assert_eq!(text, read("tests/data/timeseries/COLVAR.gz", &[0, 2]));
+        assert_eq!(text, read("tests/data/timeseries/COLVAR.xz", &[0, 2]));
+    }
+
+    #[test]
+    fn npy() {
+        let text = read("tests/data/timeseries/COLVAR", &[0, 2]);
+        assert_eq!(text, read("tests/data/timeseries/COLVAR.npy", &[0, 2]));
+        assert_eq!(text, read("tests/data/timeseries/COLVAR.npy.gz", &[0, 2]));
+        assert_eq!(text, read("tests/data/timeseries/COLVAR_fortran.npy", &[0, 2]));
+        assert!(TimeseriesReader::open("tests/data/timeseries/COLVAR.npy", &[4]).is_err());
+    }
+
+    #[test]
+    fn parse_npy_header() {
+        let header = super::parse_npy_header(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (1000, 3), }").unwrap();
+        assert_eq!(NpyHeader { dtype: NpyType { kind: NpyKind::Float, size: 4, big_endian: false },
+            fortran_order: false, num_rows: 1000, num_columns: 3 }, header);
+        let header = super::parse_npy_header(
+            "{'descr': '>i8', 'fortran_order': True, 'shape': (10,), }").unwrap();
+        assert_eq!(NpyHeader { dtype: NpyType { kind: NpyKind::Int, size: 8, big_endian: true },
+            fortran_order: true, num_rows: 10, num_columns: 1 }, header);
+        assert!(super::parse_npy_header("{'descr': '<c16', 'fortran_order': False, 'shape': (10,), }").is_err());
+        assert!(super::parse_npy_header("{'descr': '<f8', 'fortran_order': False, 'shape': (2, 3, 4), }").is_err());
+    }
+
+    #[test]
+    fn npy_type() {
+        let f4 = NpyType::from_descr(">f4").unwrap();
+        assert_eq!(1.5, f4.parse(&1.5_f32.to_be_bytes()));
+        let i2 = NpyType::from_descr("<i2").unwrap();
+        assert_eq!(-3.0, i2.parse(&(-3_i16).to_le_bytes()));
+        let u1 = NpyType::from_descr("|u1").unwrap();
+        assert_eq!(255.0, u1.parse(&[255]));
+        let f8 = NpyType::from_descr("<f8").unwrap();
+        assert_eq!(f64::consts::PI, f8.parse(&f64::consts::PI.to_le_bytes()));
+    }
+}
@@ -0,0 +1,45 @@
+extern crate wham;
+
+#[cfg(test)]
+mod builder {
+
+    use std::fs;
+    use wham::builder::{DatasetBuilder, HistogramGrid, Solver, Window};
+
+    // windows of the 1d_cyclic example read without the CLI
+    fn example_windows() -> Vec<Window> {
+        fs::read_to_string("example/1d_cyclic/metadata.dat").unwrap().lines()
+            .map(|line| {
+                let split: Vec<&str> = line.split_whitespace().collect();
+                let samples = fs::read_to_string(format!("example/1d_cyclic/{}", split[0])).unwrap()
+                    .lines()
+                    .map(|l| vec![l.split_whitespace().nth(1).unwrap().parse().unwrap()])
+                    .collect();
+                Window::new(vec![split[1].parse().unwrap()], vec![split[2].parse().unwrap()])
+                    .samples(samples)
+            }).collect()
+    }
+
+    #[test]
+    fn wham_1d_cyclic() {
+        let pi = std::f64::consts::PI;
+        let grid = HistogramGrid::new(vec![-pi], vec![pi], vec![100]).cyclic(vec![true]);
+        let dataset = DatasetBuilder::new(grid, 300.0)
+            .windows(example_windows())
+            .build()
+            .unwrap();
+        let result = Solver::new().solve(&dataset).unwrap();
+
+        // same PMF as the CLI
+        let expected: Vec<Vec<f64>> = fs::read_to_string("example/1d_cyclic/wham.out").unwrap().lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.split_whitespace().map(|v| v.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(expected.len(), result.free_energy.len());
+        for (bin, row) in expected.iter().enumerate() {
+            assert!((result.coords[bin][0] - row[0]).abs() < 1e-6);
+            assert!((result.free_energy[bin] - row[1]).abs() < 1e-6);
+            assert!((result.probability[bin] - row[3]).abs() < 1e-6);
+        }
+    }
+}
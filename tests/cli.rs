@@ -17,9 +17,9 @@ mod integration {
     #[test]
     fn unparseable_bias_pos() {
         let output = get_command()
-            .args(&["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
-            .args(&["-f", "tests/data/metadata_unparseable1.dat"])
-            .args(&["-o", "/dev/null"])
+            .args(["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
+            .args(["-f", "tests/data/metadata_unparseable1.dat"])
+            .args(["-o", "/dev/null"])
             .output()
             .expect("failed to execute process");
         let output = String::from_utf8_lossy(&output.stderr);
@@ -32,9 +32,9 @@ mod integration {
     #[test]
     fn unparseable_bias_fc() {
         let output = get_command()
-            .args(&["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
-            .args(&["-f", "tests/data/metadata_unparseable2.dat"])
-            .args(&["-o", "/dev/null"])
+            .args(["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
+            .args(["-f", "tests/data/metadata_unparseable2.dat"])
+            .args(["-o", "/dev/null"])
             .output()
             .expect("failed to execute process");
         let output = String::from_utf8_lossy(&output.stderr);
@@ -47,10 +47,10 @@ mod integration {
     #[test]
     fn no_convergence() {
         let output = get_command()
-            .args(&["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
-            .args(&["--iterations", "10"])
-            .args(&["-f", "example/1d_cyclic/metadata.dat"])
-            .args(&["-o", "/dev/null"])
+            .args(["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
+            .args(["--iterations", "10"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
             .output()
             .expect("failed to execute process");
         let output = String::from_utf8_lossy(&output.stderr);
@@ -63,9 +63,9 @@ mod integration {
     #[test]
     fn unparseable_timeseries() {
         let output = get_command()
-            .args(&["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
-            .args(&["-f", "tests/data/metadata_unparseable3.dat"])
-            .args(&["-o", "/dev/null"])
+            .args(["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
+            .args(["-f", "tests/data/metadata_unparseable3.dat"])
+            .args(["-o", "/dev/null"])
             .output()
             .expect("failed to execute process");
         let output = String::from_utf8_lossy(&output.stderr);
@@ -78,9 +78,9 @@ mod integration {
     #[test]
     fn unparseable_timeseries_empty() {
         let output = get_command()
-            .args(&["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
-            .args(&["-f", "tests/data/metadata_unparseable4.dat"])
-            .args(&["-o", "/dev/null"])
+            .args(["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
+            .args(["-f", "tests/data/metadata_unparseable4.dat"])
+            .args(["-o", "/dev/null"])
             .output()
             .expect("failed to execute process");
         let output = String::from_utf8_lossy(&output.stderr);
@@ -93,28 +93,29 @@ mod integration {
     #[test]
     fn skip_rows() {
         let output = get_command()
-            .args(&["--bins", "100", "--max", "3.14", "--min", "-3.14", "-T", "300", "--cyclic"])
-            .args(&["-f", "example/1d_cyclic/metadata.dat"])
-            .args(&["-o", "/tmp/wham_test_1d_cyclic.out"])
-            .args(&["--start", "50", "--end", "60"])
-            .args(&["-v"])
+            .args(["--bins", "100", "--max", "3.14", "--min", "-3.14", "-T", "300", "--cyclic"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/tmp/wham_test_1d_cyclic.out"])
+            .args(["--start", "50", "--end", "60"])
+            .args(["-v"])
             .output()
             .expect("failed to execute process");
 
-        let output = String::from_utf8_lossy(&output.stdout);
+        let output = String::from_utf8_lossy(&output.stderr);
         println!("{}", output);
+        // samples outside of -3.14..3.14 are folded into the histogram
         assert!(output.to_string().contains(
-            "25 windows, 12489 datapoints"
+            "25 windows, 12500 datapoints"
         ));
     }
 
     #[test]
     fn convdt_needs_start_end() {
         let output = get_command()
-            .args(&["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
-            .args(&["-f", "tests/data/metadata_unparseable1.dat"])
-            .args(&["-o", "/dev/null"])
-            .args(&["--convdt", "100"])
+            .args(["--bins", "100", "--min", "-3.0", "--max", "3.0", "-T", "300"])
+            .args(["-f", "tests/data/metadata_unparseable1.dat"])
+            .args(["-o", "/dev/null"])
+            .args(["--convdt", "100"])
             .output()
             .expect("failed to execute process");
         let output = String::from_utf8_lossy(&output.stderr);
@@ -124,4 +125,404 @@ mod integration {
         ));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn mbar() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--method", "mbar", "--tolerance", "0.001", "--start", "0", "--end", "10"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("MBAR converged."));
+    }
+
+    #[test]
+    fn unknown_method() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300"])
+            .args(["--method", "foo"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("\"foo\" isn't a valid value"));
+    }
+
+    #[test]
+    fn diis() {
+        let output = get_command()
+            .args(["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--diis", "5", "-v"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("WHAM converged after"));
+    }
+
+    #[test]
+    fn bias_options() {
+        let output = get_command()
+            .args(["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["-f", "tests/data/metadata_bias_options.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("WHAM converged."));
+    }
+
+    #[test]
+    fn multi_temperature() {
+        let output = get_command()
+            .args(["--bins", "30", "--max", "1.5", "--min", "-1.5", "-T", "300"])
+            .args(["--ebins", "24", "--emin", "0", "--emax", "12"])
+            .args(["-f", "tests/data/metadata_multi_temperature.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("WHAM converged."));
+    }
+
+    #[test]
+    fn multi_temperature_without_energy() {
+        let output = get_command()
+            .args(["--bins", "30", "--max", "1.5", "--min", "-1.5", "-T", "300"])
+            .args(["-f", "tests/data/metadata_multi_temperature.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("Windows at different temperatures require the potential energy"));
+    }
+
+    #[test]
+    fn parallel_tempering() {
+        let output = get_command()
+            .args(["-T", "300", "--ebins", "200", "--emin", "0", "--emax", "100"])
+            .args(["--tgrid", "300,400,5", "--thermo", "/dev/null"])
+            .args(["-f", "tests/data/metadata_parallel_tempering.dat"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("Reweighting to 5 temperatures."));
+    }
+
+    #[test]
+    fn tgrid_without_energy() {
+        let output = get_command()
+            .args(["-T", "300", "--tgrid", "300,400,5"])
+            .args(["-f", "tests/data/metadata_parallel_tempering.dat"])
+            .output()
+            .expect("failed to execute process");
+        assert!(!output.status.success());
+    }
+
+    #[test]
+    fn cyclic_per_dimension() {
+        let output = get_command()
+            .args(["--bins", "10,10", "--max", "pi,pi", "--min", "-pi,-pi", "-T", "300", "--cyclic", "1,0"])
+            .args(["--end", "10", "--tolerance", "0.001"])
+            .args(["-f", "example/2d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("cyclic=[true, false]"));
+        assert!(output.to_string().contains("WHAM converged."));
+    }
+
+    #[test]
+    fn cyclic_wrong_dimensions() {
+        let output = get_command()
+            .args(["--bins", "20,20", "--max", "pi,pi", "--min", "-pi,-pi", "-T", "300", "--cyclic", "1"])
+            .args(["-f", "example/2d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("Expected 2 cyclic flags but got 1."));
+    }
+
+    #[test]
+    fn out_of_range_discarded() {
+        let output = get_command()
+            .args(["--bins", "100", "--max", "3", "--min", "-3", "-T", "300"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("Data points outside of the histogram boundaries (discarded):"));
+        assert!(output.to_string().contains("12\t\t3348\t\t0.6696\t\texample/1d_cyclic/COLVAR+2.75.xvg"));
+    }
+
+    #[test]
+    fn out_of_range_folded() {
+        let output = get_command()
+            .args(["--bins", "100", "--max", "6.283185307179586", "--min", "0", "-T", "300", "--cyclic"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("25 windows, 125000 datapoints"));
+        assert!(!output.to_string().contains("discarded"));
+    }
+
+    #[test]
+    fn output_formats() {
+        for format in &["json", "csv", "npy", "npz"] {
+            let out_file = format!("/tmp/wham_test_format.{}", format);
+            let output = get_command()
+                .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+                .args(["--end", "10", "--tolerance", "0.001", "--format", format])
+                .args(["-f", "example/1d_cyclic/metadata.dat"])
+                .args(["-o", &out_file])
+                .output()
+                .expect("failed to execute process");
+            assert!(output.status.success());
+            let content = std::fs::read(&out_file).unwrap();
+            match *format {
+                "json" => assert!(String::from_utf8_lossy(&content).contains("\"shape\": [20]")),
+                "csv" => assert_eq!(21, String::from_utf8_lossy(&content).lines().count()),
+                "npy" => assert!(content.starts_with(b"\x93NUMPY")),
+                _ => assert!(content.starts_with(b"PK\x03\x04")),
+            }
+        }
+    }
+
+    #[test]
+    fn offsets_output() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--end", "10", "--tolerance", "0.001", "--bt", "2", "--seed", "1234"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null", "--offsets", "/tmp/wham_test_offsets.out"])
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        let offsets = std::fs::read_to_string("/tmp/wham_test_offsets.out").unwrap();
+        let lines: Vec<&str> = offsets.lines().collect();
+        assert!(lines[0].starts_with("#Iterations "));
+        assert!(lines[1].starts_with("#Max dF "));
+        assert_eq!("#Window    F    +/-    dF", lines[2]);
+        assert_eq!(3 + 25, lines.len());
+        assert!(lines[3].starts_with("0    0.000000    0.000000"));
+    }
+
+    #[test]
+    fn initial_offsets() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--end", "10", "--tolerance", "0.001", "-v"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null", "--offsets", "/tmp/wham_test_finit.out"])
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--end", "10", "--tolerance", "0.001", "-v"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null", "--finit", "/tmp/wham_test_finit.out"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("WHAM converged after 10 iterations."));
+    }
+
+    #[test]
+    fn initial_offsets_wrong_windows() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null", "--finit", "tests/data/offsets.dat"])
+            .output()
+            .expect("failed to execute process");
+        let output = String::from_utf8_lossy(&output.stderr);
+        println!("{}", output);
+        assert!(output.to_string().contains("Expected 25 initial bias offsets but got 3."));
+    }
+
+    #[test]
+    fn bootstrap_schemes() {
+        for scheme in &["block", "poisson"] {
+            let output = get_command()
+                .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+                .args(["--end", "10", "--tolerance", "0.001", "--bt", "3", "--seed", "1234"])
+                .args(["--bt_scheme", scheme])
+                .args(["-f", "example/1d_cyclic/metadata.dat"])
+                .args(["-o", "/dev/null"])
+                .output()
+                .expect("failed to execute process");
+            let output = String::from_utf8_lossy(&output.stderr);
+            println!("{}", output);
+            assert!(output.to_string().contains(&format!("bootstrap_scheme={}", scheme)));
+            assert!(output.to_string().contains("Bootstrap run 2/3"));
+        }
+    }
+
+    #[test]
+    fn bootstrap_intervals() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--end", "10", "--tolerance", "0.001", "--bt", "4", "--seed", "1234"])
+            .args(["--bt_sd", "--bt_ci", "95", "--bt_out", "/tmp/wham_test_bt_runs.out"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/tmp/wham_test_bt_ci.out"])
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        let results = std::fs::read_to_string("/tmp/wham_test_bt_ci.out").unwrap();
+        let lines: Vec<&str> = results.lines().collect();
+        assert!(lines[0].ends_with("Probability CI low    Probability CI high"));
+        assert_eq!(9, lines[1].split_whitespace().count());
+
+        let runs = std::fs::read_to_string("/tmp/wham_test_bt_runs.out").unwrap();
+        let lines: Vec<&str> = runs.lines().collect();
+        assert_eq!(1 + 4, lines.len());
+        assert!(lines[0].starts_with("#Run    A0    A1"));
+        assert_eq!(1 + 20 + 25, lines[4].split_whitespace().count());
+    }
+
+    #[test]
+    fn bootstrap_ci_out_of_range() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--bt", "4", "--bt_ci", "100"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--bt_ci requires a confidence level"));
+    }
+
+    #[test]
+    fn quiet() {
+        let output = get_command()
+            .args(["--bins", "20", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--end", "10", "--tolerance", "0.001", "-q"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", "/dev/null"])
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        // diagnostics are silenced, the PMF is still printed to stdout
+        assert!(output.stderr.is_empty());
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("# PMF"));
+    }
+
+    #[test]
+    fn bias_cutoff() {
+        let run = |args: &[&str], out: &str| {
+            let output = get_command()
+                .args(["--bins", "50", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+                .args(["-f", "example/1d_cyclic/metadata.dat", "-o", out])
+                .args(args)
+                .output()
+                .expect("failed to execute process");
+            assert!(output.status.success());
+            std::fs::read_to_string(out).unwrap()
+        };
+        let dense = run(&[], "/tmp/wham_test_dense.out");
+        let sparse = run(&["--bias_cutoff", "30"], "/tmp/wham_test_sparse.out");
+
+        // same free energy up to the truncated bias factors
+        for (d, s) in dense.lines().zip(sparse.lines()).skip(1) {
+            let d: Vec<f64> = d.split_whitespace().map(|v| v.parse().unwrap()).collect();
+            let s: Vec<f64> = s.split_whitespace().map(|v| v.parse().unwrap()).collect();
+            assert!((d[1] - s[1]).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn gromacs_pull() {
+        let output = get_command()
+            .args(["--bins", "12,12", "--min", "0.8,1.3", "--max", "1.4,1.9", "-T", "300"])
+            .args(["--ix", "tests/data/gromacs/pullx-files.dat"])
+            .args(["--it", "tests/data/gromacs/pull-params.dat"])
+            .args(["-o", "/tmp/wham_test_pull.out"])
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        let pmf = std::fs::read_to_string("/tmp/wham_test_pull.out").unwrap();
+        assert_eq!(144, pmf.lines().filter(|l| !l.starts_with('#')).count());
+
+        // the pull parameters are required
+        let output = get_command()
+            .args(["--bins", "12,12", "--min", "0.8,1.3", "--max", "1.4,1.9", "-T", "300"])
+            .args(["--ix", "tests/data/gromacs/pullx-files.dat"])
+            .output()
+            .expect("failed to execute process");
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--it"));
+    }
+
+    #[test]
+    fn frame_bias() {
+        let run = |args: &[&str], out: &str| {
+            let output = get_command()
+                .args(["--bins", "200", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+                .args(["--end", "19.99", "--tolerance", "0.00001", "-o", out])
+                .args(args)
+                .output()
+                .expect("failed to execute process");
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            std::fs::read_to_string(out).unwrap().lines().skip(1)
+                .map(|l| l.split_whitespace().map(|v| v.parse().unwrap()).collect())
+                .collect::<Vec<Vec<f64>>>()
+        };
+        let reference = run(&["-f", "tests/data/plumed/metadata_reference.dat", "--method", "mbar"],
+            "/tmp/wham_test_frame_bias_reference.out");
+
+        // the static bias given per frame yields the same free energy in the sampled region
+        let frame_bias = ["-f", "tests/data/plumed/metadata_frame_bias.dat", "--columns", "time,phi"];
+        for (method, out) in [("wham", "/tmp/wham_test_frame_bias_wham.out"),
+                ("mbar", "/tmp/wham_test_frame_bias_mbar.out")] {
+            let pmf = run(&[&frame_bias[..], &["--method", method]].concat(), out);
+            for (r, f) in reference.iter().zip(&pmf) {
+                if r[3] > 0.002 {
+                    assert!((r[1] - f[1]).abs() < 0.1, "{}: {} != {} at {}", method, r[1], f[1], r[0]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let run = |args: &[&str], out: &str| {
+            let output = get_command()
+                .args(["--bins", "50", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+                .args(["-f", "example/1d_cyclic/metadata.dat", "-o", out])
+                .args(args)
+                .output()
+                .expect("failed to execute process");
+            assert!(output.status.success());
+            std::fs::read_to_string(out).unwrap()
+        };
+        // same histograms, so the same free energy
+        let in_memory = run(&[], "/tmp/wham_test_in_memory.out");
+        let streamed = run(&["--stream"], "/tmp/wham_test_stream.out");
+        assert_eq!(in_memory, streamed);
+    }
+}
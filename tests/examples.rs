@@ -13,10 +13,10 @@ mod integration {
     fn wham_1d_cyclic() {
         let output_file = "/tmp/wham_test_1d_cyclic.out";
         get_command()
-            .args(&["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
-            .args(&["--seed", "1234"])
-            .args(&["-f", "example/1d_cyclic/metadata.dat"])
-            .args(&["-o", output_file])
+            .args(["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--seed", "1234"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", output_file])
             .output()
             .expect("failed to execute process");
 
@@ -35,10 +35,10 @@ mod integration {
     fn wham_1d_cyclic_uncorrelated() {
         let output_file = "/tmp/wham_test_1d_cyclic.out";
         get_command()
-            .args(&["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic", "--uncorr"])
-            .args(&["--seed", "1234"])
-            .args(&["-f", "example/1d_cyclic/metadata.dat"])
-            .args(&["-o", output_file])
+            .args(["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic", "--uncorr"])
+            .args(["--seed", "1234"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", output_file])
             .output()
             .expect("failed to execute process");
 
@@ -58,12 +58,12 @@ mod integration {
         // run wham with convdt
         let output_file = "/tmp/wham_test_convdt.out";
         get_command()
-            .args(&["--bins", "10", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
-            .args(&["--seed", "1234", "--tolerance", "0.001"])
-            .args(&["--start", "0", "--end", "10"])
-            .args(&["--convdt", "1"])
-            .args(&["-f", "example/1d_cyclic/metadata.dat"])
-            .args(&["-o", output_file])
+            .args(["--bins", "10", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--seed", "1234", "--tolerance", "0.000001"])
+            .args(["--start", "0", "--end", "10"])
+            .args(["--convdt", "1"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", output_file])
             .output()
             .expect("failed to execute process");
         assert!(fs::metadata(output_file).is_ok());
@@ -72,11 +72,11 @@ mod integration {
         for i in 1..11 {
             let output_file_single = format!("/tmp/wham_test_convdt_{}.out", i);
             get_command()
-                .args(&["--bins", "10", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
-                .args(&["--seed", "1234", "--tolerance", "0.001"])
-                .args(&["--start", "0", "--end", &i.to_string()])
-                .args(&["-f", "example/1d_cyclic/metadata.dat"])
-                .args(&["-o", &output_file_single])
+                .args(["--bins", "10", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+                .args(["--seed", "1234", "--tolerance", "0.000001"])
+                .args(["--start", "0", "--end", &i.to_string()])
+                .args(["-f", "example/1d_cyclic/metadata.dat"])
+                .args(["-o", &output_file_single])
                 .output()
                 .expect("failed to execute process");
             assert!(fs::metadata(output_file_single).is_ok());
@@ -86,6 +86,7 @@ mod integration {
         let output_combined = "/tmp/wham_test_convdt_combined.out";
         let mut file = OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .open(output_combined)
             .unwrap();
@@ -97,14 +98,10 @@ mod integration {
             std::fs::remove_file(output_file_single).unwrap();
         }
 
-        // compare combined runs with single run
-        let output = Command::new("diff")
-            .arg(output_file)
-            .arg(output_combined)
-            .output()
-            .expect("failed to run diff");
-        let output_len = String::from_utf8_lossy(&output.stdout).len();
-        assert_eq!(output_len, 0);
+        // compare combined runs with single run. Each slice of the single run
+        // starts from the offsets of the previous slice, so the results only
+        // agree within the convergence of WHAM.
+        assert_close(output_combined, output_file, 0.001);
 
         std::fs::remove_file(output_combined).unwrap();
         std::fs::remove_file(output_file).unwrap();
@@ -115,21 +112,17 @@ mod integration {
     fn wham_1d_cyclic_bootstrap() {
         let output_file = "/tmp/wham_test_1d_cyclic_bt.out";
         get_command()
-            .args(&["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
-            .args(&["--seed", "1234", "--bt", "100"])
-            .args(&["-f", "example/1d_cyclic/metadata.dat"])
-            .args(&["-o", output_file])
+            .args(["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--seed", "1234", "--bt", "100"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", output_file])
             .output()
             .expect("failed to execute process");
 
+        // bootstrap runs start from the converged offsets, so the errors
+        // only agree within the convergence of WHAM
         assert!(fs::metadata(output_file).is_ok());
-        let output = Command::new("diff")
-            .arg(output_file)
-            .arg("example/1d_cyclic/wham_bt.out")
-            .output()
-            .expect("failed to run diff");
-        let output_len = String::from_utf8_lossy(&output.stdout).len();
-        assert_eq!(output_len, 0);
+        assert_close(output_file, "example/1d_cyclic/wham_bt.out", 0.000_1);
         std::fs::remove_file(output_file).unwrap();
     }
 
@@ -138,10 +131,10 @@ mod integration {
         let output_file = "/tmp/wham_test_2d_cyclic.out";
         let out=
         get_command()
-            .args(&["--bins", "50,50", "--max", "pi,pi", "--min", "-pi,-pi", "-T", "300", "--cyclic"])
-            .args(&["--tolerance", "0.001"])
-            .args(&["-f", "tests/data/metadata_2d_cyclic_reduced.dat"])
-            .args(&["-o", output_file])
+            .args(["--bins", "50,50", "--max", "pi,pi", "--min", "-pi,-pi", "-T", "300", "--cyclic"])
+            .args(["--tolerance", "0.001"])
+            .args(["-f", "tests/data/metadata_2d_cyclic_reduced.dat"])
+            .args(["-o", output_file])
             .output()
             .expect("failed to execute process");
         println!("{:?}", out);
@@ -155,4 +148,39 @@ mod integration {
         assert_eq!(output_len, 0);
         std::fs::remove_file(output_file).unwrap();
     }
+
+    // parse the numeric columns of a WHAM output file
+    fn read_output(path: &str) -> Vec<Vec<f64>> {
+        fs::read_to_string(path).unwrap().lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.split_whitespace().map(|v| v.parse().unwrap()).collect())
+            .collect()
+    }
+
+    // compare the numeric columns of two WHAM output files
+    fn assert_close(actual: &str, expected: &str, tolerance: f64) {
+        let actual = read_output(actual);
+        let expected = read_output(expected);
+        assert_eq!(expected.len(), actual.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            for (a_col, e_col) in a.iter().zip(e.iter()) {
+                assert!((a_col - e_col).abs() < tolerance, "{} != {}", a_col, e_col);
+            }
+        }
+    }
+
+    #[test]
+    fn wham_1d_cyclic_logspace() {
+        let output_file = "/tmp/wham_test_1d_cyclic_logspace.out";
+        get_command()
+            .args(["--bins", "100", "--max", "pi", "--min", "-pi", "-T", "300", "--cyclic"])
+            .args(["--logspace"])
+            .args(["-f", "example/1d_cyclic/metadata.dat"])
+            .args(["-o", output_file])
+            .output()
+            .expect("failed to execute process");
+
+        assert_close(output_file, "example/1d_cyclic/wham.out", 0.000_1);
+        std::fs::remove_file(output_file).unwrap();
+    }
 }